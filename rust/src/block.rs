@@ -0,0 +1,379 @@
+// SPDX-License-Identifier: MIT
+
+//! Block devices, and the glue between them and the byte-addressed fatfs storage traits.
+//!
+//! Everything that deals with disks (partition tables, caches, filesystems) is written against
+//! `BlockDevice`, so it works the same on Apple NVMe namespaces and 512-byte disk images, and
+//! only `BlockStorage` has to know about fatfs.
+
+use crate::gpt::Partition;
+use alloc::vec::Vec;
+use core::cmp::min;
+use core::fmt;
+use fatfs::{Read, Seek, SeekFrom, Write};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The device failed a request covering `count` blocks at `lba`.
+    Io { lba: u64, count: u64 },
+    /// A request for `count` blocks at `lba` extends past the end of the device.
+    OutOfRange { lba: u64, count: u64 },
+    /// The buffer length is not a multiple of the block size.
+    Misaligned,
+    /// Writes to the device are not allowed.
+    ReadOnly,
+    /// The device has a block size that isn't supported.
+    BadBlockSize(usize),
+    /// End of storage in the middle of a byte-level read.
+    UnexpectedEof,
+    /// End of storage in the middle of a byte-level write.
+    WriteZero,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io { lba, count } => write!(f, "I/O error at LBA {} (+{})", lba, count),
+            Error::OutOfRange { lba, count } => {
+                write!(f, "LBA {} (+{}) is out of range", lba, count)
+            }
+            Error::Misaligned => f.write_str("buffer is not a multiple of the block size"),
+            Error::ReadOnly => f.write_str("device is read-only"),
+            Error::BadBlockSize(size) => write!(f, "unsupported block size {}", size),
+            Error::UnexpectedEof => f.write_str("unexpected end of storage"),
+            Error::WriteZero => f.write_str("no space left on storage"),
+        }
+    }
+}
+
+impl fatfs::IoError for Error {
+    fn is_interrupted(&self) -> bool {
+        false
+    }
+
+    fn new_unexpected_eof_error() -> Self {
+        Error::UnexpectedEof
+    }
+
+    fn new_write_zero_error() -> Self {
+        Error::WriteZero
+    }
+}
+
+/// A device addressed in fixed size logical blocks.
+///
+/// Buffers passed to `read_blocks()` and `write_blocks()` must be a multiple of the block size
+/// long, and the whole request has to lie within the device. Devices are free to require nothing
+/// else of the buffer, in particular no alignment.
+pub trait BlockDevice {
+    fn block_size(&self) -> usize;
+    fn block_count(&self) -> u64;
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), Error>;
+    fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> Result<(), Error>;
+    fn flush(&mut self) -> Result<(), Error>;
+
+    /// Whether `write_blocks()` fails with `Error::ReadOnly`, so that writes buffered on top of
+    /// the device can be refused right away.
+    fn is_read_only(&self) -> bool {
+        false
+    }
+}
+
+impl<D: BlockDevice + ?Sized> BlockDevice for &mut D {
+    fn block_size(&self) -> usize {
+        (**self).block_size()
+    }
+    fn block_count(&self) -> u64 {
+        (**self).block_count()
+    }
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), Error> {
+        (**self).read_blocks(lba, buf)
+    }
+    fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> Result<(), Error> {
+        (**self).write_blocks(lba, buf)
+    }
+    fn flush(&mut self) -> Result<(), Error> {
+        (**self).flush()
+    }
+    fn is_read_only(&self) -> bool {
+        (**self).is_read_only()
+    }
+}
+
+/// Check that a request for `len` bytes at `lba` is block sized and within `dev`, and return
+/// the number of blocks.
+pub fn check_request<D: BlockDevice + ?Sized>(dev: &D, lba: u64, len: usize) -> Result<u64, Error> {
+    let bs = dev.block_size();
+    let count = len / bs;
+    if count * bs != len {
+        return Err(Error::Misaligned);
+    }
+    let count = count as u64;
+    match lba.checked_add(count) {
+        Some(end) if end <= dev.block_count() => Ok(count),
+        _ => Err(Error::OutOfRange { lba, count }),
+    }
+}
+
+/// A window onto one partition of the underlying device.
+///
+/// LBAs are relative to the start of the partition, and any access beyond its end fails instead
+/// of spilling over into whatever comes after it on disk.
+pub struct PartitionDevice<D> {
+    inner: D,
+    start: u64,
+    count: u64,
+}
+
+impl<D: BlockDevice> PartitionDevice<D> {
+    /// Wrap `inner`, limited to the (inclusive) LBA range `start_lba..=end_lba`.
+    pub fn new(inner: D, start_lba: u64, end_lba: u64) -> Self {
+        PartitionDevice {
+            inner,
+            start: start_lba,
+            count: (end_lba + 1).saturating_sub(start_lba),
+        }
+    }
+
+    pub fn for_partition(inner: D, part: &Partition) -> Self {
+        Self::new(inner, part.get_starting_lba(), part.get_ending_lba())
+    }
+
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for PartitionDevice<D> {
+    fn block_size(&self) -> usize {
+        self.inner.block_size()
+    }
+    fn block_count(&self) -> u64 {
+        self.count
+    }
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), Error> {
+        check_request(self, lba, buf.len())?;
+        self.inner.read_blocks(self.start + lba, buf)
+    }
+    fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> Result<(), Error> {
+        check_request(self, lba, buf.len())?;
+        self.inner.write_blocks(self.start + lba, buf)
+    }
+    fn flush(&mut self) -> Result<(), Error> {
+        self.inner.flush()
+    }
+    fn is_read_only(&self) -> bool {
+        self.inner.is_read_only()
+    }
+}
+
+/// Byte-addressed fatfs storage on top of a block device.
+///
+/// Whole blocks are passed straight through to the device, partial blocks at the start and end
+/// of a request go through a single block buffer (read-modify-write for writes).
+pub struct BlockStorage<D> {
+    dev: D,
+    block: Vec<u8>,
+    pos: u64,
+}
+
+impl<D: BlockDevice> BlockStorage<D> {
+    pub fn new(dev: D) -> Self {
+        BlockStorage {
+            block: vec![0; dev.block_size()],
+            dev,
+            pos: 0,
+        }
+    }
+
+    /// Size of the storage in bytes.
+    pub fn len(&self) -> u64 {
+        self.dev.block_count() * self.dev.block_size() as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn device(&mut self) -> &mut D {
+        &mut self.dev
+    }
+
+    pub fn into_inner(self) -> D {
+        self.dev
+    }
+
+    /// Split the current position into an LBA, an offset into that block, and the number of
+    /// bytes (at most `len`) that can be transferred starting there.
+    fn locate(&self, len: usize) -> (u64, usize, usize) {
+        let bs = self.dev.block_size();
+        let lba = self.pos / bs as u64;
+        let off = (self.pos % bs as u64) as usize;
+        let len = min(len as u64, self.len() - self.pos) as usize;
+        match (off, len / bs) {
+            (0, 0) => (lba, 0, len),
+            (0, blocks) => (lba, 0, blocks * bs),
+            (off, _) => (lba, off, min(len, bs - off)),
+        }
+    }
+}
+
+impl<D> fatfs::IoBase for BlockStorage<D> {
+    type Error = Error;
+}
+
+impl<D: BlockDevice> Read for BlockStorage<D> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.pos >= self.len() {
+            return Err(Error::UnexpectedEof);
+        }
+        let (lba, off, len) = self.locate(buf.len());
+        if off == 0 && len % self.block.len() == 0 {
+            self.dev.read_blocks(lba, &mut buf[..len])?;
+        } else {
+            self.dev.read_blocks(lba, &mut self.block)?;
+            buf[..len].copy_from_slice(&self.block[off..off + len]);
+        }
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl<D: BlockDevice> Write for BlockStorage<D> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.pos >= self.len() {
+            return Err(Error::WriteZero);
+        }
+        let (lba, off, len) = self.locate(buf.len());
+        if off == 0 && len % self.block.len() == 0 {
+            self.dev.write_blocks(lba, &buf[..len])?;
+        } else {
+            self.dev.read_blocks(lba, &mut self.block)?;
+            self.block[off..off + len].copy_from_slice(&buf[..len]);
+            self.dev.write_blocks(lba, &self.block)?;
+        }
+        self.pos += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        self.dev.flush()
+    }
+}
+
+impl<D: BlockDevice> Seek for BlockStorage<D> {
+    fn seek(&mut self, from: SeekFrom) -> Result<u64, Self::Error> {
+        let pos = match from {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::End(n) => self.len().checked_add_signed(n),
+            SeekFrom::Current(n) => self.pos.checked_add_signed(n),
+        };
+        self.pos = pos.ok_or(Error::UnexpectedEof)?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    /// In-memory block device for host tests.
+    pub(crate) struct MemDevice {
+        pub(crate) data: Vec<u8>,
+        block_size: usize,
+    }
+
+    impl MemDevice {
+        pub(crate) fn new(size: usize, block_size: usize) -> Self {
+            MemDevice {
+                data: vec![0; size],
+                block_size,
+            }
+        }
+        pub(crate) fn sector_mut(&mut self, lba: u64) -> &mut [u8] {
+            let off = lba as usize * self.block_size;
+            &mut self.data[off..]
+        }
+    }
+
+    impl BlockDevice for MemDevice {
+        fn block_size(&self) -> usize {
+            self.block_size
+        }
+        fn block_count(&self) -> u64 {
+            (self.data.len() / self.block_size) as u64
+        }
+        fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), Error> {
+            check_request(self, lba, buf.len())?;
+            let off = lba as usize * self.block_size;
+            buf.copy_from_slice(&self.data[off..off + buf.len()]);
+            Ok(())
+        }
+        fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> Result<(), Error> {
+            check_request(self, lba, buf.len())?;
+            let off = lba as usize * self.block_size;
+            self.data[off..off + buf.len()].copy_from_slice(buf);
+            Ok(())
+        }
+        fn flush(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn partition_bounds() {
+        let mut disk = MemDevice::new(64 * 512, 512);
+        disk.data
+            .iter_mut()
+            .enumerate()
+            .for_each(|(i, b)| *b = (i / 512) as u8);
+
+        let mut part = PartitionDevice::new(disk, 4, 7);
+        assert_eq!(part.block_count(), 4);
+        let mut block = [0u8; 512];
+        part.read_blocks(3, &mut block).unwrap();
+        assert_eq!(block[0], 7);
+        assert_eq!(
+            part.read_blocks(3, &mut [0; 1024]),
+            Err(Error::OutOfRange { lba: 3, count: 2 })
+        );
+        assert_eq!(part.write_blocks(0, &[0; 16]), Err(Error::Misaligned));
+
+        let mut part = BlockStorage::new(part);
+        assert_eq!(part.len(), 4 * 512);
+        assert_eq!(part.seek(SeekFrom::End(-1)).unwrap(), 4 * 512 - 1);
+
+        let mut buf = [0u8; 16];
+        assert_eq!(part.read(&mut buf).unwrap(), 1);
+        assert_eq!(buf[0], 7);
+        assert!(part.read(&mut buf).is_err());
+        assert!(part.write(&buf).is_err());
+
+        part.seek(SeekFrom::Start(512)).unwrap();
+        part.write_all(&[0xaa; 4]).unwrap();
+        part.seek(SeekFrom::Current(-4)).unwrap();
+        part.read_exact(&mut buf[..4]).unwrap();
+        assert_eq!(buf[..4], [0xaa; 4]);
+        assert!(part.seek(SeekFrom::Current(-1024)).is_err());
+
+        // Unaligned reads spanning whole blocks
+        let mut big = [0u8; 1030];
+        part.seek(SeekFrom::Start(510)).unwrap();
+        part.read_exact(&mut big).unwrap();
+        assert_eq!(big[..2], [4, 4]);
+        assert_eq!(big[2..6], [0xaa; 4]);
+        assert_eq!(big[6], 5);
+        assert_eq!(big[514..1026], [6; 512]);
+        assert_eq!(big[1026..], [7; 4]);
+
+        let disk = part.into_inner().into_inner();
+        assert_eq!(disk.data[5 * 512..5 * 512 + 5], [0xaa, 0xaa, 0xaa, 0xaa, 5]);
+    }
+}
@@ -0,0 +1,420 @@
+// SPDX-License-Identifier: MIT
+
+//! Boot Loader Specification Type #1 entries.
+//!
+//! Entries are `loader/entries/*.conf` files with one `key value` pair per line, see
+//! <https://uapi-group.org/specifications/specs/boot_loader_specification/>. Parsing, ordering
+//! and selection live here, reading them from a partition is up to `chainload`.
+
+use crate::println;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+
+/// Directory holding the entries, relative to the root of the partition.
+pub const ENTRIES_DIR: &str = "loader/entries";
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// File name without `.conf` and boot counting suffix.
+    pub id: String,
+    pub title: Option<String>,
+    pub version: Option<String>,
+    pub machine_id: Option<String>,
+    pub sort_key: Option<String>,
+    /// Paths relative to the root of the partition.
+    pub linux: Option<String>,
+    pub initrd: Vec<String>,
+    pub devicetree: Option<String>,
+    pub devicetree_overlay: Vec<String>,
+    /// Kernel command line, with multiple `options` lines joined by spaces.
+    pub options: String,
+    pub architecture: Option<String>,
+    /// Boot counting `+LEFT-DONE` from the file name, if any.
+    pub tries_left: Option<u32>,
+    pub tries_done: Option<u32>,
+}
+
+fn path(value: &str) -> String {
+    value.trim_start_matches('/').into()
+}
+
+impl Entry {
+    /// Parse the entry in `file_name` (with its `.conf` suffix) from `text`.
+    ///
+    /// Unknown keys are ignored, as the specification requires.
+    pub fn parse(file_name: &str, text: &str) -> Self {
+        let mut entry = Entry::default();
+
+        let stem = file_name
+            .get(..file_name.len().saturating_sub(5))
+            .filter(|_| file_name.to_ascii_lowercase().ends_with(".conf"))
+            .unwrap_or(file_name);
+        entry.id = stem.into();
+        if let Some((id, counters)) = stem.rsplit_once('+') {
+            let (left, done) = counters.split_once('-').unwrap_or((counters, "0"));
+            if let (Ok(left), Ok(done)) = (left.parse(), done.parse()) {
+                entry.id = id.into();
+                entry.tries_left = Some(left);
+                entry.tries_done = Some(done);
+            }
+        }
+
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = line
+                .split_once(|c: char| c.is_ascii_whitespace())
+                .unwrap_or((line, ""));
+            let value = value.trim();
+
+            match key {
+                "title" => entry.title = Some(value.into()),
+                "version" => entry.version = Some(value.into()),
+                "machine-id" => entry.machine_id = Some(value.into()),
+                "sort-key" => entry.sort_key = Some(value.into()),
+                "linux" => entry.linux = Some(path(value)),
+                "initrd" => entry.initrd.push(path(value)),
+                "devicetree" => entry.devicetree = Some(path(value)),
+                "devicetree-overlay" => entry
+                    .devicetree_overlay
+                    .extend(value.split_ascii_whitespace().map(path)),
+                "options" => {
+                    if !entry.options.is_empty() {
+                        entry.options.push(' ');
+                    }
+                    entry.options.push_str(value);
+                }
+                "architecture" => entry.architecture = Some(value.into()),
+                _ => {}
+            }
+        }
+
+        entry
+    }
+
+    /// Whether m1n1 can boot this entry.
+    ///
+    /// EFI programs can't be run, so it needs a `linux` kernel for this architecture.
+    pub fn is_bootable(&self) -> bool {
+        let native = match &self.architecture {
+            Some(arch) => arch.eq_ignore_ascii_case("aa64"),
+            None => true,
+        };
+        native && self.linux.is_some()
+    }
+
+    /// Whether boot counting has run out of tries for this entry.
+    pub fn is_bad(&self) -> bool {
+        self.tries_left == Some(0)
+    }
+
+    pub fn title(&self) -> &str {
+        self.title.as_deref().unwrap_or(&self.id)
+    }
+}
+
+/// Sort entries in boot menu order.
+///
+/// Entries without tries left go last. Entries with a `sort-key` come first, ordered by it, then
+/// by `machine-id` and by `version` from newest to oldest. The rest are ordered by id from
+/// newest to oldest.
+pub fn sort(entries: &mut [Entry]) {
+    entries.sort_by(|a, b| {
+        a.is_bad()
+            .cmp(&b.is_bad())
+            .then_with(|| match (&a.sort_key, &b.sort_key) {
+                (Some(ka), Some(kb)) => ka
+                    .cmp(kb)
+                    .then_with(|| a.machine_id.cmp(&b.machine_id))
+                    .then_with(|| {
+                        let va = a.version.as_deref().unwrap_or("");
+                        let vb = b.version.as_deref().unwrap_or("");
+                        compare_versions(vb, va)
+                    }),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => Ordering::Equal,
+            })
+            .then_with(|| compare_versions(&b.id, &a.id))
+    });
+}
+
+/// Compare two version strings as described by the UAPI Version Format Specification.
+///
+/// Digit runs compare numerically, letters compare lexically, and `~` sorts before anything,
+/// even the end of the string, so `1.0~rc1` is older than `1.0`.
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    fn is_valid(c: u8) -> bool {
+        c.is_ascii_alphanumeric() || b"~-^.".contains(&c)
+    }
+
+    fn skip_invalid(s: &[u8]) -> &[u8] {
+        let n = s.iter().take_while(|&&c| !is_valid(c)).count();
+        &s[n..]
+    }
+
+    fn split_run(s: &[u8], pred: fn(&u8) -> bool) -> (&[u8], &[u8]) {
+        let n = s.iter().take_while(|c| pred(c)).count();
+        s.split_at(n)
+    }
+
+    let (mut a, mut b) = (a.as_bytes(), b.as_bytes());
+    loop {
+        a = skip_invalid(a);
+        b = skip_invalid(b);
+
+        let (ca, cb) = (a.first().copied(), b.first().copied());
+
+        if ca == Some(b'~') || cb == Some(b'~') {
+            let ord = (ca != Some(b'~')).cmp(&(cb != Some(b'~')));
+            if ord != Ordering::Equal {
+                return ord;
+            }
+            a = &a[1..];
+            b = &b[1..];
+            continue;
+        }
+
+        let (Some(ca), Some(cb)) = (ca, cb) else {
+            return a.len().min(1).cmp(&b.len().min(1));
+        };
+
+        // A separator that the other version doesn't have makes it older
+        if let Some(&sep) = b"-^.".iter().find(|&&sep| ca == sep || cb == sep) {
+            let ord = (ca != sep).cmp(&(cb != sep));
+            if ord != Ordering::Equal {
+                return ord;
+            }
+            a = &a[1..];
+            b = &b[1..];
+            continue;
+        }
+
+        if ca.is_ascii_digit() || cb.is_ascii_digit() {
+            let ord = ca.is_ascii_digit().cmp(&cb.is_ascii_digit());
+            if ord != Ordering::Equal {
+                return ord;
+            }
+            let (da, ra) = split_run(a, u8::is_ascii_digit);
+            let (db, rb) = split_run(b, u8::is_ascii_digit);
+            let da = &da[da.iter().take_while(|&&c| c == b'0').count()..];
+            let db = &db[db.iter().take_while(|&&c| c == b'0').count()..];
+            let ord = da.len().cmp(&db.len()).then_with(|| da.cmp(db));
+            if ord != Ordering::Equal {
+                return ord;
+            }
+            a = ra;
+            b = rb;
+        } else {
+            let (la, ra) = split_run(a, u8::is_ascii_alphabetic);
+            let (lb, rb) = split_run(b, u8::is_ascii_alphabetic);
+            let ord = la.cmp(lb);
+            if ord != Ordering::Equal {
+                return ord;
+            }
+            a = ra;
+            b = rb;
+        }
+    }
+}
+
+/// Match `s` against a glob `pattern` with `*` and `?` wildcards.
+fn glob_match(pattern: &str, s: &str) -> bool {
+    let (p, s): (Vec<char>, Vec<char>) = (pattern.chars().collect(), s.chars().collect());
+    let (mut pi, mut si) = (0, 0);
+    // Position after the last `*` and the input position it was tried at
+    let mut star = None;
+
+    while si < s.len() {
+        match p.get(pi) {
+            Some('*') => {
+                star = Some((pi + 1, si));
+                pi += 1;
+            }
+            Some(&c) if c == '?' || c == s[si] => {
+                pi += 1;
+                si += 1;
+            }
+            _ => match star {
+                Some((spi, ssi)) => {
+                    pi = spi;
+                    si = ssi + 1;
+                    star = Some((spi, ssi + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    p[pi..].iter().all(|&c| c == '*')
+}
+
+/// Select the first bootable entry, in boot menu order, whose id matches the glob `pattern`.
+///
+/// `entries` must already be sorted.
+pub fn select<'a>(entries: &'a [Entry], pattern: &str) -> Option<&'a Entry> {
+    for entry in entries {
+        if !glob_match(pattern, &entry.id) {
+            continue;
+        }
+        if entry.is_bootable() {
+            return Some(entry);
+        }
+        println!("bls: skipping {}, no aa64 linux kernel", entry.id);
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    #[test]
+    fn parse_entry() {
+        let text = "# Boot Loader Specification type#1 entry\n\
+                    title      Fedora Linux Asahi Remix 40\n\
+                    version    6.8.9-400.asahi.fc40.aarch64+16k\n\
+                    machine-id 6a9857a393724b7a981ebb5b8495b9ea\n\
+                    sort-key   fedora\n\
+                    options    root=UUID=0e1a2b3c ro\n\
+                    options    quiet   rhgb\n\
+                    linux      /vmlinuz-6.8.9-400.asahi.fc40.aarch64+16k\n\
+                    initrd     /initramfs-6.8.9.img\n\
+                    initrd     /microcode.img\n\
+                    devicetree /dtb/apple/t8103-j274.dtb\n\
+                    devicetree-overlay /overlays/a.dtbo /overlays/b.dtbo\n\
+                    grub_users $grub_users\n";
+        let entry = Entry::parse("6a9857a393724b7a981ebb5b8495b9ea-6.8.9.conf", text);
+
+        assert_eq!(entry.id, "6a9857a393724b7a981ebb5b8495b9ea-6.8.9");
+        assert_eq!(entry.title(), "Fedora Linux Asahi Remix 40");
+        assert_eq!(entry.sort_key.as_deref(), Some("fedora"));
+        assert_eq!(
+            entry.linux.as_deref(),
+            Some("vmlinuz-6.8.9-400.asahi.fc40.aarch64+16k")
+        );
+        assert_eq!(entry.initrd, ["initramfs-6.8.9.img", "microcode.img"]);
+        assert_eq!(
+            entry.devicetree.as_deref(),
+            Some("dtb/apple/t8103-j274.dtb")
+        );
+        assert_eq!(
+            entry.devicetree_overlay,
+            ["overlays/a.dtbo", "overlays/b.dtbo"]
+        );
+        assert_eq!(entry.options, "root=UUID=0e1a2b3c ro quiet   rhgb");
+        assert_eq!(entry.tries_left, None);
+        assert!(entry.is_bootable());
+
+        let entry = Entry::parse("linux+3-1.conf", "efi /EFI/Linux/linux.efi\n");
+        assert_eq!(entry.id, "linux");
+        assert_eq!((entry.tries_left, entry.tries_done), (Some(3), Some(1)));
+        assert!(!entry.is_bootable());
+
+        let entry = Entry::parse("linux+0.conf", "linux /Image\narchitecture x64\n");
+        assert_eq!((entry.tries_left, entry.tries_done), (Some(0), Some(0)));
+        assert!(entry.is_bad());
+        assert!(!entry.is_bootable());
+    }
+
+    #[test]
+    fn versions() {
+        let ordered = [
+            "~1",
+            "",
+            "0",
+            "1.0~rc1",
+            "1.0",
+            "1.0-1",
+            "1.0^1",
+            "1.0.1",
+            "1.0a",
+            "1.1",
+            "1.02.1",
+            "1.2b",
+            "1.10",
+            "6.8.9",
+            "6.8.10-400.asahi",
+            "6.10.0",
+        ];
+        for (i, a) in ordered.iter().enumerate() {
+            for (j, b) in ordered.iter().enumerate() {
+                assert_eq!(compare_versions(a, b), i.cmp(&j), "{:?} vs {:?}", a, b);
+            }
+        }
+        assert_eq!(compare_versions("1.01", "1.1"), Ordering::Equal);
+        // Invalid characters are skipped
+        assert_eq!(compare_versions("_1.0 ", "1.0"), Ordering::Equal);
+    }
+
+    fn entry(file_name: &str, text: &str) -> Entry {
+        Entry::parse(file_name, &format!("linux /Image\n{}", text))
+    }
+
+    fn ids(entries: &[Entry]) -> Vec<&str> {
+        entries.iter().map(|e| e.id.as_str()).collect()
+    }
+
+    #[test]
+    fn ordering() {
+        let mut entries = [
+            entry("arch-6.9.conf", ""),
+            entry("arch-6.10.conf", ""),
+            entry("fedora-6.8.conf", "sort-key fedora\nversion 6.8\n"),
+            entry("fedora-6.10.conf", "sort-key fedora\nversion 6.10\n"),
+            entry("broken-7.0+0-3.conf", "sort-key fedora\nversion 7.0\n"),
+            entry("debian.conf", "sort-key debian\n"),
+            entry(
+                "other-machine.conf",
+                "sort-key fedora\nmachine-id b\nversion 9\n",
+            ),
+            entry(
+                "fedora-6.9.conf",
+                "sort-key fedora\nmachine-id a\nversion 6.9\n",
+            ),
+        ];
+        sort(&mut entries);
+        assert_eq!(
+            ids(&entries),
+            [
+                "debian",
+                "fedora-6.10",
+                "fedora-6.8",
+                "fedora-6.9",
+                "other-machine",
+                "arch-6.10",
+                "arch-6.9",
+                "broken-7.0",
+            ]
+        );
+    }
+
+    #[test]
+    fn selection() {
+        let mut entries = [
+            entry("efi-only.conf", "architecture aa64\n"),
+            entry("linux-6.8.conf", ""),
+            entry("linux-6.10.conf", ""),
+            entry("rescue.conf", ""),
+            Entry::parse("uki-9.conf", "efi /EFI/Linux/uki.efi\n"),
+        ];
+        entries[0].linux = None;
+        sort(&mut entries);
+
+        assert_eq!(select(&entries, "*").unwrap().id, "rescue");
+        assert_eq!(select(&entries, "linux-*").unwrap().id, "linux-6.10");
+        assert_eq!(select(&entries, "linux-6.?").unwrap().id, "linux-6.8");
+        assert_eq!(select(&entries, "*-6.8").unwrap().id, "linux-6.8");
+        assert_eq!(select(&entries, "rescue").unwrap().id, "rescue");
+        assert!(select(&entries, "uki*").is_none());
+        assert!(select(&entries, "efi-only").is_none());
+        assert!(select(&entries, "linux").is_none());
+
+        assert!(glob_match("*a*b*", "xxaxxbxx"));
+        assert!(glob_match("a**", "a"));
+        assert!(!glob_match("*a*b", "xxaxxbxc"));
+    }
+}
@@ -0,0 +1,31 @@
+// SPDX-License-Identifier: MIT
+
+//! Reading integers out of byte buffers.
+
+/// The little-endian `u32` at `offset`, or `None` if `data` ends before it does.
+pub fn u32_le(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// The little-endian `u64` at `offset`, or `None` if `data` ends before it does.
+pub fn u64_le(data: &[u8], offset: usize) -> Option<u64> {
+    let bytes = data.get(offset..offset.checked_add(8)?)?;
+    Some(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounds() {
+        let data = [1, 2, 3, 4, 5, 6, 7, 8, 9];
+        assert_eq!(u32_le(&data, 0), Some(0x04030201));
+        assert_eq!(u32_le(&data, 5), Some(0x09080706));
+        assert_eq!(u32_le(&data, 6), None);
+        assert_eq!(u32_le(&data, usize::MAX), None);
+        assert_eq!(u64_le(&data, 1), Some(0x0908070605040302));
+        assert_eq!(u64_le(&data, 2), None);
+    }
+}
@@ -0,0 +1,447 @@
+// SPDX-License-Identifier: MIT
+
+//! LRU block cache with sequential read-ahead.
+//!
+//! FAT constantly bounces between the FAT itself, directory clusters and file data, so a single
+//! buffered sector gets evicted all the time. This keeps a fixed budget of recently used blocks
+//! around instead, and speculatively reads ahead when it sees a sequential access pattern, which
+//! is what loading a large file looks like.
+
+use crate::block::{check_request, BlockDevice, Error};
+use crate::println;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// Largest supported block size. Cache memory is made of pages of this size and alignment, so
+/// blocks in it can be handed to DMA directly.
+pub const MAX_BLOCK_SIZE: usize = 4096;
+
+pub const DEFAULT_BUDGET: usize = 256 * 1024;
+pub const DEFAULT_READAHEAD: usize = 16;
+
+#[repr(C, align(4096))]
+struct CachePage([u8; MAX_BLOCK_SIZE]);
+
+#[derive(Copy, Clone, Default)]
+struct Slot {
+    lba: Option<u64>,
+    dirty: bool,
+    last_used: u64,
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub readahead: u64,
+    pub writebacks: u64,
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let total = self.hits + self.misses;
+        write!(
+            f,
+            "{} hits, {} misses ({}% hit rate), {} blocks read ahead, {} written back",
+            self.hits,
+            self.misses,
+            (self.hits * 100).checked_div(total).unwrap_or(0),
+            self.readahead,
+            self.writebacks
+        )
+    }
+}
+
+pub struct BlockCache {
+    block_size: usize,
+    slots: Vec<Slot>,
+    // Blocks are packed into pages, so none of them straddles two pages
+    pages: Box<[CachePage]>,
+    clock: u64,
+    readahead: usize,
+    // Where the next miss has to be for the access pattern to count as sequential
+    next_seq: Option<u64>,
+    stats: CacheStats,
+}
+
+impl BlockCache {
+    /// Create a cache for `block_size` blocks using up to `budget` bytes of memory.
+    pub fn new(block_size: usize, budget: usize) -> Self {
+        assert!(block_size.is_power_of_two() && block_size <= MAX_BLOCK_SIZE);
+        let count = (budget / block_size).max(1);
+        let pages = (count * block_size).div_ceil(MAX_BLOCK_SIZE);
+        let pages: Box<[CachePage]> = unsafe { Box::new_zeroed_slice(pages).assume_init() };
+
+        BlockCache {
+            block_size,
+            slots: vec![Slot::default(); count],
+            pages,
+            clock: 0,
+            readahead: DEFAULT_READAHEAD.min(count / 2),
+            next_seq: None,
+            stats: CacheStats::default(),
+        }
+    }
+
+    /// Set the number of blocks to read ahead on sequential access, 0 disables read-ahead.
+    pub fn set_readahead(&mut self, blocks: usize) {
+        // Never let read-ahead evict everything else
+        self.readahead = blocks.min(self.slots.len() / 2);
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.slots.iter().any(|s| s.dirty)
+    }
+
+    /// Whether any of the `count` blocks starting at `lba` only exist in the cache.
+    fn is_range_dirty(&self, lba: u64, count: u64) -> bool {
+        self.slots
+            .iter()
+            .any(|s| s.dirty && s.lba.is_some_and(|l| (lba..lba + count).contains(&l)))
+    }
+
+    fn block(&self, idx: usize) -> &[u8] {
+        let off = idx * self.block_size;
+        &self.pages[off / MAX_BLOCK_SIZE].0[off % MAX_BLOCK_SIZE..][..self.block_size]
+    }
+
+    fn block_mut(&mut self, idx: usize) -> &mut [u8] {
+        let off = idx * self.block_size;
+        &mut self.pages[off / MAX_BLOCK_SIZE].0[off % MAX_BLOCK_SIZE..][..self.block_size]
+    }
+
+    fn find(&self, lba: u64) -> Option<usize> {
+        self.slots.iter().position(|s| s.lba == Some(lba))
+    }
+
+    fn touch(&mut self, idx: usize) {
+        self.clock += 1;
+        self.slots[idx].last_used = self.clock;
+    }
+
+    fn writeback<D: BlockDevice>(&mut self, dev: &mut D, idx: usize) -> Result<(), Error> {
+        let slot = self.slots[idx];
+        if let (true, Some(lba)) = (slot.dirty, slot.lba) {
+            dev.write_blocks(lba, self.block(idx))?;
+            self.slots[idx].dirty = false;
+            self.stats.writebacks += 1;
+        }
+        Ok(())
+    }
+
+    /// Free up the least recently used slot.
+    fn evict<D: BlockDevice>(&mut self, dev: &mut D) -> Result<usize, Error> {
+        let idx = self
+            .slots
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, s)| (s.lba.is_some(), s.last_used))
+            .map(|(i, _)| i)
+            .unwrap();
+        self.writeback(dev, idx)?;
+        self.slots[idx].lba = None;
+        Ok(idx)
+    }
+
+    fn load<D: BlockDevice>(&mut self, dev: &mut D, lba: u64, fill: bool) -> Result<usize, Error> {
+        let idx = self.evict(dev)?;
+        if fill {
+            dev.read_blocks(lba, self.block_mut(idx))?;
+        }
+        self.slots[idx].lba = Some(lba);
+        self.touch(idx);
+        Ok(idx)
+    }
+
+    fn prefetch<D: BlockDevice>(&mut self, dev: &mut D, start: u64) -> u64 {
+        let mut lba = start;
+        let end = (start + self.readahead as u64).min(dev.block_count());
+        while lba < end {
+            if self.find(lba).is_none() {
+                // Read-ahead is speculative, just stop at the first error
+                if self.load(dev, lba, true).is_err() {
+                    break;
+                }
+                self.stats.readahead += 1;
+            }
+            lba += 1;
+        }
+        lba
+    }
+
+    fn get<D: BlockDevice>(&mut self, dev: &mut D, lba: u64, fill: bool) -> Result<usize, Error> {
+        if let Some(idx) = self.find(lba) {
+            self.stats.hits += 1;
+            self.touch(idx);
+            return Ok(idx);
+        }
+
+        self.stats.misses += 1;
+        let idx = self.load(dev, lba, fill)?;
+        self.next_seq = match self.next_seq {
+            Some(next) if fill && next == lba && self.readahead > 0 => {
+                Some(self.prefetch(dev, lba + 1))
+            }
+            _ => Some(lba + 1),
+        };
+        Ok(idx)
+    }
+
+    /// Read `buf.len()` bytes at offset `off` of block `lba`.
+    pub fn read<D: BlockDevice>(
+        &mut self,
+        dev: &mut D,
+        lba: u64,
+        off: usize,
+        buf: &mut [u8],
+    ) -> Result<(), Error> {
+        let idx = self.get(dev, lba, true)?;
+        buf.copy_from_slice(&self.block(idx)[off..off + buf.len()]);
+        Ok(())
+    }
+
+    /// Write `data` at offset `off` of block `lba`. The block is only written back to the device
+    /// on eviction or `flush()`.
+    pub fn write<D: BlockDevice>(
+        &mut self,
+        dev: &mut D,
+        lba: u64,
+        off: usize,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        // Partial blocks need a read-modify-write
+        let fill = off != 0 || data.len() != self.block_size;
+        let idx = self.get(dev, lba, fill)?;
+        self.block_mut(idx)[off..off + data.len()].copy_from_slice(data);
+        self.slots[idx].dirty = true;
+        Ok(())
+    }
+
+    /// Write back all dirty blocks, in LBA order.
+    pub fn flush<D: BlockDevice>(&mut self, dev: &mut D) -> Result<(), Error> {
+        let mut dirty: Vec<usize> = (0..self.slots.len())
+            .filter(|&i| self.slots[i].dirty)
+            .collect();
+        dirty.sort_by_key(|&i| self.slots[i].lba);
+        for idx in dirty {
+            self.writeback(dev, idx)?;
+        }
+        Ok(())
+    }
+}
+
+/// A block device with a `BlockCache` in front of it.
+///
+/// Single block requests (which is what filesystem metadata access looks like) go through the
+/// cache. Larger reads bypass it unless they cover dirty blocks, so that loading big files
+/// doesn't evict everything else and can use multi-block transfers.
+pub struct CachedDevice<D: BlockDevice> {
+    dev: D,
+    cache: BlockCache,
+}
+
+impl<D: BlockDevice> CachedDevice<D> {
+    pub fn new(dev: D) -> Self {
+        Self::with_budget(dev, DEFAULT_BUDGET)
+    }
+
+    /// Like `new()`, but with a cache budget of `budget` bytes.
+    pub fn with_budget(dev: D, budget: usize) -> Self {
+        CachedDevice {
+            cache: BlockCache::new(dev.block_size(), budget),
+            dev,
+        }
+    }
+
+    /// Number of blocks to read ahead when reading sequentially.
+    pub fn set_readahead(&mut self, blocks: usize) {
+        self.cache.set_readahead(blocks);
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+}
+
+impl<D: BlockDevice> Drop for CachedDevice<D> {
+    fn drop(&mut self) {
+        if self.cache.is_dirty() {
+            println!("cache: discarding unflushed writes");
+        }
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for CachedDevice<D> {
+    fn block_size(&self) -> usize {
+        self.dev.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.dev.block_count()
+    }
+
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), Error> {
+        let count = check_request(self, lba, buf.len())?;
+        if count > 1 && !self.cache.is_range_dirty(lba, count) {
+            return self.dev.read_blocks(lba, buf);
+        }
+        for (lba, block) in (lba..).zip(buf.chunks_exact_mut(self.cache.block_size)) {
+            self.cache.read(&mut self.dev, lba, 0, block)?;
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> Result<(), Error> {
+        check_request(self, lba, buf.len())?;
+        // Don't hold on to blocks that can never be written back
+        if self.dev.is_read_only() {
+            return Err(Error::ReadOnly);
+        }
+        for (lba, block) in (lba..).zip(buf.chunks_exact(self.cache.block_size)) {
+            self.cache.write(&mut self.dev, lba, 0, block)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        if !self.cache.is_dirty() {
+            return Ok(());
+        }
+        self.cache.flush(&mut self.dev)?;
+        self.dev.flush()
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.dev.is_read_only()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    struct CountingDev {
+        data: Vec<u8>,
+        reads: usize,
+        writes: usize,
+        read_only: bool,
+    }
+
+    impl BlockDevice for CountingDev {
+        fn block_size(&self) -> usize {
+            512
+        }
+
+        fn block_count(&self) -> u64 {
+            (self.data.len() / 512) as u64
+        }
+
+        fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), Error> {
+            let count = check_request(self, lba, buf.len())?;
+            let off = lba as usize * 512;
+            buf.copy_from_slice(&self.data[off..off + buf.len()]);
+            self.reads += count as usize;
+            Ok(())
+        }
+
+        fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> Result<(), Error> {
+            let count = check_request(self, lba, buf.len())?;
+            let off = lba as usize * 512;
+            self.data[off..off + buf.len()].copy_from_slice(buf);
+            self.writes += count as usize;
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn is_read_only(&self) -> bool {
+            self.read_only
+        }
+    }
+
+    #[test]
+    fn readahead_and_writeback() {
+        let mut dev = CountingDev {
+            data: (0..64 * 512).map(|i| (i / 512) as u8).collect(),
+            reads: 0,
+            writes: 0,
+            read_only: false,
+        };
+        let mut cache = BlockCache::new(512, 16 * 512);
+        cache.set_readahead(4);
+        // The budget is spent on blocks of the device's size, packed into pages
+        assert_eq!((cache.slots.len(), cache.pages.len()), (16, 2));
+        assert_eq!(BlockCache::new(4096, 16 * 512).slots.len(), 2);
+
+        // Sequential reads trigger read-ahead, and never read past the end of the device
+        let mut buf = [0u8; 1];
+        for lba in 0..64 {
+            cache.read(&mut dev, lba, 0, &mut buf).unwrap();
+            assert_eq!(buf[0], lba as u8);
+        }
+        let stats = cache.stats();
+        assert_eq!(stats.hits + stats.misses, 64);
+        assert_eq!(stats.readahead as usize + stats.misses as usize, dev.reads);
+        assert_eq!(dev.reads, 64);
+        assert!(stats.misses < 20);
+
+        // Dirty blocks are written back on eviction and flush, partial writes are merged
+        cache.write(&mut dev, 1, 2, &[0xaa, 0xbb]).unwrap();
+        cache.write(&mut dev, 2, 0, &[0xcc; 512]).unwrap();
+        assert!(cache.is_dirty());
+        for lba in 40..60 {
+            cache.read(&mut dev, lba, 0, &mut buf).unwrap();
+        }
+        cache.flush(&mut dev).unwrap();
+        assert!(!cache.is_dirty());
+        assert_eq!(dev.writes, 2);
+        assert_eq!(dev.data[512..516], [1, 1, 0xaa, 0xbb]);
+        assert_eq!(dev.data[2 * 512..3 * 512], [0xcc; 512]);
+    }
+
+    #[test]
+    fn cached_device() {
+        let dev = CountingDev {
+            data: (0..64 * 512).map(|i| (i / 512) as u8).collect(),
+            reads: 0,
+            writes: 0,
+            read_only: false,
+        };
+        let mut dev = CachedDevice::with_budget(dev, 16 * 512);
+        dev.set_readahead(0);
+
+        // Multi-block reads bypass the cache, unless they cover dirty blocks
+        let mut buf = [0u8; 4 * 512];
+        dev.read_blocks(8, &mut buf).unwrap();
+        assert_eq!(buf[3 * 512], 11);
+        assert_eq!(dev.stats().misses, 0);
+
+        dev.write_blocks(10, &[0xaa; 512]).unwrap();
+        dev.read_blocks(8, &mut buf).unwrap();
+        assert_eq!(buf[2 * 512..3 * 512], [0xaa; 512]);
+        assert_eq!(dev.stats().misses, 4);
+        assert_eq!(dev.dev.writes, 0);
+
+        assert_eq!(
+            dev.read_blocks(62, &mut buf),
+            Err(Error::OutOfRange { lba: 62, count: 4 })
+        );
+        dev.flush().unwrap();
+        assert_eq!(dev.dev.writes, 1);
+        assert_eq!(dev.dev.data[10 * 512], 0xaa);
+
+        // Writes to a read-only device fail right away instead of when they are written back
+        dev.dev.read_only = true;
+        assert_eq!(dev.write_blocks(11, &[0xbb; 512]), Err(Error::ReadOnly));
+        assert!(!dev.cache.is_dirty());
+        assert_eq!(dev.dev.data[11 * 512], 11);
+    }
+}
@@ -1,63 +1,383 @@
 // SPDX-License-Identifier: MIT
 #![deny(unsafe_op_in_unsafe_fn)]
 
+use crate::block::{self, BlockDevice};
+use crate::bls;
 use crate::c_size_t;
+use crate::cache::CachedDevice;
+use crate::config;
+use crate::decompress;
 use crate::gpt;
+use crate::hex::{self, Hex};
+use crate::image::{self, Kind};
 use crate::nvme;
 use crate::println;
+use crate::ramdisk::RamDisk;
+use crate::sha256;
+use crate::signature::{self, Verifier};
+use crate::slots;
+use crate::spec::{self, Device, Spec};
+use alloc::ffi::CString;
 use alloc::vec::Vec;
 use core::ffi::{c_char, c_int, c_void, CStr};
-use fatfs::{FileSystem, FsOptions, Read, Seek, SeekFrom};
-use uuid::Uuid;
+use fatfs::{FileSystem, FsOptions, Read, Seek, SeekFrom, Write};
 
 #[derive(Debug)]
 pub enum Error {
-    FATError(fatfs::Error<nvme::Error>),
-    GPTError(gpt::Error<nvme::Error>),
+    FATError(fatfs::Error<block::Error>),
+    GPTError(gpt::Error),
     BadArgs,
+    BadSpec(spec::ParseError),
     PartitionNotFound,
+    NoBootEntry,
+    BadDigestFile,
+    DigestMismatch,
+    Decompress(decompress::Error),
+    Signature(signature::Error),
+    BadImage(Kind),
+    NoBootableSlot,
+    NoRamDisk,
     Unknown,
 }
 
-impl From<fatfs::Error<nvme::Error>> for Error {
-    fn from(err: fatfs::Error<nvme::Error>) -> Error {
+/// Files for booting Linux directly, from a Boot Loader Specification entry.
+pub struct Linux {
+    pub kernel: Vec<u8>,
+    /// All initramfs images, concatenated.
+    pub initrd: Vec<u8>,
+    pub fdt: Option<Vec<u8>>,
+    pub cmdline: CString,
+}
+
+/// [`Linux`] as handed over to C, buffers that weren't loaded are NULL.
+#[repr(C)]
+pub struct LinuxImages {
+    pub kernel: *mut c_void,
+    pub kernel_size: c_size_t,
+    pub initrd: *mut c_void,
+    pub initrd_size: c_size_t,
+    pub fdt: *mut c_void,
+    pub fdt_size: c_size_t,
+    pub cmdline: *mut c_char,
+}
+
+/// A loaded image, with the variables from the configuration file next to it.
+pub struct Image {
+    pub data: Vec<u8>,
+    pub vars: Vec<CString>,
+}
+
+impl From<fatfs::Error<block::Error>> for Error {
+    fn from(err: fatfs::Error<block::Error>) -> Error {
         Error::FATError(err)
     }
 }
 
-impl From<gpt::Error<nvme::Error>> for Error {
-    fn from(err: gpt::Error<nvme::Error>) -> Error {
+impl From<gpt::Error> for Error {
+    fn from(err: gpt::Error) -> Error {
         Error::GPTError(err)
     }
 }
 
-fn load_image(spec: &str) -> Result<Vec<u8>, Error> {
+type Fs<'a> = FileSystem<block::BlockStorage<block::PartitionDevice<&'a mut dyn BlockDevice>>>;
+
+fn parse_spec(spec: &str) -> Result<Vec<Spec>, Error> {
+    spec::parse(spec).map_err(|err| {
+        println!("Bad chainload spec: {}", spec);
+        let column = "Bad chainload spec: ".len() + err.column;
+        println!("{:>1$} {2}", "^", column, err.msg);
+        Error::BadSpec(err)
+    })
+}
+
+/// Run `f` on the filesystem of each alternative in `spec` in turn, until it succeeds.
+fn with_fs<T>(
+    spec: &str,
+    mut f: impl FnMut(&Fs<'_>, &Spec) -> Result<T, Error>,
+) -> Result<T, Error> {
+    let mut ret = Err(Error::PartitionNotFound);
+    for spec in parse_spec(spec)? {
+        println!("Trying {}", spec);
+        ret = with_spec_fs(&spec, &mut f);
+        match &ret {
+            Ok(_) => break,
+            Err(err) => println!("{}: {:?}", spec, err),
+        }
+    }
+    ret
+}
+
+fn with_spec_fs<T>(
+    spec: &Spec,
+    f: &mut impl FnMut(&Fs<'_>, &Spec) -> Result<T, Error>,
+) -> Result<T, Error> {
+    let (nsids, search) = match spec.device {
+        Device::RamDisk => {
+            let mut disk = RamDisk::registered().ok_or(Error::NoRamDisk)?;
+            return with_disk_fs(&mut disk, spec, f);
+        }
+        Device::Nvme(Some(nsid)) => (vec![nsid], false),
+        // Without a namespace all of them are searched in order
+        Device::Nvme(None) => (nvme::namespaces(), true),
+    };
+
+    for nsid in nsids {
+        println!("Trying namespace {}", nsid);
+        let dev = match nvme::NVMEStorage::new(nsid, 0) {
+            Ok(dev) => dev,
+            Err(err) if search => {
+                println!("Skipping namespace {}: {}", nsid, err);
+                continue;
+            }
+            Err(err) => return Err(fatfs::Error::Io(err).into()),
+        };
+        let ret = with_cached_fs(dev, spec, f);
+        match ret {
+            // Not every namespace has a partition table
+            Err(err @ (Error::PartitionNotFound | Error::GPTError(_))) if search => {
+                println!("Not on namespace {}: {:?}", nsid, err);
+            }
+            ret => return ret,
+        }
+    }
+
+    Err(Error::PartitionNotFound)
+}
+
+/// Like [`with_disk_fs`], with a [`CachedDevice`] in front of `dev` that is flushed afterwards.
+fn with_cached_fs<T>(
+    dev: impl BlockDevice,
+    spec: &Spec,
+    f: &mut impl FnMut(&Fs<'_>, &Spec) -> Result<T, Error>,
+) -> Result<T, Error> {
+    let mut disk = CachedDevice::new(dev);
+    let ret = with_disk_fs(&mut disk, spec, f);
+    println!("Cache: {}", disk.stats());
+    let ret = ret?;
+    // Unmounting leaves its writes in the cache
+    disk.flush().map_err(fatfs::Error::Io)?;
+    Ok(ret)
+}
+
+/// Run `f` on the FAT filesystem on `disk`, in the first partition matching all of the selectors
+/// in `spec` or on the whole device if there are none.
+fn with_disk_fs<T>(
+    disk: &mut dyn BlockDevice,
+    spec: &Spec,
+    f: &mut impl FnMut(&Fs<'_>, &Spec) -> Result<T, Error>,
+) -> Result<T, Error> {
+    let dev = if spec.selectors.is_empty() {
+        let end = disk.block_count().saturating_sub(1);
+        block::PartitionDevice::new(disk, 0, end)
+    } else {
+        let part = {
+            let pt = gpt::PartitionTable::new(&mut *disk)?;
+
+            //println!("Partitions:");
+            //pt.dump();
+
+            pt.partitions()
+                .into_iter()
+                .find(|part| spec.selectors.iter().all(|sel| sel.matches(&pt, part)))
+                .ok_or(Error::PartitionNotFound)?
+        };
+
+        println!(
+            "Partition: {}..{}",
+            part.get_starting_lba(),
+            part.get_ending_lba()
+        );
+
+        block::PartitionDevice::for_partition(disk, &part)
+    };
+
+    let storage = block::BlockStorage::new(dev);
+    let opts = FsOptions::new().update_accessed_date(false);
+    let fs = FileSystem::new(storage, opts)?;
+
+    let ret = f(&fs, spec)?;
+    // Writes the FSInfo sector and clears the dirty flag, if anything was written
+    fs.unmount()?;
+    Ok(ret)
+}
+
+fn load_image(spec: &str) -> Result<Image, Error> {
     println!("Chainloading {}", spec);
+    let verifier = Verifier::current();
+    with_fs(spec, |fs, spec| {
+        let load = |spec: &Spec| {
+            let image = load_from_fs(fs, spec, &verifier)?;
+            check_image(&spec.path, &image.data, &[Kind::M1n1Raw, Kind::M1n1MachO])?;
+            Ok(image)
+        };
+        if spec.path.contains(slots::PLACEHOLDER) {
+            load_slot(fs, spec, load)
+        } else {
+            load(spec)
+        }
+    })
+}
+
+/// Load the image from the A/B slot picked by the state file on `fs`, and update the state.
+///
+/// A slot whose image is missing or bad is given up on, and the other one tried straight away,
+/// other errors are returned as they are. A slot that hasn't booted successfully is only booted
+/// once the try taken from it has been saved, otherwise it could be retried forever.
+fn load_slot(
+    fs: &Fs<'_>,
+    spec: &Spec,
+    mut load: impl FnMut(&Spec) -> Result<Image, Error>,
+) -> Result<Image, Error> {
+    let mut saved = match read_file(fs, slots::STATE_PATH) {
+        Ok(text) => slots::State::parse(&text),
+        Err(Error::FATError(fatfs::Error::NotFound)) => slots::State::default(),
+        Err(err) => return Err(err),
+    };
+    let save = |state: &slots::State| {
+        println!("slots: updating {}", slots::STATE_PATH);
+        write_file(fs, slots::STATE_PATH, state.to_text().as_bytes())
+    };
 
-    let mut args = spec.split(';');
+    let mut state = saved;
+    let mut writable = true;
+    let ret = loop {
+        let Some(slot) = state.select() else {
+            println!("slots: no bootable slot left");
+            break Err(Error::NoBootableSlot);
+        };
 
-    let uuid = Uuid::parse_str(args.next().ok_or(Error::BadArgs)?).or(Err(Error::BadArgs))?;
-    let path = args.next().ok_or(Error::BadArgs)?;
+        if !state.slot(slot).successful {
+            if let Err(err) = save(&state) {
+                println!(
+                    "slots: can't take a try from slot {} (is nvme_writes=1 set?): {:?}",
+                    slot, err
+                );
+                // Only for this boot, the state on disk stays as it was
+                state.mark_bad(slot);
+                writable = false;
+                continue;
+            }
+            saved = state;
+        }
+        println!("slots: booting slot {}", slot);
 
-    let part = {
-        let storage = nvme::NVMEStorage::new(1, 0);
-        let mut pt = gpt::GPT::new(storage)?;
+        let slot_spec = Spec {
+            path: slot.path(&spec.path),
+            ..spec.clone()
+        };
+        match load(&slot_spec) {
+            Ok(mut image) => {
+                image.vars.push(slot.chosen_var());
+                break Ok(image);
+            }
+            Err(err) if is_bad_image(&err) => {
+                println!("slots: slot {} failed to load: {:?}", slot, err);
+                state.mark_bad(slot);
+            }
+            // The slot may well be fine, don't give up on it over a flaky read
+            Err(err) => break Err(err),
+        }
+    };
 
-        //println!("Partitions:");
-        //pt.dump();
+    if writable && state != saved {
+        // The slot that is booted is already accounted for, this just records the bad ones
+        if let Err(err) = save(&state) {
+            println!("slots: failed to update the state: {:?}", err);
+        }
+    }
+
+    ret
+}
+
+/// Whether `err` means that the image is missing or bad, rather than that it couldn't be read.
+fn is_bad_image(err: &Error) -> bool {
+    matches!(
+        err,
+        Error::FATError(fatfs::Error::NotFound)
+            | Error::BadDigestFile
+            | Error::DigestMismatch
+            | Error::Decompress(_)
+            | Error::Signature(_)
+            | Error::BadImage(_)
+    )
+}
 
-        println!("Searching for partition UUID: {}", uuid);
-        pt.find_by_partuuid(uuid)?.ok_or(Error::PartitionNotFound)?
+/// Refuse `data` from `path` unless it is an image of one of the `expected` kinds.
+fn check_image(path: &str, data: &[u8], expected: &[Kind]) -> Result<(), Error> {
+    let kind = image::identify(data);
+    if expected.contains(&kind) {
+        return Ok(());
+    }
+
+    println!("{} is a {}, refusing to boot it", path, kind);
+    if kind == Kind::Linux {
+        println!("  Linux kernels are booted from a BLS entry with linux=");
+    }
+    Err(Error::BadImage(kind))
+}
+
+/// Load the file described by `spec` and the configuration file from `fs`.
+fn load_from_fs(fs: &Fs<'_>, spec: &Spec, verifier: &Verifier) -> Result<Image, Error> {
+    let data = read_signed(fs, &spec.path, verifier)?;
+    println!("File read successfully");
+    verify_sha256(fs, spec, &data)?;
+    // Signatures and digests are of the file as stored
+    let data = decompress::decompress(data, decompress::MAX_SIZE).map_err(Error::Decompress)?;
+
+    // The configuration file is optional, but has to be signed like the image
+    let vars = match read_signed(fs, config::CONFIG_PATH, verifier) {
+        Ok(text) => config::parse(&text),
+        Err(Error::FATError(fatfs::Error::NotFound)) => Vec::new(),
+        Err(err @ Error::Signature(_)) => return Err(err),
+        Err(err) => {
+            println!("Failed to read {}: {:?}", config::CONFIG_PATH, err);
+            Vec::new()
+        }
     };
+    println!("Config: {} variables", vars.len());
 
-    let offset = part.get_starting_lba();
+    Ok(Image { data, vars })
+}
 
-    println!("Partition offset: {}", offset);
+/// Check `data` against the SHA-256 digest in `spec`, or else in a `.sha256` file next to it.
+///
+/// Without either, the file is not verified.
+fn verify_sha256(fs: &Fs<'_>, spec: &Spec, data: &[u8]) -> Result<(), Error> {
+    let expected = match spec.sha256 {
+        Some(digest) => digest,
+        None => {
+            let path = format!("{}.sha256", spec.path);
+            let text = match read_file(fs, &path) {
+                Ok(text) => text,
+                Err(Error::FATError(fatfs::Error::NotFound)) => return Ok(()),
+                Err(err) => return Err(err),
+            };
+            // sha256sum output, the digest followed by the file name
+            core::str::from_utf8(&text)
+                .ok()
+                .and_then(|text| text.split_ascii_whitespace().next())
+                .and_then(hex::parse)
+                .ok_or_else(|| {
+                    println!("{}: no SHA-256 digest found", path);
+                    Error::BadDigestFile
+                })?
+        }
+    };
 
-    let storage = nvme::NVMEStorage::new(1, offset);
-    let opts = FsOptions::new().update_accessed_date(false);
+    let digest = sha256::sha256(data);
+    if digest != expected {
+        println!("SHA-256 mismatch, refusing {}", spec.path);
+        println!("  expected: {}", Hex(&expected));
+        println!("  actual:   {}", Hex(&digest));
+        return Err(Error::DigestMismatch);
+    }
+    println!("SHA-256 verified: {}", Hex(&digest));
 
-    let fs = FileSystem::new(storage, opts)?;
+    Ok(())
+}
+
+/// Read the file at `path` from `fs`.
+fn read_file(fs: &Fs<'_>, path: &str) -> Result<Vec<u8>, Error> {
     let mut file = fs.root_dir().open_file(path)?;
 
     let size = file.seek(SeekFrom::End(0))? as usize;
@@ -71,24 +391,163 @@ fn load_image(spec: &str) -> Result<Vec<u8>, Error> {
         let read = file.read(slice)?;
         slice = &mut slice[read..];
     }
-    println!("File read successfully");
 
     Ok(buf)
 }
 
+/// Replace the contents of the file at `path` on `fs` with `data`.
+fn write_file(fs: &Fs<'_>, path: &str, data: &[u8]) -> Result<(), Error> {
+    let mut file = fs.root_dir().create_file(path)?;
+    file.truncate()?;
+    file.write_all(data)?;
+    file.flush()?;
+    Ok(())
+}
+
+/// Read the file at `path` from `fs` and check it against its signature in `<path>.sig`.
+fn read_signed(fs: &Fs<'_>, path: &str, verifier: &Verifier) -> Result<Vec<u8>, Error> {
+    let data = read_file(fs, path)?;
+    if !verifier.is_enabled() {
+        return Ok(data);
+    }
+
+    let sig = match read_file(fs, &format!("{}{}", path, signature::SIG_EXT)) {
+        Ok(sig) => Some(sig),
+        Err(Error::FATError(fatfs::Error::NotFound)) => None,
+        Err(err) => return Err(err),
+    };
+    verifier
+        .check(path, &data, sig.as_deref())
+        .map_err(Error::Signature)?;
+
+    Ok(data)
+}
+
+/// Read all Boot Loader Specification entries on `fs`, in boot menu order.
+///
+/// Entries that fail signature verification are left out.
+fn read_bls_entries(fs: &Fs<'_>, verifier: &Verifier) -> Result<Vec<bls::Entry>, Error> {
+    let mut entries = Vec::new();
+
+    for dirent in fs.root_dir().open_dir(bls::ENTRIES_DIR)?.iter() {
+        let dirent = dirent?;
+        let name = dirent.file_name();
+        if !dirent.is_file() || !name.to_ascii_lowercase().ends_with(".conf") {
+            continue;
+        }
+
+        let text = match read_signed(fs, &format!("{}/{}", bls::ENTRIES_DIR, name), verifier) {
+            Ok(text) => text,
+            Err(Error::Signature(_)) => continue,
+            Err(err) => return Err(err),
+        };
+        match core::str::from_utf8(&text) {
+            Ok(text) => entries.push(bls::Entry::parse(&name, text)),
+            Err(_) => println!("bls: {} is not UTF-8, skipping", name),
+        }
+    }
+
+    bls::sort(&mut entries);
+    Ok(entries)
+}
+
+/// Select the first bootable entry on `fs` whose id matches the glob `pattern`.
+fn select_bls_entry(fs: &Fs<'_>, pattern: &str, verifier: &Verifier) -> Result<bls::Entry, Error> {
+    let entries = read_bls_entries(fs, verifier)?;
+    for entry in &entries {
+        println!("bls: found {} ({})", entry.id, entry.title());
+    }
+
+    let entry = bls::select(&entries, pattern).ok_or(Error::NoBootEntry)?;
+    println!("bls: selected {}", entry.id);
+    Ok(entry.clone())
+}
+
+/// Find the Boot Loader Specification entry to boot on the partition described by `spec`.
+///
+/// The path in the spec is a glob the entry id has to match, `*` picks the first bootable entry.
+pub fn find_bls_entry(spec: &str) -> Result<bls::Entry, Error> {
+    let verifier = Verifier::current();
+    with_fs(spec, |fs, spec| select_bls_entry(fs, &spec.path, &verifier))
+}
+
+/// Load the kernel, initramfs images and devicetree of the BLS entry matching `pattern` on `fs`.
+fn load_linux_from(fs: &Fs<'_>, pattern: &str, verifier: &Verifier) -> Result<Linux, Error> {
+    let entry = select_bls_entry(fs, pattern, verifier)?;
+
+    let kernel = entry.linux.as_deref().ok_or(Error::NoBootEntry)?;
+    let kernel = read_signed(fs, kernel, verifier)?;
+    // The kernel decompresses initramfs images itself, but not its own Image.gz
+    let kernel = decompress::decompress(kernel, decompress::MAX_SIZE).map_err(Error::Decompress)?;
+
+    let mut initrd = Vec::new();
+    for path in &entry.initrd {
+        initrd.extend_from_slice(&read_signed(fs, path, verifier)?);
+        // The kernel skips zero padding between concatenated archives
+        initrd.resize(initrd.len().next_multiple_of(4), 0);
+    }
+
+    let fdt = match &entry.devicetree {
+        Some(path) => Some(read_signed(fs, path, verifier)?),
+        None => None,
+    };
+    if !entry.devicetree_overlay.is_empty() {
+        println!("bls: devicetree overlays are not supported, ignoring them");
+    }
+
+    let cmdline = CString::new(entry.options).map_err(|_| Error::BadArgs)?;
+
+    Ok(Linux {
+        kernel,
+        initrd,
+        fdt,
+        cmdline,
+    })
+}
+
+/// Load the files for booting Linux from the BLS entry described by `spec`.
+///
+/// The path in the spec is a glob the entry id has to match, as for [`find_bls_entry`].
+pub fn load_linux(spec: &str) -> Result<Linux, Error> {
+    println!("Loading Linux from {}", spec);
+    let verifier = Verifier::current();
+    with_fs(spec, |fs, spec| {
+        let linux = load_linux_from(fs, &spec.path, &verifier)?;
+        check_image(&spec.path, &linux.kernel, &[Kind::Linux])?;
+        Ok(linux)
+    })
+}
+
+/// Hand `buf` over to C, as NULL if it is empty.
+fn leak(buf: Vec<u8>) -> (*mut c_void, c_size_t) {
+    if buf.is_empty() {
+        (core::ptr::null_mut(), 0)
+    } else {
+        let len = buf.len();
+        (buf.leak().as_mut_ptr() as *mut c_void, len)
+    }
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn rust_load_image(
     raw_spec: *const c_char,
     image: *mut *mut c_void,
     size: *mut c_size_t,
+    vars: *mut *mut *mut c_char,
+    var_cnt: *mut c_size_t,
 ) -> c_int {
-    let spec = unsafe { CStr::from_ptr(raw_spec).to_str().unwrap() };
+    let spec = unsafe { CStr::from_ptr(raw_spec) }
+        .to_str()
+        .map_err(|_| Error::BadArgs);
 
-    match load_image(spec) {
-        Ok(buf) => {
+    match spec.and_then(load_image) {
+        Ok(Image { data, vars: config }) => {
+            let config: Vec<*mut c_char> = config.into_iter().map(CString::into_raw).collect();
             unsafe {
-                *size = buf.len();
-                *image = buf.leak().as_mut_ptr() as *mut c_void;
+                *size = data.len();
+                *image = data.leak().as_mut_ptr() as *mut c_void;
+                *var_cnt = config.len();
+                *vars = config.leak().as_mut_ptr();
             }
             0
         }
@@ -98,3 +557,591 @@ pub unsafe extern "C" fn rust_load_image(
         }
     }
 }
+
+/// Load the files for booting Linux described by `raw_spec` into `images`.
+///
+/// # Safety
+///
+/// `raw_spec` must be a NUL terminated string and `images` valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn rust_load_linux(
+    raw_spec: *const c_char,
+    images: *mut LinuxImages,
+) -> c_int {
+    let spec = unsafe { CStr::from_ptr(raw_spec) }
+        .to_str()
+        .map_err(|_| Error::BadArgs);
+
+    match spec.and_then(load_linux) {
+        Ok(linux) => {
+            let (kernel, kernel_size) = leak(linux.kernel);
+            let (initrd, initrd_size) = leak(linux.initrd);
+            let (fdt, fdt_size) = leak(linux.fdt.unwrap_or_default());
+            unsafe {
+                *images = LinuxImages {
+                    kernel,
+                    kernel_size,
+                    initrd,
+                    initrd_size,
+                    fdt,
+                    fdt_size,
+                    cmdline: linux.cmdline.into_raw(),
+                };
+            }
+            0
+        }
+        Err(err) => {
+            println!("Loading Linux failed: {:?}", err);
+            -1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::tests::MemDevice;
+    use crate::block::{BlockStorage, PartitionDevice};
+    use crate::gpt::tests::{write_gpt, TestPartition, ESP_TYPE};
+    use crate::signature::Policy;
+    use crate::slots::Slot;
+    use fatfs::{FatType, FormatVolumeOptions};
+    use std::format;
+    use std::string::String;
+
+    const ESP_UUID: &str = "5f3e2b1a-7c4d-4e8f-9a0b-1c2d3e4f5a6b";
+    const KERNEL: &str = "vmlinuz";
+    const LFN_PATH: &str = "EFI/Linux/Linux Kernel Image 6.8.0-asahi.efi";
+
+    const UNVERIFIED: Verifier = Verifier {
+        key: None,
+        policy: Policy::Off,
+    };
+
+    // FAT32 needs at least 65525 clusters, use one block per cluster to keep the images small
+    const ESP_BLOCKS: u64 = 70000;
+
+    fn payload(len: usize, seed: u8) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8 ^ seed).collect()
+    }
+
+    /// A GPT disk with a FAT32 ESP holding a kernel in the root directory and one with a long
+    /// name in a subdirectory.
+    fn esp_image(block_size: usize) -> MemDevice {
+        let start = (1 << 20) / block_size as u64;
+        let end = start + ESP_BLOCKS - 1;
+        // Room for the backup GPT
+        let size = (end + 64) as usize * block_size;
+
+        let mut disk = MemDevice::new(size, block_size);
+        write_gpt(
+            &mut disk,
+            &[TestPartition {
+                type_guid: ESP_TYPE,
+                guid: ESP_UUID,
+                start,
+                end,
+                name: "EFI System Partition",
+            }],
+        );
+
+        let mut storage = BlockStorage::new(PartitionDevice::new(&mut disk, start, end));
+        let opts = FormatVolumeOptions::new()
+            .fat_type(FatType::Fat32)
+            .bytes_per_sector(block_size as u16)
+            .bytes_per_cluster(block_size as u32);
+        fatfs::format_volume(&mut storage, opts).unwrap();
+
+        let fs = FileSystem::new(storage, FsOptions::new()).unwrap();
+        let root = fs.root_dir();
+        root.create_file(KERNEL)
+            .unwrap()
+            .write_all(&payload(300_000, 0))
+            .unwrap();
+        root.create_dir("EFI").unwrap();
+        root.create_dir("EFI/Linux").unwrap();
+        root.create_file(LFN_PATH)
+            .unwrap()
+            .write_all(&payload(12345, 0x5a))
+            .unwrap();
+        fs.unmount().unwrap();
+
+        disk
+    }
+
+    fn spec(path: &str) -> String {
+        format!("{};{}", ESP_UUID, path)
+    }
+
+    fn with_test_fs<T>(
+        disk: &mut dyn BlockDevice,
+        spec: &str,
+        mut f: impl FnMut(&Fs<'_>, &Spec) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let specs = spec::parse(spec).map_err(Error::BadSpec)?;
+        with_disk_fs(disk, &specs[0], &mut f)
+    }
+
+    fn load_image_from(disk: &mut dyn BlockDevice, spec: &str) -> Result<Image, Error> {
+        with_test_fs(disk, spec, |fs, spec| load_from_fs(fs, spec, &UNVERIFIED))
+    }
+
+    fn load(disk: &mut dyn BlockDevice, spec: &str) -> Result<Vec<u8>, Error> {
+        Ok(load_image_from(disk, spec)?.data)
+    }
+
+    #[test]
+    fn gpt_fat32() {
+        let mut disk = esp_image(4096);
+        let buf = load(&mut disk, &spec(KERNEL)).unwrap();
+        assert_eq!(buf, payload(300_000, 0));
+    }
+
+    #[test]
+    fn sectors_512() {
+        let mut disk = esp_image(512);
+        let buf = load(&mut disk, &spec(KERNEL)).unwrap();
+        assert_eq!(buf, payload(300_000, 0));
+    }
+
+    #[test]
+    fn long_filename() {
+        let mut disk = esp_image(4096);
+        let buf = load(&mut disk, &spec(LFN_PATH)).unwrap();
+        assert_eq!(buf, payload(12345, 0x5a));
+    }
+
+    #[test]
+    fn bare_fat() {
+        let mut disk = esp_image(512);
+        let start = (1 << 20) / 512;
+        let mut part = PartitionDevice::new(&mut disk, start, start + ESP_BLOCKS - 1);
+        let buf = load(&mut part, ";vmlinuz").unwrap();
+        assert_eq!(buf, payload(300_000, 0));
+    }
+
+    #[test]
+    fn corrupt_primary_gpt() {
+        let mut disk = esp_image(512);
+        disk.sector_mut(1)[0..8].fill(0);
+        let buf = load(&mut disk, &spec(KERNEL)).unwrap();
+        assert_eq!(buf, payload(300_000, 0));
+
+        let last = disk.block_count() - 1;
+        disk.sector_mut(last)[40] ^= 0xff;
+        assert!(matches!(
+            load(&mut disk, &spec(KERNEL)),
+            Err(Error::GPTError(_))
+        ));
+    }
+
+    #[test]
+    fn selectors() {
+        let mut disk = esp_image(512);
+        let buf = load(
+            &mut disk,
+            "parttype=esp,partlabel=\"EFI System Partition\";vmlinuz",
+        );
+        assert_eq!(buf.unwrap(), payload(300_000, 0));
+
+        assert!(matches!(
+            load(&mut disk, "partlabel=ESP;vmlinuz"),
+            Err(Error::PartitionNotFound)
+        ));
+        assert!(matches!(
+            load(&mut disk, "00000000-0000-0000-0000-000000000001;vmlinuz"),
+            Err(Error::PartitionNotFound)
+        ));
+        assert!(matches!(
+            load(&mut disk, &spec("initrd.img")),
+            Err(Error::FATError(fatfs::Error::NotFound))
+        ));
+        assert!(matches!(load(&mut disk, "vmlinuz"), Err(Error::BadSpec(_))));
+    }
+
+    /// Add `files` to the ESP of an `esp_image()`, creating their directories.
+    fn add_files(disk: &mut MemDevice, files: &[(&str, &[u8])]) {
+        let start = (1 << 20) / disk.block_size() as u64;
+        let part = PartitionDevice::new(&mut *disk, start, start + ESP_BLOCKS - 1);
+        let fs = FileSystem::new(BlockStorage::new(part), FsOptions::new()).unwrap();
+        for (path, data) in files {
+            for (i, _) in path.match_indices('/') {
+                fs.root_dir().create_dir(&path[..i]).unwrap();
+            }
+            let mut file = fs.root_dir().create_file(path).unwrap();
+            file.truncate().unwrap();
+            file.write_all(data).unwrap();
+        }
+        fs.unmount().unwrap();
+    }
+
+    #[test]
+    fn config_file() {
+        let mut disk = esp_image(4096);
+        let image = load_image_from(&mut disk, &spec(KERNEL)).unwrap();
+        assert!(image.vars.is_empty());
+
+        add_files(
+            &mut disk,
+            &[(
+                config::CONFIG_PATH,
+                b"# comment\nchosen.bootargs=quiet\ndisplay=1920x1080\n",
+            )],
+        );
+
+        let image = load_image_from(&mut disk, &spec(KERNEL)).unwrap();
+        assert_eq!(image.data, payload(300_000, 0));
+        assert_eq!(
+            image.vars,
+            [c"chosen.bootargs=quiet", c"display=1920x1080"].map(CString::from)
+        );
+    }
+
+    #[test]
+    fn ab_slots() {
+        let mut disk = esp_image(4096);
+        add_files(
+            &mut disk,
+            &[
+                ("m1n1/boot-a.bin", b"new"),
+                ("m1n1/boot-b.bin", b"old"),
+                (slots::STATE_PATH, b"active=a\na.tries=1\na.successful=0\n"),
+            ],
+        );
+        let boot = |disk: &mut MemDevice, path: &str| {
+            with_test_fs(disk, &spec(path), |fs, spec| {
+                load_slot(fs, spec, |spec| load_from_fs(fs, spec, &UNVERIFIED))
+            })
+        };
+        let state = |disk: &mut MemDevice| {
+            with_test_fs(disk, &spec(KERNEL), |fs, _| {
+                read_file(fs, slots::STATE_PATH)
+            })
+            .map(|text| slots::State::parse(&text))
+            .unwrap()
+        };
+
+        // The last try of the new slot, then back to the old one
+        let image = boot(&mut disk, "m1n1/boot-{slot}.bin").unwrap();
+        assert_eq!(image.data, b"new");
+        assert_eq!(
+            image.vars,
+            [c"chosen.asahi,m1n1-boot-slot=a"].map(CString::from)
+        );
+        assert_eq!(state(&mut disk).slot(Slot::A).tries, 0);
+        assert_eq!(
+            boot(&mut disk, "m1n1/boot-{slot}.bin").unwrap().data,
+            b"old"
+        );
+
+        // A slot that doesn't load is given up on right away
+        add_files(
+            &mut disk,
+            &[
+                ("m1n1/a-only.bin", b"a"),
+                (slots::STATE_PATH, b"active=b\n"),
+            ],
+        );
+        assert_eq!(boot(&mut disk, "m1n1/{slot}-only.bin").unwrap().data, b"a");
+        assert!(!state(&mut disk).slot(Slot::B).is_bootable());
+
+        // An I/O error doesn't say anything about the slot
+        let state_before = state(&mut disk);
+        let ret = with_test_fs(&mut disk, &spec("m1n1/boot-{slot}.bin"), |fs, spec| {
+            load_slot(fs, spec, |_| {
+                Err(Error::FATError(fatfs::Error::Io(block::Error::Io {
+                    lba: 0,
+                    count: 1,
+                })))
+            })
+        });
+        assert!(matches!(ret, Err(Error::FATError(fatfs::Error::Io(_)))));
+        assert_eq!(state(&mut disk), state_before);
+
+        // And with neither, chainloading fails
+        assert!(matches!(
+            boot(&mut disk, "m1n1/missing-{slot}.bin"),
+            Err(Error::NoBootableSlot)
+        ));
+    }
+
+    #[test]
+    fn ab_slots_cached() {
+        let mut disk = esp_image(4096);
+        add_files(
+            &mut disk,
+            &[
+                ("m1n1/boot-a.bin", b"new"),
+                ("m1n1/boot-b.bin", b"old"),
+                (slots::STATE_PATH, b"active=a\na.tries=2\na.successful=0\n"),
+            ],
+        );
+
+        let specs = spec::parse(&spec("m1n1/boot-{slot}.bin")).unwrap();
+        let image = with_cached_fs(&mut disk, &specs[0], &mut |fs, spec| {
+            load_slot(fs, spec, |spec| load_from_fs(fs, spec, &UNVERIFIED))
+        })
+        .unwrap();
+        assert_eq!(image.data, b"new");
+
+        // Both the new state and the clean unmount made it out of the cache
+        let (text, dirty) = with_test_fs(&mut disk, &spec(KERNEL), |fs, _| {
+            Ok((
+                read_file(fs, slots::STATE_PATH)?,
+                fs.read_status_flags()?.dirty(),
+            ))
+        })
+        .unwrap();
+        assert_eq!(slots::State::parse(&text).slot(Slot::A).tries, 1);
+        assert!(!dirty);
+    }
+
+    /// A device that fails all writes, like the SSD without `nvme_writes=1`.
+    struct ReadOnlyDevice<'a>(&'a mut MemDevice);
+
+    impl BlockDevice for ReadOnlyDevice<'_> {
+        fn block_size(&self) -> usize {
+            self.0.block_size()
+        }
+        fn block_count(&self) -> u64 {
+            self.0.block_count()
+        }
+        fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), block::Error> {
+            self.0.read_blocks(lba, buf)
+        }
+        fn write_blocks(&mut self, _lba: u64, _buf: &[u8]) -> Result<(), block::Error> {
+            Err(block::Error::ReadOnly)
+        }
+        fn flush(&mut self) -> Result<(), block::Error> {
+            Ok(())
+        }
+        fn is_read_only(&self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn ab_slots_read_only() {
+        let mut disk = esp_image(4096);
+        let boot = |disk: &mut MemDevice, state: &[u8]| {
+            add_files(
+                disk,
+                &[
+                    ("m1n1/boot-a.bin", b"new"),
+                    ("m1n1/boot-b.bin", b"old"),
+                    (slots::STATE_PATH, state),
+                ],
+            );
+            let image = with_test_fs(
+                &mut ReadOnlyDevice(disk),
+                &spec("m1n1/boot-{slot}.bin"),
+                |fs, spec| load_slot(fs, spec, |spec| load_from_fs(fs, spec, &UNVERIFIED)),
+            );
+            let text = with_test_fs(disk, &spec(KERNEL), |fs, _| {
+                read_file(fs, slots::STATE_PATH)
+            });
+            assert_eq!(text.unwrap(), state);
+            image
+        };
+
+        // The new slot can't have a try taken, so the old one boots
+        let image = boot(&mut disk, b"active=a\na.tries=3\na.successful=0\n").unwrap();
+        assert_eq!(image.data, b"old");
+        assert_eq!(
+            image.vars,
+            [c"chosen.asahi,m1n1-boot-slot=b"].map(CString::from)
+        );
+
+        // Successful slots don't need the state to change
+        let state = b"active=b\na.tries=3\na.successful=0\nb.successful=1\n";
+        assert_eq!(boot(&mut disk, state).unwrap().data, b"old");
+        assert_eq!(boot(&mut disk, b"active=a\n").unwrap().data, b"new");
+
+        // Without a successful slot to fall back to, chainloading fails
+        let state = b"active=a\na.tries=3\na.successful=0\nb.tries=1\nb.successful=0\n";
+        assert!(matches!(boot(&mut disk, state), Err(Error::NoBootableSlot)));
+    }
+
+    #[test]
+    fn bls_entries() {
+        let mut disk = esp_image(512);
+        let find = |disk: &mut MemDevice, spec: &str| {
+            with_test_fs(disk, spec, |fs, spec| {
+                select_bls_entry(fs, &spec.path, &UNVERIFIED)
+            })
+        };
+        assert!(matches!(
+            find(&mut disk, "parttype=esp;*"),
+            Err(Error::FATError(fatfs::Error::NotFound))
+        ));
+
+        add_files(
+            &mut disk,
+            &[
+                (
+                    "loader/entries/asahi-6.8.conf",
+                    b"title Asahi 6.8\nlinux /vmlinuz-6.8\ninitrd /initrd-6.8\n",
+                ),
+                (
+                    "loader/entries/asahi-6.10+0-3.conf",
+                    b"title Asahi 6.10\nlinux /vmlinuz-6.10\n",
+                ),
+                (
+                    "loader/entries/asahi-6.9.conf",
+                    b"title Asahi 6.9\nlinux /vmlinuz-6.9\noptions quiet\n",
+                ),
+                ("loader/entries/README.txt", b"not an entry"),
+            ],
+        );
+
+        let entry = find(&mut disk, "parttype=esp;*").unwrap();
+        assert_eq!(entry.id, "asahi-6.9");
+        assert_eq!(entry.linux.as_deref(), Some("vmlinuz-6.9"));
+        assert_eq!(entry.options, "quiet");
+
+        let entry = find(&mut disk, "parttype=esp;*6.10").unwrap();
+        assert_eq!(entry.title(), "Asahi 6.10");
+        assert!(entry.is_bad());
+
+        let entry = find(&mut disk, "parttype=esp;\"asahi-6.[8]\"");
+        assert!(matches!(entry, Err(Error::NoBootEntry)));
+    }
+
+    #[test]
+    fn linux_from_bls() {
+        let mut disk = esp_image(4096);
+        add_files(
+            &mut disk,
+            &[
+                (
+                    "loader/entries/asahi.conf",
+                    b"linux /asahi/Image\n\
+                      initrd /asahi/initramfs.img\n\
+                      initrd /asahi/firmware.cpio\n\
+                      devicetree /asahi/t8103-j274.dtb\n\
+                      options root=LABEL=asahi\n\
+                      options quiet\n",
+                ),
+                ("loader/entries/rescue.conf", b"linux /rescue/Image\n"),
+                ("asahi/Image", &payload(100_000, 1)),
+                ("asahi/initramfs.img", &payload(4097, 2)),
+                ("asahi/firmware.cpio", &payload(100, 3)),
+                ("asahi/t8103-j274.dtb", &payload(5000, 4)),
+                ("rescue/Image", &payload(5000, 5)),
+            ],
+        );
+        let load = |disk: &mut MemDevice, spec: &str| {
+            with_test_fs(disk, spec, |fs, spec| {
+                load_linux_from(fs, &spec.path, &UNVERIFIED)
+            })
+        };
+
+        let linux = load(&mut disk, "parttype=esp;asahi").unwrap();
+        assert_eq!(linux.kernel, payload(100_000, 1));
+        let mut initrd = payload(4097, 2);
+        initrd.extend([0; 3]);
+        initrd.extend(payload(100, 3));
+        assert_eq!(linux.initrd, initrd);
+        assert_eq!(linux.fdt, Some(payload(5000, 4)));
+        assert_eq!(linux.cmdline.to_str().unwrap(), "root=LABEL=asahi quiet");
+
+        let linux = load(&mut disk, "parttype=esp;*").unwrap();
+        assert_eq!(linux.kernel, payload(5000, 5));
+        assert!(linux.initrd.is_empty());
+        assert!(linux.fdt.is_none());
+        assert!(linux.cmdline.is_empty());
+    }
+
+    #[test]
+    fn sha256_digests() {
+        let mut disk = esp_image(4096);
+        let digest = Hex(&sha256::sha256(&payload(300_000, 0))).to_string();
+        let bad = Hex(&sha256::sha256(b"")).to_string();
+
+        let buf = load(&mut disk, &format!("{};sha256={}", spec(KERNEL), digest));
+        assert_eq!(buf.unwrap(), payload(300_000, 0));
+        assert!(matches!(
+            load(&mut disk, &format!("{};sha256={}", spec(KERNEL), bad)),
+            Err(Error::DigestMismatch)
+        ));
+
+        let sidecar = format!("{}  vmlinuz\n", digest.to_uppercase());
+        add_files(
+            &mut disk,
+            &[
+                ("vmlinuz.sha256", sidecar.as_bytes()),
+                (
+                    "EFI/Linux/Linux Kernel Image 6.8.0-asahi.efi.sha256",
+                    bad.as_bytes(),
+                ),
+            ],
+        );
+        assert_eq!(load(&mut disk, &spec(KERNEL)).unwrap(), payload(300_000, 0));
+        assert!(matches!(
+            load(&mut disk, &spec(LFN_PATH)),
+            Err(Error::DigestMismatch)
+        ));
+        // The digest in the spec takes precedence
+        let buf = load(&mut disk, &format!("{};sha256={}", spec(KERNEL), digest));
+        assert!(buf.is_ok());
+
+        add_files(&mut disk, &[("vmlinuz.sha256", b"vmlinuz: OK\n")]);
+        assert!(matches!(
+            load(&mut disk, &spec(KERNEL)),
+            Err(Error::BadDigestFile)
+        ));
+    }
+
+    #[test]
+    fn signatures() {
+        let mut disk = esp_image(4096);
+        let verifier = |policy| Verifier {
+            key: hex::parse("03a107bff3ce10be1d70dd18e74bc09967e4d6309ba50d5f1ddc8664125531b8"),
+            policy,
+        };
+        let load = |disk: &mut MemDevice, spec: &str, verifier: &Verifier| {
+            with_test_fs(disk, spec, |fs, spec| load_from_fs(fs, spec, verifier))
+        };
+
+        let enforce = verifier(Policy::Enforce);
+        assert!(matches!(
+            load(&mut disk, &spec(KERNEL), &enforce),
+            Err(Error::Signature(signature::Error::NoSignature))
+        ));
+
+        add_files(
+            &mut disk,
+            &[
+                (
+                    "vmlinuz.sig",
+                    b"a045f361493318e577e134d553a0fe776462dea2f356f31be6b1012a52aaaef8\
+                      7ac7e03f7bc801c383459e15aea53c06ddae1afd054eea00fe1c44c54ac2780d",
+                ),
+                ("EFI/Linux/Linux Kernel Image 6.8.0-asahi.efi.sig", &[0; 64]),
+            ],
+        );
+        let image = load(&mut disk, &spec(KERNEL), &enforce).unwrap();
+        assert_eq!(image.data, payload(300_000, 0));
+        assert!(matches!(
+            load(&mut disk, &spec(LFN_PATH), &enforce),
+            Err(Error::Signature(signature::Error::BadSignature))
+        ));
+        let image = load(&mut disk, &spec(LFN_PATH), &verifier(Policy::Warn));
+        assert_eq!(image.unwrap().data, payload(12345, 0x5a));
+
+        // The configuration file needs a signature too
+        add_files(&mut disk, &[(config::CONFIG_PATH, b"display=1920x1080\n")]);
+        assert!(matches!(
+            load(&mut disk, &spec(KERNEL), &enforce),
+            Err(Error::Signature(signature::Error::NoSignature))
+        ));
+        let sig = hex::parse::<64>(
+            "db90b62b82693c681ada03d09b98ecff853be493ebbc0a1aa4df0ec5ff6c9a84\
+             39d550495ec67cfe76f4da9b2ed7036c1b5b4fad28b9cc4fa9462a937c383f0f",
+        )
+        .unwrap();
+        add_files(&mut disk, &[("m1n1/m1n1.conf.sig", &sig)]);
+        let image = load(&mut disk, &spec(KERNEL), &enforce).unwrap();
+        assert_eq!(image.vars, [CString::from(c"display=1920x1080")]);
+    }
+}
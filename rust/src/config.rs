@@ -0,0 +1,92 @@
+// SPDX-License-Identifier: MIT
+
+//! m1n1 configuration file on the chainload partition.
+//!
+//! The file holds one `name=value` variable per line, with the same meaning as variables appended
+//! to m1n1 as payloads. Blank lines and lines starting with `#` are ignored.
+
+use crate::println;
+use alloc::ffi::CString;
+use alloc::vec::Vec;
+
+/// Path of the configuration file, relative to the root of the chainload partition.
+pub const CONFIG_PATH: &str = "m1n1/m1n1.conf";
+
+// Limits enforced by check_var() in payload.c
+const MAX_VAR_NAME: usize = 64;
+const MAX_VAR_SIZE: usize = 1024;
+
+/// Parse a configuration file into variables.
+///
+/// Malformed lines are reported and skipped, so a typo doesn't prevent booting.
+pub fn parse(text: &[u8]) -> Vec<CString> {
+    let mut vars = Vec::new();
+
+    for (i, line) in text.split(|&c| c == b'\n').enumerate() {
+        let line = line.trim_ascii();
+        if line.is_empty() || line[0] == b'#' {
+            continue;
+        }
+
+        let name_len = match line.iter().position(|&c| c == b'=') {
+            Some(len) if len > 0 && len <= MAX_VAR_NAME => len,
+            _ => {
+                println!("config: line {}: expected name=value", i + 1);
+                continue;
+            }
+        };
+        if line.len() - name_len - 1 > MAX_VAR_SIZE {
+            println!("config: line {}: value too long", i + 1);
+            continue;
+        }
+
+        match CString::new(line) {
+            Ok(var) => vars.push(var),
+            Err(_) => println!("config: line {}: unexpected NUL", i + 1),
+        }
+    }
+
+    vars
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::string::String;
+
+    fn parse_str(text: &str) -> Vec<String> {
+        parse(text.as_bytes())
+            .into_iter()
+            .map(|var| var.into_string().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn variables() {
+        let text = "# boot options\r\n\
+                    display=wait,3840x2160\r\n\
+                    \n   \n\
+                    \tchosen.bootargs=quiet splash root=LABEL=asahi  \n\
+                    mitigations=off";
+        assert_eq!(
+            parse_str(text),
+            [
+                "display=wait,3840x2160",
+                "chosen.bootargs=quiet splash root=LABEL=asahi",
+                "mitigations=off",
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed() {
+        let long_name = format!("{}=1", "x".repeat(MAX_VAR_NAME + 1));
+        let long_value = format!("chosen.x={}", "y".repeat(MAX_VAR_SIZE + 1));
+        let max_value = format!("chosen.x={}", "y".repeat(MAX_VAR_SIZE));
+        let text = format!(
+            "tso\n=1\n{}\n{}\n{}\nbad=\0\ntso=1\n",
+            long_name, long_value, max_value
+        );
+        assert_eq!(parse_str(&text), [max_value.as_str(), "tso=1"]);
+    }
+}
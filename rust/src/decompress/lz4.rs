@@ -0,0 +1,231 @@
+// SPDX-License-Identifier: MIT
+
+//! LZ4 frames, and the legacy format Linux compresses its kernel images with.
+
+use super::xxhash::xxh32;
+use super::{take, take_le, take_u8, Error, Output};
+use crate::println;
+use alloc::vec::Vec;
+
+const MAGIC: u32 = 0x184d2204;
+const LEGACY_MAGIC: u32 = 0x184c2102;
+const SKIPPABLE_MAGIC: u32 = 0x184d2a50;
+const SKIPPABLE_MASK: u32 = 0xfffffff0;
+
+// Legacy frames are made of independent blocks of up to 8 MiB
+const LEGACY_BLOCK_SIZE: usize = 8 << 20;
+
+fn magic(data: &[u8]) -> Option<u32> {
+    Some(u32::from_le_bytes(*data.first_chunk::<4>()?))
+}
+
+fn is_magic(magic: u32) -> bool {
+    magic == MAGIC || magic == LEGACY_MAGIC || magic & SKIPPABLE_MASK == SKIPPABLE_MAGIC
+}
+
+pub(super) fn is_frame(data: &[u8]) -> bool {
+    matches!(magic(data), Some(MAGIC | LEGACY_MAGIC))
+}
+
+/// Decode all frames in `data`.
+pub(super) fn decompress(mut data: &[u8], limit: usize) -> Result<Vec<u8>, Error> {
+    let mut out = Output::new(limit);
+
+    while let Some(magic) = magic(data).filter(|&magic| is_magic(magic)) {
+        data = &data[4..];
+        match magic {
+            MAGIC => frame(&mut data, &mut out)?,
+            LEGACY_MAGIC => legacy_frame(&mut data, &mut out)?,
+            _ => {
+                let len = take_le(&mut data, 4)? as usize;
+                take(&mut data, len)?;
+            }
+        }
+    }
+    if !data.is_empty() {
+        println!("lz4: ignoring {} bytes after the last frame", data.len());
+    }
+
+    Ok(out.buf)
+}
+
+fn frame(data: &mut &[u8], out: &mut Output) -> Result<(), Error> {
+    let descriptor = *data;
+    let flags = take_u8(data)?;
+    let block_desc = take_u8(data)?;
+    if flags >> 6 != 1 {
+        return Err(Error::Unsupported("lz4 frame version"));
+    }
+    if flags & 0x02 != 0 || block_desc & 0x8f != 0 {
+        return Err(Error::Corrupt("lz4 reserved bits set"));
+    }
+    let block_checksum = flags & 0x10 != 0;
+    let content_checksum = flags & 0x04 != 0;
+    let max_block_size = match block_desc >> 4 & 7 {
+        size @ 4..=7 => 1 << (8 + 2 * size),
+        _ => return Err(Error::Corrupt("lz4 block size")),
+    };
+
+    let content_size = match flags & 0x08 {
+        0 => None,
+        _ => Some(take_le(data, 8)?),
+    };
+    if flags & 0x01 != 0 {
+        return Err(Error::Unsupported("lz4 dictionaries"));
+    }
+    let descriptor = &descriptor[..descriptor.len() - data.len()];
+    if take_u8(data)? != (xxh32(descriptor, 0) >> 8) as u8 {
+        return Err(Error::BadChecksum);
+    }
+
+    let start = out.len();
+    if let Some(size) = content_size {
+        out.reserve(usize::try_from(size).map_err(|_| Error::TooLarge)?)?;
+    }
+
+    loop {
+        let size = take_le(data, 4)? as u32;
+        if size == 0 {
+            break;
+        }
+        let len = (size & 0x7fffffff) as usize;
+        if len > max_block_size {
+            return Err(Error::Corrupt("lz4 block too large"));
+        }
+        let block = take(data, len)?;
+        if block_checksum && take_le(data, 4)? as u32 != xxh32(block, 0) {
+            return Err(Error::BadChecksum);
+        }
+
+        if size & 0x80000000 != 0 {
+            out.extend(block)?;
+        } else {
+            decode_block(block, out, start)?;
+        }
+    }
+
+    let content = &out.buf[start..];
+    if content_size.is_some_and(|size| size != content.len() as u64) {
+        return Err(Error::Corrupt("lz4 content size mismatch"));
+    }
+    if content_checksum && take_le(data, 4)? as u32 != xxh32(content, 0) {
+        return Err(Error::BadChecksum);
+    }
+
+    Ok(())
+}
+
+fn legacy_frame(data: &mut &[u8], out: &mut Output) -> Result<(), Error> {
+    // The frame simply ends with the input, or where the next one starts
+    while let Some(size) = magic(data) {
+        // Linux appends the uncompressed size to its compressed images
+        if is_magic(size) || data.len() == 4 {
+            break;
+        }
+        *data = &data[4..];
+
+        let block = take(data, size as usize)?;
+        let start = out.len();
+        decode_block(block, out, start)?;
+        if out.len() - start > LEGACY_BLOCK_SIZE {
+            return Err(Error::Corrupt("lz4 block too large"));
+        }
+    }
+
+    Ok(())
+}
+
+/// Read the rest of a length whose first 4 bits were `len`.
+fn length(block: &mut &[u8], mut len: usize) -> Result<usize, Error> {
+    if len == 15 {
+        loop {
+            let byte = take_u8(block)?;
+            len += byte as usize;
+            if byte != 255 {
+                break;
+            }
+        }
+    }
+    Ok(len)
+}
+
+/// Decode a compressed block, whose matches can reach back to `start`.
+fn decode_block(mut block: &[u8], out: &mut Output, start: usize) -> Result<(), Error> {
+    loop {
+        let token = take_u8(&mut block)?;
+
+        let literals = length(&mut block, (token >> 4) as usize)?;
+        out.extend(take(&mut block, literals)?)?;
+        // The last sequence is only literals
+        if block.is_empty() {
+            return Ok(());
+        }
+
+        let offset = take_le(&mut block, 2)? as usize;
+        let len = length(&mut block, (token & 0xf) as usize)? + 4;
+        out.copy_match(start, offset, len)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::lines;
+    use super::*;
+    use crate::hex;
+
+    // lz4 -9 -BX of lines(40), with block and content checksums
+    const LINES: &str = "04224d187440bd74010000ff116d316e3120636861696e6c6f61642074657374206c696e652030206f66\
+                         20300a200006103120001f31200007103220001f34200007103320001f39200007113460001f36210007\
+                         103521002f3235210007103621001f334200081137a3000f840008103821001f36c50008103921001f38\
+                         060108113147011f332100080148011f3264000812314a011f37220008014c012f3732220008014e010f\
+                         210009014e011f33ca0009014f011f364400090150011f39920108113151011f330f01090152011f3778\
+                         0208113252011f31880008113253011f35660008113253011f391b0208113253011f3497010811325301\
+                         1f390203090154011f348800090154011f396600090154011f351001090154011f382100080153011f36\
+                         970108113353011f32620208113353011f38660008113353011f35f103090153011f32b90108113353011f\
+                         38910308113353011f367501081233f6030fee00090153011f314400090253010f1f0208903339206f66\
+                         2036360a0e7eeaca00000000069d7c3a";
+
+    #[test]
+    fn frames() {
+        let data = hex::decode(LINES).unwrap();
+        assert_eq!(decompress(&data, 1 << 20), Ok(lines(40)));
+
+        // The same block in a legacy frame, twice, with the size Linux appends
+        let block = &data[11..data.len() - 12];
+        let mut legacy = LEGACY_MAGIC.to_le_bytes().to_vec();
+        for _ in 0..2 {
+            legacy.extend((block.len() as u32).to_le_bytes());
+            legacy.extend(block);
+        }
+        legacy.extend(2686u32.to_le_bytes());
+        assert_eq!(decompress(&legacy, 1 << 20), Ok(lines(40).repeat(2)));
+
+        // Followed by a skippable frame and the first one again
+        legacy.truncate(legacy.len() - 4);
+        legacy.extend(hex::decode("582a4d180100000000").unwrap());
+        legacy.extend(&data);
+        assert_eq!(decompress(&legacy, 1 << 20), Ok(lines(40).repeat(3)));
+    }
+
+    #[test]
+    fn corrupt() {
+        let data = hex::decode(LINES).unwrap();
+        assert_eq!(decompress(&data, 1342), Err(Error::TooLarge));
+        assert_eq!(decompress(&data[..100], 1 << 20), Err(Error::Truncated));
+
+        // Every byte is covered by a checksum
+        for i in 4..data.len() {
+            let mut bad = data.clone();
+            bad[i] ^= 0x10;
+            assert!(decompress(&bad, 1 << 20).is_err(), "flipped byte {}", i);
+        }
+
+        // A match reaching back before the start of the block
+        let mut out = Output::new(100);
+        out.extend(b"abcd").unwrap();
+        assert_eq!(
+            decode_block(&[0x10, b'x', 0x02, 0x00], &mut out, 4),
+            Err(Error::Corrupt("match offset out of range"))
+        );
+    }
+}
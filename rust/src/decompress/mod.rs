@@ -0,0 +1,401 @@
+// SPDX-License-Identifier: MIT
+
+//! Transparent decompression of gzip, xz, zstd and lz4 images.
+//!
+//! gzip and xz go through the same C decoders as payloads (tinf and minilzlib), zstd and lz4 are
+//! decoded here. Output is never allowed to grow past a caller supplied limit, or past what is
+//! left of the heap, so a hostile or corrupt image can't exhaust it.
+
+mod lz4;
+mod xxhash;
+mod zstd;
+
+use crate::c_size_t;
+use crate::println;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// Largest image we are willing to decompress.
+pub const MAX_SIZE: usize = 1 << 30;
+
+// Heap left for everything that runs after decompression
+const HEAP_RESERVE: usize = 64 << 20;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Format {
+    Gzip,
+    Xz,
+    Zstd,
+    Lz4,
+}
+
+impl Format {
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Gzip => "gzip",
+            Format::Xz => "xz",
+            Format::Zstd => "zstd",
+            Format::Lz4 => "lz4",
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The output would exceed the size limit.
+    TooLarge,
+    /// The output doesn't fit in what is left of the heap.
+    OutOfMemory,
+    /// The input ended in the middle of a stream.
+    Truncated,
+    Corrupt(&'static str),
+    BadChecksum,
+    Unsupported(&'static str),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::TooLarge => f.write_str("output too large"),
+            Error::OutOfMemory => f.write_str("out of memory"),
+            Error::Truncated => f.write_str("truncated input"),
+            Error::Corrupt(what) => write!(f, "corrupt input: {}", what),
+            Error::BadChecksum => f.write_str("checksum mismatch"),
+            Error::Unsupported(what) => write!(f, "unsupported: {}", what),
+        }
+    }
+}
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+
+/// Identify compressed data by its magic number.
+pub fn detect(data: &[u8]) -> Option<Format> {
+    if data.starts_with(GZIP_MAGIC) {
+        Some(Format::Gzip)
+    } else if data.starts_with(XZ_MAGIC) {
+        Some(Format::Xz)
+    } else if zstd::is_frame(data) {
+        Some(Format::Zstd)
+    } else if lz4::is_frame(data) {
+        Some(Format::Lz4)
+    } else {
+        None
+    }
+}
+
+/// Decompress `data` if it is compressed in a format we know, or return it unchanged.
+///
+/// The compressed buffer is freed as soon as it has been decoded.
+pub fn decompress(data: Vec<u8>, limit: usize) -> Result<Vec<u8>, Error> {
+    let Some(format) = detect(&data) else {
+        return Ok(data);
+    };
+
+    println!("Decompressing {} image...", format.name());
+    let out = match format {
+        Format::Gzip => gunzip(&data, limit),
+        Format::Xz => unxz(&data, limit),
+        Format::Zstd => zstd::decompress(&data, limit),
+        Format::Lz4 => lz4::decompress(&data, limit),
+    };
+    match &out {
+        Ok(out) => println!("{} bytes uncompressed to {} bytes", data.len(), out.len()),
+        Err(err) => println!("{} decompression failed: {}", format.name(), err),
+    }
+    out
+}
+
+/// Output buffer of a decoder, which refuses to grow past the limit.
+struct Output {
+    buf: Vec<u8>,
+    limit: usize,
+}
+
+impl Output {
+    fn new(limit: usize) -> Output {
+        Output {
+            buf: Vec::new(),
+            limit,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Make room for `len` more bytes, without overshooting the limit or the heap like doubling
+    /// would.
+    fn reserve(&mut self, len: usize) -> Result<(), Error> {
+        let needed = self.buf.len().checked_add(len).ok_or(Error::TooLarge)?;
+        if needed > self.limit {
+            return Err(Error::TooLarge);
+        }
+        if needed > self.buf.capacity() {
+            // The bigger buffer is allocated before the old one is freed
+            let capacity = needed
+                .max(2 * self.buf.capacity())
+                .min(self.limit)
+                .min(heap_available());
+            if capacity < needed {
+                return Err(Error::OutOfMemory);
+            }
+            self.buf.reserve_exact(capacity - self.buf.len());
+        }
+        Ok(())
+    }
+
+    fn extend(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.reserve(data.len())?;
+        self.buf.extend_from_slice(data);
+        Ok(())
+    }
+
+    fn fill(&mut self, byte: u8, len: usize) -> Result<(), Error> {
+        self.reserve(len)?;
+        self.buf.resize(self.buf.len() + len, byte);
+        Ok(())
+    }
+
+    /// Append `len` bytes copied from `offset` bytes back, where the copy may overlap itself.
+    ///
+    /// Matches can't reach back past `start`, the beginning of the current frame.
+    fn copy_match(&mut self, start: usize, offset: usize, len: usize) -> Result<(), Error> {
+        if offset == 0 || offset > self.buf.len() - start {
+            return Err(Error::Corrupt("match offset out of range"));
+        }
+        self.reserve(len)?;
+
+        let from = self.buf.len() - offset;
+        if offset >= len {
+            self.buf.extend_from_within(from..from + len);
+        } else {
+            for i in from..from + len {
+                self.buf.push(self.buf[i]);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(test))]
+extern "C" {
+    fn heapblock_free() -> c_size_t;
+}
+
+#[cfg(test)]
+use tests::heapblock_free;
+
+/// How much more can be allocated, keeping `HEAP_RESERVE` for later. Memory freed back to the
+/// allocator isn't counted, so this errs on the small side.
+fn heap_available() -> usize {
+    unsafe { heapblock_free() }.saturating_sub(HEAP_RESERVE)
+}
+
+/// A zeroed buffer of `size` bytes, for decoders that need all of their output space up front.
+fn output_buffer(size: usize, limit: usize) -> Result<Vec<u8>, Error> {
+    if size > limit {
+        return Err(Error::TooLarge);
+    }
+    if size > heap_available() {
+        return Err(Error::OutOfMemory);
+    }
+    Ok(vec![0u8; size])
+}
+
+/// The uncompressed size from the trailer of a gzip stream.
+///
+/// It's only the size modulo 2^32, which is good enough with limits below that.
+fn gzip_size(data: &[u8]) -> Result<usize, Error> {
+    data.last_chunk::<4>()
+        .filter(|_| data.len() >= 18)
+        .map(|size| u32::from_le_bytes(*size) as usize)
+        .ok_or(Error::Truncated)
+}
+
+/// Take the next `len` bytes off the front of `input`.
+fn take<'a>(input: &mut &'a [u8], len: usize) -> Result<&'a [u8], Error> {
+    if input.len() < len {
+        return Err(Error::Truncated);
+    }
+    let (head, rest) = input.split_at(len);
+    *input = rest;
+    Ok(head)
+}
+
+fn take_u8(input: &mut &[u8]) -> Result<u8, Error> {
+    Ok(take(input, 1)?[0])
+}
+
+fn take_le(input: &mut &[u8], len: usize) -> Result<u64, Error> {
+    let bytes = take(input, len)?;
+    Ok(bytes
+        .iter()
+        .rev()
+        .fold(0, |acc, &byte| acc << 8 | byte as u64))
+}
+
+#[cfg(not(test))]
+extern "C" {
+    fn tinf_gzip_uncompress(
+        dest: *mut u8,
+        dest_len: *mut u32,
+        source: *const u8,
+        source_len: *mut u32,
+    ) -> i32;
+    fn XzDecode(
+        input: *mut u8,
+        input_size: *mut u32,
+        output: *mut u8,
+        output_size: *mut u32,
+    ) -> bool;
+}
+
+#[cfg(not(test))]
+fn gunzip(data: &[u8], limit: usize) -> Result<Vec<u8>, Error> {
+    let mut source_len = u32::try_from(data.len()).map_err(|_| Error::TooLarge)?;
+    // tinf decodes in one go, into a buffer sized from the trailer
+    let mut out = output_buffer(gzip_size(data)?, limit)?;
+    let size = out.len() as u32;
+    let mut dest_len = size;
+    let ret = unsafe {
+        tinf_gzip_uncompress(
+            out.as_mut_ptr(),
+            &mut dest_len,
+            data.as_ptr(),
+            &mut source_len,
+        )
+    };
+    if ret != 0 || dest_len != size {
+        return Err(Error::Corrupt("gzip stream"));
+    }
+    Ok(out)
+}
+
+#[cfg(not(test))]
+fn unxz(data: &[u8], limit: usize) -> Result<Vec<u8>, Error> {
+    let input_size = u32::try_from(data.len()).map_err(|_| Error::TooLarge)?;
+    // minilzlib never writes to its input, despite the signature
+    let input = data.as_ptr() as *mut u8;
+
+    // A first pass without an output buffer gets the uncompressed size from the index
+    let mut source_len = input_size;
+    let mut size = 0;
+    if !unsafe { XzDecode(input, &mut source_len, core::ptr::null_mut(), &mut size) } {
+        return Err(Error::Corrupt(
+            "xz stream, only CRC32 and no checks are supported",
+        ));
+    }
+    let mut out = output_buffer(size as usize, limit)?;
+    let mut dest_len = size;
+    source_len = input_size;
+    if !unsafe { XzDecode(input, &mut source_len, out.as_mut_ptr(), &mut dest_len) } {
+        return Err(Error::Corrupt("xz stream"));
+    }
+    out.truncate(dest_len as usize);
+    Ok(out)
+}
+
+// Host tests don't link the C decoders
+#[cfg(test)]
+fn gunzip(_data: &[u8], _limit: usize) -> Result<Vec<u8>, Error> {
+    Err(Error::Unsupported("gzip in host tests"))
+}
+
+#[cfg(test)]
+fn unxz(_data: &[u8], _limit: usize) -> Result<Vec<u8>, Error> {
+    Err(Error::Unsupported("xz in host tests"))
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use crate::hex;
+    use std::cell::Cell;
+    use std::format;
+
+    std::thread_local! {
+        static HEAP_FREE: Cell<usize> = const { Cell::new(usize::MAX) };
+    }
+
+    pub(super) unsafe fn heapblock_free() -> c_size_t {
+        HEAP_FREE.with(Cell::get)
+    }
+
+    /// The text the compressed test vectors were made from.
+    pub(in super::super) fn lines(count: usize) -> Vec<u8> {
+        (0..count)
+            .flat_map(|i| {
+                format!("m1n1 chainload test line {} of {}\n", i, i * i % 97).into_bytes()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn detection() {
+        assert_eq!(detect(b"\x1f\x8b\x08\x00"), Some(Format::Gzip));
+        assert_eq!(detect(b"\xfd7zXZ\x00\x00\x04"), Some(Format::Xz));
+        assert_eq!(detect(b"\x28\xb5\x2f\xfd\x04"), Some(Format::Zstd));
+        assert_eq!(detect(b"\x04\x22\x4d\x18\x64"), Some(Format::Lz4));
+        assert_eq!(detect(b"\x02\x21\x4c\x18"), Some(Format::Lz4));
+        assert_eq!(detect(b"\xfd7zXZ"), None);
+        assert_eq!(detect(b"MZ\x00\x00"), None);
+        assert_eq!(detect(b""), None);
+
+        let raw = b"not compressed".to_vec();
+        assert_eq!(decompress(raw.clone(), 0), Ok(raw));
+    }
+
+    #[test]
+    fn output_limit() {
+        let mut out = Output::new(10);
+        out.extend(b"abc").unwrap();
+        out.copy_match(0, 3, 5).unwrap();
+        assert_eq!(out.buf, b"abcabcab");
+        assert_eq!(
+            out.copy_match(0, 9, 1),
+            Err(Error::Corrupt("match offset out of range"))
+        );
+        assert_eq!(
+            out.copy_match(2, 7, 1),
+            Err(Error::Corrupt("match offset out of range"))
+        );
+        assert_eq!(out.fill(0, 3), Err(Error::TooLarge));
+        out.fill(b'x', 2).unwrap();
+        assert_eq!(out.buf, b"abcabcabxx");
+        assert!(out.buf.capacity() <= 10);
+    }
+
+    #[test]
+    fn heap_limit() {
+        HEAP_FREE.with(|free| free.set(HEAP_RESERVE + 100));
+        assert_eq!(output_buffer(100, MAX_SIZE).map(|out| out.len()), Ok(100));
+        assert_eq!(output_buffer(101, MAX_SIZE), Err(Error::OutOfMemory));
+        assert_eq!(output_buffer(101, 100), Err(Error::TooLarge));
+
+        // Growing stops short of the heap instead of doubling past it
+        let mut out = Output::new(MAX_SIZE);
+        out.fill(0, 60).unwrap();
+        out.fill(0, 40).unwrap();
+        assert_eq!(out.buf.capacity(), 100);
+        assert_eq!(out.fill(0, 1), Err(Error::OutOfMemory));
+
+        HEAP_FREE.with(|free| free.set(HEAP_RESERVE / 2));
+        assert_eq!(output_buffer(0, MAX_SIZE).map(|out| out.len()), Ok(0));
+        assert_eq!(output_buffer(1, MAX_SIZE), Err(Error::OutOfMemory));
+        HEAP_FREE.with(|free| free.set(usize::MAX));
+    }
+
+    #[test]
+    fn gzip_trailer() {
+        // An empty file, as written by gzip -n
+        let mut data = hex::decode("1f8b080000000000000303000000000000000000").unwrap();
+        assert_eq!(gzip_size(&data), Ok(0));
+
+        let len = data.len();
+        data[len - 4..].copy_from_slice(&(64u32 << 20).to_le_bytes());
+        assert_eq!(gzip_size(&data), Ok(64 << 20));
+        assert_eq!(gzip_size(&data[..17]), Err(Error::Truncated));
+        assert_eq!(gzip_size(b""), Err(Error::Truncated));
+    }
+}
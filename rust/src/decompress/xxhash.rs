@@ -0,0 +1,151 @@
+// SPDX-License-Identifier: MIT
+
+//! xxHash, the checksums of lz4 (XXH32) and zstd (XXH64) frames.
+
+use crate::bytes::{u32_le, u64_le};
+
+const P32: [u32; 5] = [0x9e3779b1, 0x85ebca77, 0xc2b2ae3d, 0x27d4eb2f, 0x165667b1];
+const P64: [u64; 5] = [
+    0x9e3779b185ebca87,
+    0xc2b2ae3d27d4eb4f,
+    0x165667b19e3779f9,
+    0x85ebca77c2b2ae63,
+    0x27d4eb2f165667c5,
+];
+
+fn round32(acc: u32, input: u32) -> u32 {
+    acc.wrapping_add(input.wrapping_mul(P32[1]))
+        .rotate_left(13)
+        .wrapping_mul(P32[0])
+}
+
+pub fn xxh32(data: &[u8], seed: u32) -> u32 {
+    let mut stripes = data.chunks_exact(16);
+    let mut h = if data.len() >= 16 {
+        let mut v = [
+            seed.wrapping_add(P32[0]).wrapping_add(P32[1]),
+            seed.wrapping_add(P32[1]),
+            seed,
+            seed.wrapping_sub(P32[0]),
+        ];
+        for stripe in &mut stripes {
+            for (i, v) in v.iter_mut().enumerate() {
+                *v = round32(*v, u32_le(stripe, 4 * i).unwrap());
+            }
+        }
+        v[0].rotate_left(1)
+            .wrapping_add(v[1].rotate_left(7))
+            .wrapping_add(v[2].rotate_left(12))
+            .wrapping_add(v[3].rotate_left(18))
+    } else {
+        seed.wrapping_add(P32[4])
+    };
+    h = h.wrapping_add(data.len() as u32);
+
+    let rest = stripes.remainder();
+    let mut words = rest.chunks_exact(4);
+    for word in &mut words {
+        h = h
+            .wrapping_add(u32_le(word, 0).unwrap().wrapping_mul(P32[2]))
+            .rotate_left(17)
+            .wrapping_mul(P32[3]);
+    }
+    for &byte in words.remainder() {
+        h = h
+            .wrapping_add((byte as u32).wrapping_mul(P32[4]))
+            .rotate_left(11)
+            .wrapping_mul(P32[0]);
+    }
+
+    h ^= h >> 15;
+    h = h.wrapping_mul(P32[1]);
+    h ^= h >> 13;
+    h = h.wrapping_mul(P32[2]);
+    h ^ (h >> 16)
+}
+
+fn round64(acc: u64, input: u64) -> u64 {
+    acc.wrapping_add(input.wrapping_mul(P64[1]))
+        .rotate_left(31)
+        .wrapping_mul(P64[0])
+}
+
+fn merge64(h: u64, v: u64) -> u64 {
+    (h ^ round64(0, v))
+        .wrapping_mul(P64[0])
+        .wrapping_add(P64[3])
+}
+
+pub fn xxh64(data: &[u8], seed: u64) -> u64 {
+    let mut stripes = data.chunks_exact(32);
+    let mut h = if data.len() >= 32 {
+        let mut v = [
+            seed.wrapping_add(P64[0]).wrapping_add(P64[1]),
+            seed.wrapping_add(P64[1]),
+            seed,
+            seed.wrapping_sub(P64[0]),
+        ];
+        for stripe in &mut stripes {
+            for (i, v) in v.iter_mut().enumerate() {
+                *v = round64(*v, u64_le(stripe, 8 * i).unwrap());
+            }
+        }
+        let h = v[0]
+            .rotate_left(1)
+            .wrapping_add(v[1].rotate_left(7))
+            .wrapping_add(v[2].rotate_left(12))
+            .wrapping_add(v[3].rotate_left(18));
+        v.into_iter().fold(h, merge64)
+    } else {
+        seed.wrapping_add(P64[4])
+    };
+    h = h.wrapping_add(data.len() as u64);
+
+    let rest = stripes.remainder();
+    let mut words = rest.chunks_exact(8);
+    for word in &mut words {
+        h = (h ^ round64(0, u64_le(word, 0).unwrap()))
+            .rotate_left(27)
+            .wrapping_mul(P64[0])
+            .wrapping_add(P64[3]);
+    }
+    let mut rest = words.remainder();
+    if rest.len() >= 4 {
+        h = (h ^ (u32_le(rest, 0).unwrap() as u64).wrapping_mul(P64[0]))
+            .rotate_left(23)
+            .wrapping_mul(P64[1])
+            .wrapping_add(P64[2]);
+        rest = &rest[4..];
+    }
+    for &byte in rest {
+        h = (h ^ (byte as u64).wrapping_mul(P64[4]))
+            .rotate_left(11)
+            .wrapping_mul(P64[0]);
+    }
+
+    h ^= h >> 33;
+    h = h.wrapping_mul(P64[1]);
+    h ^= h >> 29;
+    h = h.wrapping_mul(P64[2]);
+    h ^ (h >> 32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    #[test]
+    fn vectors() {
+        let data: Vec<u8> = (0..1000).map(|i| (i * 7) as u8).collect();
+
+        assert_eq!(xxh32(b"", 0), 0x02cc5d05);
+        assert_eq!(xxh32(b"abc", 0), 0x32d153ff);
+        assert_eq!(xxh32(&data, 0), 0x7ed9647e);
+
+        assert_eq!(xxh64(b"", 0), 0xef46db3751d8e999);
+        assert_eq!(xxh64(b"abc", 0), 0x44bc2cf5ad770999);
+        // As found in zstd frames, which only keep the low 32 bits
+        assert_eq!(xxh64(&data, 0) as u32, 0xa9cfc168);
+    }
+}
@@ -0,0 +1,857 @@
+// SPDX-License-Identifier: MIT
+
+//! Zstandard frames (RFC 8878), without dictionaries.
+
+use super::xxhash::xxh64;
+use super::{take, take_le, take_u8, Error, Output};
+use crate::println;
+use alloc::vec::Vec;
+
+const MAGIC: u32 = 0xfd2fb528;
+const SKIPPABLE_MAGIC: u32 = 0x184d2a50;
+const SKIPPABLE_MASK: u32 = 0xfffffff0;
+
+const MAX_BLOCK_SIZE: usize = 128 << 10;
+
+fn magic(data: &[u8]) -> Option<u32> {
+    Some(u32::from_le_bytes(*data.first_chunk::<4>()?))
+}
+
+pub(super) fn is_frame(data: &[u8]) -> bool {
+    magic(data) == Some(MAGIC)
+}
+
+/// Decode all frames in `data`.
+pub(super) fn decompress(mut data: &[u8], limit: usize) -> Result<Vec<u8>, Error> {
+    let mut out = Output::new(limit);
+
+    while let Some(magic) = magic(data) {
+        if magic == MAGIC {
+            data = &data[4..];
+            frame(&mut data, &mut out)?;
+        } else if magic & SKIPPABLE_MASK == SKIPPABLE_MAGIC {
+            data = &data[4..];
+            let len = take_le(&mut data, 4)? as usize;
+            take(&mut data, len)?;
+        } else {
+            break;
+        }
+    }
+    if !data.is_empty() {
+        println!("zstd: ignoring {} bytes after the last frame", data.len());
+    }
+
+    Ok(out.buf)
+}
+
+/// Decoder state carried from one block of a frame to the next.
+struct Frame {
+    start: usize,
+    huffman: Option<Huffman>,
+    literals: Vec<u8>,
+    literal_lengths: Option<Fse>,
+    offsets: Option<Fse>,
+    match_lengths: Option<Fse>,
+    repeat_offsets: [usize; 3],
+}
+
+fn frame(data: &mut &[u8], out: &mut Output) -> Result<(), Error> {
+    let header = take_u8(data)?;
+    let single_segment = header & 0x20 != 0;
+    let checksum = header & 0x04 != 0;
+    if header & 0x08 != 0 {
+        return Err(Error::Corrupt("zstd reserved bit set"));
+    }
+    if !single_segment {
+        // Window descriptor, we keep all output around anyway
+        take_u8(data)?;
+    }
+    let dictionary = take_le(data, [0, 1, 2, 4][(header & 3) as usize])?;
+    if dictionary != 0 {
+        return Err(Error::Unsupported("zstd dictionaries"));
+    }
+    let content_size = match (header >> 6, single_segment) {
+        (0, false) => None,
+        (0, true) => Some(take_le(data, 1)?),
+        (1, _) => Some(take_le(data, 2)? + 256),
+        (2, _) => Some(take_le(data, 4)?),
+        _ => Some(take_le(data, 8)?),
+    };
+
+    let mut frame = Frame {
+        start: out.len(),
+        huffman: None,
+        literals: Vec::new(),
+        literal_lengths: None,
+        offsets: None,
+        match_lengths: None,
+        repeat_offsets: [1, 4, 8],
+    };
+    if let Some(size) = content_size {
+        out.reserve(usize::try_from(size).map_err(|_| Error::TooLarge)?)?;
+    }
+
+    loop {
+        let header = take_le(data, 3)? as usize;
+        let size = header >> 3;
+        match header >> 1 & 3 {
+            0 => out.extend(take(data, size)?)?,
+            1 => out.fill(take_u8(data)?, size)?,
+            2 if size <= MAX_BLOCK_SIZE => frame.block(take(data, size)?, out)?,
+            2 => return Err(Error::Corrupt("zstd block too large")),
+            _ => return Err(Error::Corrupt("zstd reserved block type")),
+        }
+        if header & 1 != 0 {
+            break;
+        }
+    }
+
+    let content = &out.buf[frame.start..];
+    if content_size.is_some_and(|size| size != content.len() as u64) {
+        return Err(Error::Corrupt("zstd content size mismatch"));
+    }
+    // The low 32 bits of the XXH64 of the content
+    if checksum && take_le(data, 4)? != xxh64(content, 0) & 0xffffffff {
+        return Err(Error::BadChecksum);
+    }
+
+    Ok(())
+}
+
+impl Frame {
+    fn block(&mut self, mut block: &[u8], out: &mut Output) -> Result<(), Error> {
+        self.read_literals(&mut block)?;
+        self.execute_sequences(block, out)
+    }
+
+    /// Decode the literals section into `self.literals`.
+    fn read_literals(&mut self, block: &mut &[u8]) -> Result<(), Error> {
+        let header = take_u8(block)?;
+        let kind = header & 3;
+        let format = header >> 2 & 3;
+
+        self.literals.clear();
+        if kind < 2 {
+            let size = match format {
+                0 | 2 => (header >> 3) as usize,
+                1 => (header >> 4) as usize | (take_u8(block)? as usize) << 4,
+                _ => (header >> 4) as usize | (take_le(block, 2)? as usize) << 4,
+            };
+            if size > MAX_BLOCK_SIZE {
+                return Err(Error::Corrupt("zstd literals too large"));
+            }
+            match kind {
+                0 => self.literals.extend_from_slice(take(block, size)?),
+                _ => self.literals.resize(size, take_u8(block)?),
+            }
+            return Ok(());
+        }
+
+        // Both sizes are packed after the 4 bits of type and format
+        let (header_len, bits) = match format {
+            0 | 1 => (3, 10),
+            2 => (4, 14),
+            _ => (5, 18),
+        };
+        let sizes = (header as u64) >> 4 | take_le(block, header_len - 1)? << 4;
+        let size = (sizes & ((1 << bits) - 1)) as usize;
+        let compressed_size = (sizes >> bits) as usize;
+        if size > MAX_BLOCK_SIZE {
+            return Err(Error::Corrupt("zstd literals too large"));
+        }
+        let mut compressed = take(block, compressed_size)?;
+
+        // Compressed literals start with a Huffman table, treeless ones reuse the last one
+        if kind == 2 {
+            self.huffman = Some(Huffman::read(&mut compressed)?);
+        }
+        let huffman = self
+            .huffman
+            .as_ref()
+            .ok_or(Error::Corrupt("zstd literals without a Huffman table"))?;
+
+        self.literals.resize(size, 0);
+        if format == 0 {
+            return huffman.decode(compressed, &mut self.literals);
+        }
+
+        let jump_table = take(&mut compressed, 6)?;
+        let segment = size.div_ceil(4);
+        if 3 * segment > size {
+            return Err(Error::Corrupt("zstd literals too small for 4 streams"));
+        }
+        let mut streams = self.literals.chunks_mut(segment);
+        for i in 0..3 {
+            let len = u16::from_le_bytes([jump_table[2 * i], jump_table[2 * i + 1]]);
+            let stream = take(&mut compressed, len as usize)?;
+            huffman.decode(stream, streams.next().unwrap())?;
+        }
+        huffman.decode(compressed, streams.next().unwrap_or_default())
+    }
+
+    /// Decode the sequences section and copy literals and matches to `out`.
+    fn execute_sequences(&mut self, mut block: &[u8], out: &mut Output) -> Result<(), Error> {
+        let count = match take_u8(&mut block)? as usize {
+            count @ 0..=127 => count,
+            count @ 128..=254 => (count - 128) << 8 | take_u8(&mut block)? as usize,
+            _ => take_le(&mut block, 2)? as usize + 0x7f00,
+        };
+        if count == 0 {
+            return out.extend(&self.literals);
+        }
+
+        let modes = take_u8(&mut block)?;
+        if modes & 3 != 0 {
+            return Err(Error::Corrupt("zstd reserved bits set"));
+        }
+        let literal_lengths = Fse::for_sequences(
+            &mut self.literal_lengths,
+            modes >> 6,
+            &mut block,
+            &LITERAL_LENGTHS,
+        )?;
+        let offsets = Fse::for_sequences(&mut self.offsets, modes >> 4 & 3, &mut block, &OFFSETS)?;
+        let match_lengths = Fse::for_sequences(
+            &mut self.match_lengths,
+            modes >> 2 & 3,
+            &mut block,
+            &MATCH_LENGTHS,
+        )?;
+
+        let mut bits = BackwardBits::new(block)?;
+        let mut ll_state = literal_lengths.init(&mut bits);
+        let mut of_state = offsets.init(&mut bits);
+        let mut ml_state = match_lengths.init(&mut bits);
+
+        let mut literals = &self.literals[..];
+        for i in 0..count {
+            // Offset codes are at most 31, as checked when the table was built
+            let of_code = offsets.symbol(of_state) as u32;
+            let ml_code = match_lengths.symbol(ml_state) as usize;
+            let ll_code = literal_lengths.symbol(ll_state) as usize;
+            let offset_value = (1 << of_code) + bits.read(of_code);
+            let (base, extra) = *MATCH_LENGTH_CODES
+                .get(ml_code)
+                .ok_or(Error::Corrupt("zstd match length code"))?;
+            let match_len = base as usize + bits.read(extra) as usize;
+            let (base, extra) = *LITERAL_LENGTH_CODES
+                .get(ll_code)
+                .ok_or(Error::Corrupt("zstd literal length code"))?;
+            let literal_len = base as usize + bits.read(extra) as usize;
+
+            let offset =
+                repeat_offset(&mut self.repeat_offsets, offset_value as usize, literal_len)?;
+
+            if literal_len > literals.len() {
+                return Err(Error::Corrupt("zstd sequence past the literals"));
+            }
+            let (copy, rest) = literals.split_at(literal_len);
+            out.extend(copy)?;
+            literals = rest;
+            out.copy_match(self.start, offset, match_len)?;
+
+            if i + 1 < count {
+                literal_lengths.update(&mut ll_state, &mut bits);
+                match_lengths.update(&mut ml_state, &mut bits);
+                offsets.update(&mut of_state, &mut bits);
+            }
+        }
+        if !bits.is_empty() {
+            return Err(Error::Corrupt("zstd sequence bitstream not consumed"));
+        }
+
+        out.extend(literals)
+    }
+}
+
+/// Resolve an offset value to a distance, keeping track of the repeat offsets in `rep`.
+fn repeat_offset(rep: &mut [usize; 3], value: usize, literal_len: usize) -> Result<usize, Error> {
+    if value > 3 {
+        let offset = value - 3;
+        *rep = [offset, rep[0], rep[1]];
+        return Ok(offset);
+    }
+
+    // Without literals, the repeat offsets are shifted by one
+    let index = value - 1 + (literal_len == 0) as usize;
+    let offset = match index {
+        0 => return Ok(rep[0]),
+        3 => rep[0] - 1,
+        _ => rep[index],
+    };
+    if offset == 0 {
+        return Err(Error::Corrupt("zstd zero repeat offset"));
+    }
+    if index > 1 {
+        rep[2] = rep[1];
+    }
+    rep[1] = rep[0];
+    rep[0] = offset;
+    Ok(offset)
+}
+
+/// Load up to 57 bits of `data` starting at bit `pos`, least significant first.
+fn load_bits(data: &[u8], pos: usize, len: u32) -> u64 {
+    if len == 0 {
+        return 0;
+    }
+    let byte = pos / 8;
+    let mut word = [0u8; 8];
+    let avail = data.len().saturating_sub(byte).min(8);
+    word[..avail].copy_from_slice(&data[byte..byte + avail]);
+    (u64::from_le_bytes(word) >> (pos % 8)) & ((1 << len) - 1)
+}
+
+/// A bitstream read from the end, as used by entropy coded data.
+///
+/// Reading past the beginning returns zeros, which is how a decoder finishes its last symbols.
+struct BackwardBits<'a> {
+    data: &'a [u8],
+    /// Bits left to read, negative once we are past the beginning.
+    pos: isize,
+}
+
+impl<'a> BackwardBits<'a> {
+    fn new(data: &'a [u8]) -> Result<Self, Error> {
+        // The highest set bit of the last byte marks the end of the stream
+        match data.last() {
+            Some(&last) if last != 0 => Ok(BackwardBits {
+                data,
+                pos: (data.len() * 8) as isize - last.leading_zeros() as isize - 1,
+            }),
+            _ => Err(Error::Corrupt("zstd bitstream without end mark")),
+        }
+    }
+
+    fn read(&mut self, len: u32) -> u64 {
+        self.pos -= len as isize;
+        if self.pos >= 0 {
+            load_bits(self.data, self.pos as usize, len)
+        } else if self.pos + (len as isize) > 0 {
+            let missing = -self.pos as u32;
+            load_bits(self.data, 0, len - missing) << missing
+        } else {
+            0
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos == 0
+    }
+
+    fn is_overflowed(&self) -> bool {
+        self.pos < 0
+    }
+}
+
+/// A bitstream read from the start, as used by FSE table descriptions.
+struct ForwardBits<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl ForwardBits<'_> {
+    fn read(&mut self, len: u32) -> Result<u64, Error> {
+        if self.pos + len as usize > self.data.len() * 8 {
+            return Err(Error::Truncated);
+        }
+        let bits = load_bits(self.data, self.pos, len);
+        self.pos += len as usize;
+        Ok(bits)
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+struct FseEntry {
+    symbol: u8,
+    bits: u8,
+    base: u16,
+}
+
+/// A finite state entropy decoding table.
+#[derive(Debug, Clone)]
+struct Fse {
+    log: u32,
+    table: Vec<FseEntry>,
+}
+
+/// The limits and predefined distribution of one kind of sequence symbol.
+struct SequenceCode {
+    max_log: u32,
+    max_symbol: usize,
+    default_log: u32,
+    default: &'static [i16],
+}
+
+const LITERAL_LENGTHS: SequenceCode = SequenceCode {
+    max_log: 9,
+    max_symbol: 35,
+    default_log: 6,
+    default: &[
+        4, 3, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 1, 1, 1, 2, 2, 2, 2, 2, 2, 2, 2, 2, 3, 2, 1, 1, 1,
+        1, 1, -1, -1, -1, -1,
+    ],
+};
+
+const MATCH_LENGTHS: SequenceCode = SequenceCode {
+    max_log: 9,
+    max_symbol: 52,
+    default_log: 6,
+    default: &[
+        1, 4, 3, 2, 2, 2, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, -1, -1, -1, -1, -1, -1, -1,
+    ],
+};
+
+const OFFSETS: SequenceCode = SequenceCode {
+    max_log: 8,
+    max_symbol: 31,
+    default_log: 5,
+    default: &[
+        1, 1, 1, 1, 1, 1, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, -1, -1, -1, -1, -1,
+    ],
+};
+
+/// Baseline and number of extra bits of each literal length code.
+const LITERAL_LENGTH_CODES: [(u32, u32); 36] = [
+    (0, 0),
+    (1, 0),
+    (2, 0),
+    (3, 0),
+    (4, 0),
+    (5, 0),
+    (6, 0),
+    (7, 0),
+    (8, 0),
+    (9, 0),
+    (10, 0),
+    (11, 0),
+    (12, 0),
+    (13, 0),
+    (14, 0),
+    (15, 0),
+    (16, 1),
+    (18, 1),
+    (20, 1),
+    (22, 1),
+    (24, 2),
+    (28, 2),
+    (32, 3),
+    (40, 3),
+    (48, 4),
+    (64, 6),
+    (128, 7),
+    (256, 8),
+    (512, 9),
+    (1024, 10),
+    (2048, 11),
+    (4096, 12),
+    (8192, 13),
+    (16384, 14),
+    (32768, 15),
+    (65536, 16),
+];
+
+/// Baseline and number of extra bits of each match length code.
+const MATCH_LENGTH_CODES: [(u32, u32); 53] = [
+    (3, 0),
+    (4, 0),
+    (5, 0),
+    (6, 0),
+    (7, 0),
+    (8, 0),
+    (9, 0),
+    (10, 0),
+    (11, 0),
+    (12, 0),
+    (13, 0),
+    (14, 0),
+    (15, 0),
+    (16, 0),
+    (17, 0),
+    (18, 0),
+    (19, 0),
+    (20, 0),
+    (21, 0),
+    (22, 0),
+    (23, 0),
+    (24, 0),
+    (25, 0),
+    (26, 0),
+    (27, 0),
+    (28, 0),
+    (29, 0),
+    (30, 0),
+    (31, 0),
+    (32, 0),
+    (33, 0),
+    (34, 0),
+    (35, 1),
+    (37, 1),
+    (39, 1),
+    (41, 1),
+    (43, 2),
+    (47, 2),
+    (51, 3),
+    (59, 3),
+    (67, 4),
+    (83, 4),
+    (99, 5),
+    (131, 7),
+    (259, 8),
+    (515, 9),
+    (1027, 10),
+    (2051, 11),
+    (4099, 12),
+    (8195, 13),
+    (16387, 14),
+    (32771, 15),
+    (65539, 16),
+];
+
+impl Fse {
+    /// Build the decoding table for a distribution, where -1 means "less than 1".
+    fn new(log: u32, counts: &[i16]) -> Result<Fse, Error> {
+        let size = 1usize << log;
+        let mut table = vec![FseEntry::default(); size];
+        let mut next = [0u16; 256];
+
+        // Symbols with a count below 1 take one state each, at the end
+        let mut high = size;
+        for (symbol, &count) in counts.iter().enumerate() {
+            if count == -1 {
+                high -= 1;
+                table[high].symbol = symbol as u8;
+                next[symbol] = 1;
+            }
+        }
+
+        let step = (size >> 1) + (size >> 3) + 3;
+        let mut pos = 0;
+        for (symbol, &count) in counts.iter().enumerate() {
+            if count <= 0 {
+                continue;
+            }
+            next[symbol] = count as u16;
+            for _ in 0..count {
+                table[pos].symbol = symbol as u8;
+                loop {
+                    pos = (pos + step) & (size - 1);
+                    if pos < high {
+                        break;
+                    }
+                }
+            }
+        }
+        if pos != 0 {
+            return Err(Error::Corrupt("zstd FSE distribution"));
+        }
+
+        for entry in &mut table {
+            let state = next[entry.symbol as usize];
+            next[entry.symbol as usize] += 1;
+            let bits = log - (15 - state.leading_zeros());
+            entry.bits = bits as u8;
+            entry.base = ((state << bits) as usize - size) as u16;
+        }
+
+        Ok(Fse { log, table })
+    }
+
+    /// A table that always decodes to `symbol`.
+    fn rle(symbol: u8) -> Fse {
+        Fse {
+            log: 0,
+            table: vec![FseEntry {
+                symbol,
+                bits: 0,
+                base: 0,
+            }],
+        }
+    }
+
+    /// Read a table description from the start of `data`.
+    fn read(data: &mut &[u8], max_log: u32, max_symbol: usize) -> Result<Fse, Error> {
+        let mut bits = ForwardBits { data, pos: 0 };
+        let log = bits.read(4)? as u32 + 5;
+        if log > max_log {
+            return Err(Error::Corrupt("zstd FSE accuracy too high"));
+        }
+
+        let mut counts = Vec::new();
+        let mut remaining = (1i32 << log) + 1;
+        while remaining > 1 {
+            if counts.len() > max_symbol {
+                return Err(Error::Corrupt("zstd FSE too many symbols"));
+            }
+
+            // Values below the threshold take one bit less
+            let len = 32 - remaining.leading_zeros();
+            let mut value = bits.read(len)? as i32;
+            let low_mask = (1 << (len - 1)) - 1;
+            let threshold = (1 << len) - 1 - remaining;
+            if value & low_mask < threshold {
+                bits.pos -= 1;
+                value &= low_mask;
+            } else if value > low_mask {
+                value -= threshold;
+            }
+
+            let count = value - 1;
+            remaining -= count.abs();
+            counts.push(count as i16);
+
+            if count == 0 {
+                loop {
+                    let repeat = bits.read(2)?;
+                    counts.extend((0..repeat).map(|_| 0));
+                    if repeat != 3 {
+                        break;
+                    }
+                }
+            }
+        }
+        if remaining != 1 || counts.len() > max_symbol + 1 {
+            return Err(Error::Corrupt("zstd FSE distribution"));
+        }
+
+        *data = &data[bits.pos.div_ceil(8)..];
+        Fse::new(log, &counts)
+    }
+
+    /// Get the table for a kind of sequence symbol, as given by its compression mode.
+    fn for_sequences<'a>(
+        previous: &'a mut Option<Fse>,
+        mode: u8,
+        block: &mut &[u8],
+        code: &SequenceCode,
+    ) -> Result<&'a Fse, Error> {
+        match mode {
+            0 => *previous = Some(Fse::new(code.default_log, code.default)?),
+            1 => {
+                let symbol = take_u8(block)?;
+                if symbol as usize > code.max_symbol {
+                    return Err(Error::Corrupt("zstd RLE symbol"));
+                }
+                *previous = Some(Fse::rle(symbol));
+            }
+            2 => *previous = Some(Fse::read(block, code.max_log, code.max_symbol)?),
+            _ => {}
+        }
+        previous
+            .as_ref()
+            .ok_or(Error::Corrupt("zstd repeated table without a previous one"))
+    }
+
+    fn init(&self, bits: &mut BackwardBits<'_>) -> usize {
+        bits.read(self.log) as usize
+    }
+
+    fn symbol(&self, state: usize) -> u8 {
+        self.table[state].symbol
+    }
+
+    fn update(&self, state: &mut usize, bits: &mut BackwardBits<'_>) {
+        let entry = self.table[*state];
+        *state = entry.base as usize + bits.read(entry.bits as u32) as usize;
+    }
+}
+
+const MAX_HUFFMAN_BITS: u32 = 11;
+
+/// A Huffman decoding table, indexed by the next `max_bits` bits of the stream.
+#[derive(Debug, Clone)]
+struct Huffman {
+    max_bits: u32,
+    symbols: Vec<u8>,
+    bits: Vec<u8>,
+}
+
+impl Huffman {
+    /// Read a Huffman tree description from the start of `data`.
+    fn read(data: &mut &[u8]) -> Result<Huffman, Error> {
+        let header = take_u8(data)? as usize;
+        let mut weights = Vec::with_capacity(256);
+
+        if header >= 128 {
+            // Directly stored, 4 bits each
+            let count = header - 127;
+            for byte in take(data, count.div_ceil(2))? {
+                weights.extend([byte >> 4, byte & 0xf]);
+            }
+            weights.truncate(count);
+        } else {
+            // FSE compressed, with two interleaved states
+            let mut compressed = take(data, header)?;
+            let fse = Fse::read(&mut compressed, 6, 255)?;
+            let mut bits = BackwardBits::new(compressed)?;
+            let mut states = [fse.init(&mut bits), fse.init(&mut bits)];
+            'decode: loop {
+                for i in 0..2 {
+                    if weights.len() >= 255 {
+                        return Err(Error::Corrupt("zstd too many Huffman weights"));
+                    }
+                    weights.push(fse.symbol(states[i]));
+                    fse.update(&mut states[i], &mut bits);
+                    if bits.is_overflowed() {
+                        weights.push(fse.symbol(states[1 - i]));
+                        break 'decode;
+                    }
+                }
+            }
+        }
+
+        Huffman::from_weights(&mut weights)
+    }
+
+    /// Build the table from the weights of all but the last symbol, whose weight is implied.
+    fn from_weights(weights: &mut Vec<u8>) -> Result<Huffman, Error> {
+        let mut total = 0u32;
+        for &weight in weights.iter() {
+            if weight as u32 > MAX_HUFFMAN_BITS {
+                return Err(Error::Corrupt("zstd Huffman weight"));
+            }
+            if weight > 0 {
+                total += 1 << (weight - 1);
+            }
+        }
+        if total == 0 || weights.len() >= 256 {
+            return Err(Error::Corrupt("zstd Huffman weights"));
+        }
+
+        // The last weight fills the total up to the next power of two
+        let max_bits = 32 - total.leading_zeros();
+        let rest = (1 << max_bits) - total;
+        if max_bits > MAX_HUFFMAN_BITS || !rest.is_power_of_two() {
+            return Err(Error::Corrupt("zstd Huffman weights"));
+        }
+        weights.push(rest.trailing_zeros() as u8 + 1);
+
+        // Codes are handed out by increasing length, each taking a range of table entries
+        let size = 1 << max_bits;
+        let mut symbols = vec![0u8; size];
+        let mut bits = vec![0u8; size];
+        let mut rank_start = [0usize; MAX_HUFFMAN_BITS as usize + 2];
+        for &weight in weights.iter().filter(|&&weight| weight > 0) {
+            rank_start[weight as usize] += 1 << (weight - 1);
+        }
+        let mut pos = 0;
+        for start in rank_start.iter_mut() {
+            let count = *start;
+            *start = pos;
+            pos += count;
+        }
+        for (symbol, &weight) in weights.iter().enumerate() {
+            if weight == 0 {
+                continue;
+            }
+            let start = rank_start[weight as usize];
+            let len = 1 << (weight - 1);
+            symbols[start..start + len].fill(symbol as u8);
+            bits[start..start + len].fill((max_bits + 1 - weight as u32) as u8);
+            rank_start[weight as usize] += len;
+        }
+
+        Ok(Huffman {
+            max_bits,
+            symbols,
+            bits,
+        })
+    }
+
+    /// Decode a single stream, which has to fill `out` exactly.
+    fn decode(&self, stream: &[u8], out: &mut [u8]) -> Result<(), Error> {
+        let mut bits = BackwardBits::new(stream)?;
+        let mask = (1 << self.max_bits) - 1;
+        let mut state = bits.read(self.max_bits) as usize;
+        for out in out.iter_mut() {
+            *out = self.symbols[state];
+            let len = self.bits[state] as u32;
+            state = ((state << len) | bits.read(len) as usize) & mask;
+        }
+        // The state has read ahead exactly one full code past the end
+        if bits.pos != -(self.max_bits as isize) {
+            return Err(Error::Corrupt("zstd Huffman stream size"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::lines;
+    use super::*;
+    use crate::hex;
+
+    // zstd -19 -C of lines(100), with compressed literals and sequence tables
+    const LINES: &str = "28b52ffd0468f5080026142e1490abd89d6b7cef26ffdf15b969679263f6ef83132f002400250035ba9a9f\
+                         a5f154cbe36185b4f3ed5cef69e8f4d54302e4282c0e39088a03e26830088d63105820148780417050c1\
+                         44463dbfc699d22cfedd8c2cea793af34c4d63c61a29fdcc84caf79a78b6fb9b12873252d7adc7472129\
+                         3bbad594d94d34e3b3f2432529b7fbcd5cd31be9ee956cae4825742da3aaab53001f85c5713429de743e\
+                         4b332fcf72fed1ace57999ac3c65674e3ab1f6a46c58a5ffc8362f80c4a81140fafe9f01d09d03110086\
+                         ff4f18df0c3fc052a2922902134c3e3003b1e9cb811c06130bcca4b30c2783c9e868d83498580593832a\
+                         2f998399f464314c0c23974a40989a2ea7d399248c284cac2ac1a604127c3fff3899c7b3ab8160d4a90a\
+                         dbcf1178";
+    // zstd -C of 100000 zeros, with the content size
+    const ZEROS: &str = "28b52ffda4a086010055000010000001009b8639c002db234ef3";
+
+    #[test]
+    fn frames() {
+        assert_eq!(
+            decompress(&hex::decode(LINES).unwrap(), 1 << 20),
+            Ok(lines(100))
+        );
+        assert_eq!(
+            decompress(&hex::decode(ZEROS).unwrap(), 1 << 20),
+            Ok(vec![0; 100000])
+        );
+
+        // Concatenated frames, with a skippable frame in between and the size Linux appends
+        let mut data = hex::decode(LINES).unwrap();
+        data.extend(hex::decode("5e2a4d1803000000abcdef").unwrap());
+        data.extend(hex::decode(ZEROS).unwrap());
+        data.extend(103372u32.to_le_bytes());
+        let mut expected = lines(100);
+        expected.resize(103372, 0);
+        assert_eq!(decompress(&data, 1 << 20), Ok(expected));
+    }
+
+    #[test]
+    fn corrupt() {
+        let data = hex::decode(LINES).unwrap();
+        assert_eq!(decompress(&data, 3371), Err(Error::TooLarge));
+        // The content size is checked up front
+        assert_eq!(
+            decompress(&hex::decode(ZEROS).unwrap(), 99999),
+            Err(Error::TooLarge)
+        );
+        assert_eq!(decompress(&data[..200], 1 << 20), Err(Error::Truncated));
+
+        let mut bad = data.clone();
+        *bad.last_mut().unwrap() ^= 1;
+        assert_eq!(decompress(&bad, 1 << 20), Err(Error::BadChecksum));
+
+        // Damage is caught without panicking, or doesn't matter, as in the window size
+        for i in 4..data.len() {
+            let mut bad = data.clone();
+            bad[i] ^= 0x10;
+            if let Ok(out) = decompress(&bad, 1 << 20) {
+                assert_eq!(out, lines(100), "flipped byte {}", i);
+            }
+        }
+    }
+
+    #[test]
+    fn huffman_weights() {
+        // Three symbols of 1, 2 and 2 bits
+        let huffman = Huffman::from_weights(&mut vec![2, 1]).unwrap();
+        assert_eq!(huffman.max_bits, 2);
+        assert_eq!(huffman.symbols, [1, 2, 0, 0]);
+        assert_eq!(huffman.bits, [2, 2, 1, 1]);
+
+        // The implied last weight has to complete a power of two
+        assert!(Huffman::from_weights(&mut vec![3, 1]).is_err());
+        assert!(Huffman::from_weights(&mut vec![0, 0]).is_err());
+        assert!(Huffman::from_weights(&mut vec![12]).is_err());
+    }
+}
@@ -0,0 +1,478 @@
+// SPDX-License-Identifier: MIT
+
+//! Ed25519 signature verification (RFC 8032).
+//!
+//! Only public data is involved in verifying, so this is written for clarity rather than speed
+//! or constant time execution.
+
+use crate::sha512::Sha512;
+
+pub const PUBLIC_KEY_SIZE: usize = 32;
+pub const SIGNATURE_SIZE: usize = 64;
+
+const MASK: u64 = (1 << 51) - 1;
+
+/// An element of GF(2^255 - 19), in five 51-bit limbs.
+#[derive(Debug, Copy, Clone)]
+struct Fe([u64; 5]);
+
+impl Fe {
+    const ZERO: Fe = Fe([0; 5]);
+    const ONE: Fe = Fe([1, 0, 0, 0, 0]);
+
+    fn from_u64(v: u64) -> Fe {
+        Fe([v & MASK, v >> 51, 0, 0, 0])
+    }
+
+    /// Load 255 bits, ignoring the top bit.
+    fn from_bytes(b: &[u8; 32]) -> Fe {
+        let load = |i: usize| u64::from_le_bytes(b[i..i + 8].try_into().unwrap());
+        Fe([
+            load(0) & MASK,
+            (load(6) >> 3) & MASK,
+            (load(12) >> 6) & MASK,
+            (load(19) >> 1) & MASK,
+            (load(24) >> 12) & MASK,
+        ])
+    }
+
+    /// Carry so that every limb fits in 52 bits.
+    fn carry(mut self) -> Fe {
+        let l = &mut self.0;
+        for i in 0..4 {
+            l[i + 1] += l[i] >> 51;
+            l[i] &= MASK;
+        }
+        l[0] += 19 * (l[4] >> 51);
+        l[4] &= MASK;
+        self
+    }
+
+    /// The canonical encoding, fully reduced modulo p.
+    fn to_bytes(self) -> [u8; 32] {
+        let mut l = self.carry().0;
+
+        // Add 19 and see whether that carries past 2^255, i.e. whether the value is >= p
+        let mut q = (l[0] + 19) >> 51;
+        for limb in &l[1..] {
+            q = (limb + q) >> 51;
+        }
+        l[0] += 19 * q;
+        for i in 0..4 {
+            l[i + 1] += l[i] >> 51;
+            l[i] &= MASK;
+        }
+        l[4] &= MASK;
+
+        let mut out = [0u8; 32];
+        let (mut acc, mut bits, mut j) = (0u128, 0, 0);
+        for limb in l {
+            acc |= (limb as u128) << bits;
+            bits += 51;
+            while bits >= 8 {
+                out[j] = acc as u8;
+                acc >>= 8;
+                bits -= 8;
+                j += 1;
+            }
+        }
+        out[j] = acc as u8;
+        out
+    }
+
+    fn add(self, rhs: Fe) -> Fe {
+        let mut l = self.0;
+        for (a, b) in l.iter_mut().zip(rhs.0) {
+            *a += b;
+        }
+        Fe(l).carry()
+    }
+
+    fn sub(self, rhs: Fe) -> Fe {
+        // Add 16p first so that nothing underflows
+        const P16: [u64; 5] = [(MASK - 18) << 4, MASK << 4, MASK << 4, MASK << 4, MASK << 4];
+        let rhs = rhs.carry();
+        let mut l = self.carry().0;
+        for i in 0..5 {
+            l[i] = l[i] + P16[i] - rhs.0[i];
+        }
+        Fe(l).carry()
+    }
+
+    fn neg(self) -> Fe {
+        Fe::ZERO.sub(self)
+    }
+
+    fn mul(self, rhs: Fe) -> Fe {
+        let a = self.carry().0;
+        let b = rhs.carry().0;
+        let m = |x: u64, y: u64| x as u128 * y as u128;
+        let b19 = [b[0], b[1] * 19, b[2] * 19, b[3] * 19, b[4] * 19];
+
+        let r = [
+            m(a[0], b[0]) + m(a[1], b19[4]) + m(a[2], b19[3]) + m(a[3], b19[2]) + m(a[4], b19[1]),
+            m(a[0], b[1]) + m(a[1], b[0]) + m(a[2], b19[4]) + m(a[3], b19[3]) + m(a[4], b19[2]),
+            m(a[0], b[2]) + m(a[1], b[1]) + m(a[2], b[0]) + m(a[3], b19[4]) + m(a[4], b19[3]),
+            m(a[0], b[3]) + m(a[1], b[2]) + m(a[2], b[1]) + m(a[3], b[0]) + m(a[4], b19[4]),
+            m(a[0], b[4]) + m(a[1], b[3]) + m(a[2], b[2]) + m(a[3], b[1]) + m(a[4], b[0]),
+        ];
+
+        let mut out = [0u64; 5];
+        let mut carry = 0u128;
+        for i in 0..5 {
+            let t = r[i] + carry;
+            out[i] = t as u64 & MASK;
+            carry = t >> 51;
+        }
+        let t = out[0] as u128 + carry * 19;
+        out[0] = t as u64 & MASK;
+        out[1] += (t >> 51) as u64;
+        Fe(out).carry()
+    }
+
+    fn square(self) -> Fe {
+        self.mul(self)
+    }
+
+    /// Raise to the power `exp`, given as 32 little-endian bytes.
+    fn pow(self, exp: &[u8; 32]) -> Fe {
+        let mut r = Fe::ONE;
+        for i in (0..256).rev() {
+            r = r.square();
+            if exp[i / 8] >> (i % 8) & 1 != 0 {
+                r = r.mul(self);
+            }
+        }
+        r
+    }
+
+    /// `2^255 - 19 - k` for small `k`, as little-endian bytes.
+    fn p_minus(k: u8) -> [u8; 32] {
+        let mut b = [0xff; 32];
+        b[0] = 0xed - k;
+        b[31] = 0x7f;
+        b
+    }
+
+    fn invert(self) -> Fe {
+        self.pow(&Fe::p_minus(2))
+    }
+
+    /// Square root of -1, 2^((p - 1) / 4).
+    fn sqrt_m1() -> Fe {
+        let mut exp = [0xff; 32];
+        exp[0] = 0xfb;
+        exp[31] = 0x1f;
+        Fe::from_u64(2).pow(&exp)
+    }
+
+    fn is_negative(self) -> bool {
+        self.to_bytes()[0] & 1 != 0
+    }
+
+    fn equals(self, rhs: Fe) -> bool {
+        self.to_bytes() == rhs.to_bytes()
+    }
+}
+
+/// The curve constant d = -121665 / 121666.
+fn curve_d() -> Fe {
+    Fe::from_u64(121665)
+        .neg()
+        .mul(Fe::from_u64(121666).invert())
+}
+
+/// A point in extended coordinates, x = X/Z, y = Y/Z, xy = T/Z.
+#[derive(Debug, Copy, Clone)]
+struct Point {
+    x: Fe,
+    y: Fe,
+    z: Fe,
+    t: Fe,
+}
+
+impl Point {
+    const IDENTITY: Point = Point {
+        x: Fe::ZERO,
+        y: Fe::ONE,
+        z: Fe::ONE,
+        t: Fe::ZERO,
+    };
+
+    fn from_affine(x: Fe, y: Fe) -> Point {
+        Point {
+            x,
+            y,
+            z: Fe::ONE,
+            t: x.mul(y),
+        }
+    }
+
+    /// Decode a point, rejecting non-canonical encodings and points not on the curve.
+    fn decompress(b: &[u8; 32]) -> Option<Point> {
+        let y = Fe::from_bytes(b);
+        let sign = b[31] >> 7 != 0;
+        let mut canonical = *b;
+        canonical[31] &= 0x7f;
+        if y.to_bytes() != canonical {
+            return None;
+        }
+        Point::from_y(y, sign)
+    }
+
+    /// The point with the given y and sign of x, if there is one.
+    fn from_y(y: Fe, sign: bool) -> Option<Point> {
+        // x^2 = (y^2 - 1) / (d y^2 + 1)
+        let y2 = y.square();
+        let u = y2.sub(Fe::ONE);
+        let v = curve_d().mul(y2).add(Fe::ONE);
+
+        // Candidate root x = u v^3 (u v^7)^((p - 5) / 8)
+        let mut exp = [0xff; 32];
+        exp[0] = 0xfd;
+        exp[31] = 0x0f;
+        let v3 = v.square().mul(v);
+        let v7 = v3.square().mul(v);
+        let mut x = u.mul(v3).mul(u.mul(v7).pow(&exp));
+
+        let vx2 = v.mul(x.square());
+        if vx2.equals(u.neg()) {
+            x = x.mul(Fe::sqrt_m1());
+        } else if !vx2.equals(u) {
+            return None;
+        }
+
+        if x.is_negative() != sign {
+            if x.equals(Fe::ZERO) {
+                return None;
+            }
+            x = x.neg();
+        }
+        Some(Point::from_affine(x, y))
+    }
+
+    fn base() -> Point {
+        // y = 4/5, with positive x
+        let y = Fe::from_u64(4).mul(Fe::from_u64(5).invert());
+        Point::from_y(y, false).unwrap()
+    }
+
+    fn compress(self) -> [u8; 32] {
+        let zinv = self.z.invert();
+        let mut b = self.y.mul(zinv).to_bytes();
+        if self.x.mul(zinv).is_negative() {
+            b[31] |= 0x80;
+        }
+        b
+    }
+
+    /// Complete addition, which also works for doubling.
+    fn add(self, rhs: Point, d2: Fe) -> Point {
+        let a = self.y.sub(self.x).mul(rhs.y.sub(rhs.x));
+        let b = self.y.add(self.x).mul(rhs.y.add(rhs.x));
+        let c = self.t.mul(d2).mul(rhs.t);
+        let d = self.z.add(self.z).mul(rhs.z);
+        let (e, f, g, h) = (b.sub(a), d.sub(c), d.add(c), b.add(a));
+        Point {
+            x: e.mul(f),
+            y: g.mul(h),
+            z: f.mul(g),
+            t: e.mul(h),
+        }
+    }
+
+    fn neg(self) -> Point {
+        Point {
+            x: self.x.neg(),
+            t: self.t.neg(),
+            ..self
+        }
+    }
+
+    /// Multiply by a scalar given as 32 little-endian bytes.
+    fn mul(self, scalar: &[u8; 32], d2: Fe) -> Point {
+        let mut r = Point::IDENTITY;
+        for i in (0..256).rev() {
+            r = r.add(r, d2);
+            if scalar[i / 8] >> (i % 8) & 1 != 0 {
+                r = r.add(self, d2);
+            }
+        }
+        r
+    }
+}
+
+/// The group order L = 2^252 + 27742317777372353535851937790883648493, in 64-bit limbs.
+const L: [u64; 4] = [
+    0x5812631a5cf5d3ed,
+    0x14def9dea2f79cd6,
+    0x0000000000000000,
+    0x1000000000000000,
+];
+
+fn limbs_ge(a: &[u64; 4], b: &[u64; 4]) -> bool {
+    for i in (0..4).rev() {
+        if a[i] != b[i] {
+            return a[i] > b[i];
+        }
+    }
+    true
+}
+
+fn limbs_sub(a: &mut [u64; 4], b: &[u64; 4]) {
+    let mut borrow = false;
+    for i in 0..4 {
+        let (v, b1) = a[i].overflowing_sub(b[i]);
+        let (v, b2) = v.overflowing_sub(borrow as u64);
+        a[i] = v;
+        borrow = b1 || b2;
+    }
+}
+
+/// Reduce a little-endian number modulo L.
+fn reduce(bytes: &[u8]) -> [u8; 32] {
+    let mut r = [0u64; 4];
+    for i in (0..bytes.len() * 8).rev() {
+        // r = 2r + bit, r stays below 2L < 2^254 so nothing is lost
+        for j in (1..4).rev() {
+            r[j] = r[j] << 1 | r[j - 1] >> 63;
+        }
+        r[0] = r[0] << 1 | (bytes[i / 8] >> (i % 8) & 1) as u64;
+        if limbs_ge(&r, &L) {
+            limbs_sub(&mut r, &L);
+        }
+    }
+
+    let mut out = [0u8; 32];
+    for (chunk, limb) in out.chunks_exact_mut(8).zip(r) {
+        chunk.copy_from_slice(&limb.to_le_bytes());
+    }
+    out
+}
+
+/// Verify the signature `sig` of `msg` by the key `public_key`.
+pub fn verify(public_key: &[u8; PUBLIC_KEY_SIZE], msg: &[u8], sig: &[u8; SIGNATURE_SIZE]) -> bool {
+    let Some(a) = Point::decompress(public_key) else {
+        return false;
+    };
+    let r: &[u8; 32] = sig[..32].try_into().unwrap();
+    let s: &[u8; 32] = sig[32..].try_into().unwrap();
+
+    // S must be fully reduced, otherwise signatures would be malleable
+    let s_limbs =
+        core::array::from_fn(|i| u64::from_le_bytes(s[i * 8..i * 8 + 8].try_into().unwrap()));
+    if limbs_ge(&s_limbs, &L) {
+        return false;
+    }
+
+    let mut sha = Sha512::new();
+    sha.update(r);
+    sha.update(public_key);
+    sha.update(msg);
+    let k = reduce(&sha.finish());
+
+    // Check that [S]B - [k]A encodes to R
+    let d2 = curve_d().add(curve_d());
+    let sb = Point::base().mul(s, d2);
+    let ka = a.mul(&k, d2);
+    sb.add(ka.neg(), d2).compress() == *r
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hex;
+    use std::vec::Vec;
+
+    // RFC 8032, section 7.1
+    const VECTORS: [(&str, &str, &str); 3] = [
+        (
+            "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+            "",
+            "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e065224901555fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b",
+        ),
+        (
+            "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c",
+            "72",
+            "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00",
+        ),
+        (
+            "fc51cd8e6218a1a38da47ed00230f0580816ed13ba3303ac5deb911548908025",
+            "af82",
+            "6291d657deec24024827e69c3abe01a30ce548a284743a445e3680d7db5ac3ac18ff9b538d16f290ae67f760984dc6594a7c15e9716ed28dc027beceea1ec40a",
+        ),
+    ];
+
+    #[test]
+    fn rfc8032() {
+        for (pk, msg, sig) in VECTORS {
+            let pk: [u8; 32] = hex::parse(pk).unwrap();
+            let msg = hex::decode(msg).unwrap();
+            let mut sig: [u8; 64] = hex::parse(sig).unwrap();
+            assert!(verify(&pk, &msg, &sig));
+
+            let mut bad_msg = msg.clone();
+            bad_msg.push(0);
+            assert!(!verify(&pk, &bad_msg, &sig));
+
+            sig[5] ^= 1;
+            assert!(!verify(&pk, &msg, &sig));
+        }
+    }
+
+    #[test]
+    fn malformed() {
+        let (pk, msg, sig) = VECTORS[1];
+        let pk: [u8; 32] = hex::parse(pk).unwrap();
+        let msg = hex::decode(msg).unwrap();
+        let sig: [u8; 64] = hex::parse(sig).unwrap();
+
+        // S + L is the same scalar but must be rejected
+        let mut s = [0u64; 4];
+        for (i, limb) in s.iter_mut().enumerate() {
+            *limb = u64::from_le_bytes(sig[32 + i * 8..40 + i * 8].try_into().unwrap());
+        }
+        let mut carry = false;
+        for (limb, l) in s.iter_mut().zip(L) {
+            let (v, c1) = limb.overflowing_add(l);
+            let (v, c2) = v.overflowing_add(carry as u64);
+            *limb = v;
+            carry = c1 || c2;
+        }
+        let mut malleated = sig;
+        for (i, limb) in s.iter().enumerate() {
+            malleated[32 + i * 8..40 + i * 8].copy_from_slice(&limb.to_le_bytes());
+        }
+        assert!(!verify(&pk, &msg, &malleated));
+
+        // y >= p is not a canonical encoding
+        let mut bad_pk = [0xff; 32];
+        bad_pk[0] = 0xee;
+        bad_pk[31] = 0x7f;
+        assert!(Point::decompress(&bad_pk).is_none());
+        assert!(!verify(&bad_pk, &msg, &sig));
+
+        // y = 2 is not on the curve
+        let mut bad_pk = [0; 32];
+        bad_pk[0] = 2;
+        assert!(Point::decompress(&bad_pk).is_none());
+    }
+
+    #[test]
+    fn field() {
+        let p = Fe::p_minus(0);
+        assert_eq!(Fe::from_bytes(&p).to_bytes(), [0; 32]);
+        assert_eq!(Fe::from_u64(1).neg().to_bytes(), Fe::p_minus(1));
+        assert!(Fe::sqrt_m1().square().equals(Fe::ONE.neg()));
+        let x = Fe::from_u64(0xdead_beef_cafe);
+        assert!(x.mul(x.invert()).equals(Fe::ONE));
+        assert_eq!(
+            reduce(&[0xff; 64]),
+            reduce(&reduce(&[0xff; 64])),
+            "reduction must be idempotent"
+        );
+        let base = Point::base().compress();
+        assert_eq!(base[0], 0x58);
+        assert_eq!(base[1..], [0x66; 31]);
+    }
+}
@@ -1,55 +1,91 @@
 // SPDX-License-Identifier: MIT
 
+use crate::block::{self, BlockDevice};
+use crate::crc32::crc32;
 use crate::println;
+use alloc::string::String;
+use alloc::vec::Vec;
 use core::convert::TryInto;
+use core::fmt;
 use core::result::Result;
-use fatfs::{Read, Seek};
 use uuid::Uuid;
 
 const EFI_SIGNATURE: u64 = 0x5452415020494645;
+const EFI_REVISION: u32 = 0x00010000;
 
-const SECTOR_SIZE: usize = 4096;
+// Sanity limit for the partition entry array, the spec minimum is 16KiB
+const MAX_PARTITION_ARRAY_SIZE: usize = 1 << 20;
 
 #[derive(Debug)]
-pub enum Error<T> {
-    Io(T),
+pub enum Error {
+    Io(block::Error),
     InvalidGPTHeader,
+    /// The header places a table outside its reserved area, so it can't be rewritten safely.
+    InvalidTableLayout,
+    HeaderCRCMismatch,
+    PartitionArrayCRCMismatch,
+    InvalidPartitionIndex,
+    /// The name doesn't fit in 36 UTF-16 code units.
+    NameTooLong,
+    /// The name contains a NUL, which would end it early.
+    InvalidName,
+    NoPartitionTable,
 }
 
-impl<T> From<T> for Error<T> {
-    fn from(err: T) -> Error<T> {
+impl From<block::Error> for Error {
+    fn from(err: block::Error) -> Error {
         Error::Io(err)
     }
 }
 
 struct TableHeader {
-    bytes: [u8; Self::SIZE],
+    bytes: Vec<u8>,
     my_lba: u64,
 }
 
 impl TableHeader {
     const SIZE: usize = 0x5C;
 
-    fn read<R: Read + Seek>(rdr: &mut R, lba: u64) -> Result<Self, Error<R::Error>> {
+    /// Read the header at `lba` without validating anything.
+    fn read_raw<D: BlockDevice>(disk: &mut D, lba: u64) -> Result<Self, Error> {
         let mut hdr = Self {
-            bytes: [0; Self::SIZE],
+            bytes: vec![0; disk.block_size()],
             my_lba: lba,
         };
-        let off = SECTOR_SIZE * (lba as usize);
-        rdr.seek(fatfs::SeekFrom::Start(off as u64))?;
-        rdr.read_exact(&mut hdr.bytes)?;
-        match hdr.is_valid() {
-            true => Ok(hdr),
-            false => Err(Error::InvalidGPTHeader),
-        }
+        disk.read_blocks(lba, &mut hdr.bytes)?;
+        Ok(hdr)
+    }
+
+    fn read<D: BlockDevice>(disk: &mut D, lba: u64) -> Result<Self, Error> {
+        let hdr = Self::read_raw(disk, lba)?;
+        hdr.validate()?;
+        Ok(hdr)
     }
 
     fn get_signature(&self) -> u64 {
         u64::from_le_bytes(self.bytes[0..8].try_into().unwrap())
     }
+    fn get_revision(&self) -> u32 {
+        u32::from_le_bytes(self.bytes[8..12].try_into().unwrap())
+    }
+    fn get_header_size(&self) -> usize {
+        u32::from_le_bytes(self.bytes[12..16].try_into().unwrap()) as usize
+    }
+    fn get_header_crc32(&self) -> u32 {
+        u32::from_le_bytes(self.bytes[16..20].try_into().unwrap())
+    }
     fn get_my_lba(&self) -> u64 {
         u64::from_le_bytes(self.bytes[24..32].try_into().unwrap())
     }
+    fn get_alternate_lba(&self) -> u64 {
+        u64::from_le_bytes(self.bytes[32..40].try_into().unwrap())
+    }
+    fn get_first_usable_lba(&self) -> u64 {
+        u64::from_le_bytes(self.bytes[40..48].try_into().unwrap())
+    }
+    fn get_last_usable_lba(&self) -> u64 {
+        u64::from_le_bytes(self.bytes[48..56].try_into().unwrap())
+    }
     fn get_partition_entry_lba(&self) -> u64 {
         u64::from_le_bytes(self.bytes[72..80].try_into().unwrap())
     }
@@ -59,11 +95,260 @@ impl TableHeader {
     fn get_partition_entry_size(&self) -> usize {
         u32::from_le_bytes(self.bytes[84..88].try_into().unwrap()) as usize
     }
+    fn get_partition_entry_array_crc32(&self) -> u32 {
+        u32::from_le_bytes(self.bytes[88..92].try_into().unwrap())
+    }
+
+    fn set_u32(&mut self, off: usize, val: u32) {
+        self.bytes[off..off + 4].copy_from_slice(&val.to_le_bytes());
+    }
+    fn set_u64(&mut self, off: usize, val: u64) {
+        self.bytes[off..off + 8].copy_from_slice(&val.to_le_bytes());
+    }
+
+    /// Copy this header to a different location, e.g. to build the backup from the primary.
+    fn relocate(&self, my_lba: u64, alternate_lba: u64, entry_lba: u64) -> Self {
+        let mut hdr = Self {
+            bytes: self.bytes.clone(),
+            my_lba,
+        };
+        hdr.set_u64(24, my_lba);
+        hdr.set_u64(32, alternate_lba);
+        hdr.set_u64(72, entry_lba);
+        hdr
+    }
+
+    fn partition_array_size(&self) -> usize {
+        self.get_partition_entry_count() * self.get_partition_entry_size()
+    }
+
+    /// Size of the partition array rounded up to whole blocks.
+    fn partition_array_blocks_size(&self, block_size: usize) -> usize {
+        self.partition_array_size().next_multiple_of(block_size)
+    }
+
+    /// CRC32 of the header with the CRC field itself taken as zero.
+    fn compute_crc32(&self) -> u32 {
+        let mut bytes = self.bytes[..self.get_header_size()].to_vec();
+        bytes[16..20].fill(0);
+        crc32(&bytes)
+    }
+
     fn is_valid(&self) -> bool {
-        self.get_signature() == EFI_SIGNATURE && self.get_my_lba() == self.my_lba
+        let size = self.get_header_size();
+        let entry_size = self.get_partition_entry_size();
+
+        self.get_signature() == EFI_SIGNATURE
+            && self.get_revision() == EFI_REVISION
+            && (Self::SIZE..=self.bytes.len()).contains(&size)
+            && self.get_my_lba() == self.my_lba
+            && self.get_alternate_lba() != self.my_lba
+            && entry_size >= PartitionEntry::SIZE
+            && entry_size.is_power_of_two()
+            && self.get_partition_entry_count() <= MAX_PARTITION_ARRAY_SIZE / entry_size
+    }
+
+    /// Update the array and header CRCs and write the header and its partition array.
+    fn write<D: BlockDevice>(&mut self, disk: &mut D, entries: &[u8]) -> Result<(), Error> {
+        self.set_u32(88, crc32(entries));
+        self.set_u32(16, self.compute_crc32());
+
+        let mut blocks = entries.to_vec();
+        blocks.resize(self.partition_array_blocks_size(disk.block_size()), 0);
+        disk.write_blocks(self.get_partition_entry_lba(), &blocks)?;
+        disk.write_blocks(self.my_lba, &self.bytes)?;
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        if !self.is_valid() {
+            Err(Error::InvalidGPTHeader)
+        } else if self.compute_crc32() != self.get_header_crc32() {
+            Err(Error::HeaderCRCMismatch)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Read the partition entry array described by this header and check its CRC32.
+    fn read_partition_array<D: BlockDevice>(&self, disk: &mut D) -> Result<Vec<u8>, Error> {
+        let mut entries = vec![0; self.partition_array_blocks_size(disk.block_size())];
+        disk.read_blocks(self.get_partition_entry_lba(), &mut entries)?;
+        entries.truncate(self.partition_array_size());
+        match crc32(&entries) == self.get_partition_entry_array_crc32() {
+            true => Ok(entries),
+            false => Err(Error::PartitionArrayCRCMismatch),
+        }
+    }
+}
+
+/// Well-known partition types.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PartitionKind {
+    EfiSystem,
+    MicrosoftBasicData,
+    AppleApfs,
+    AppleApfsIsc,
+    AppleApfsRecovery,
+    AppleHfs,
+    AppleRecoveryHd,
+    LinuxFilesystem,
+    LinuxRootArm64,
+    LinuxXbootldr,
+    LinuxSwap,
+    LinuxLvm,
+    Unknown(Uuid),
+}
+
+impl PartitionKind {
+    const REGISTRY: &'static [(PartitionKind, Uuid)] = &[
+        (
+            PartitionKind::EfiSystem,
+            Uuid::from_u128(0xc12a7328_f81f_11d2_ba4b_00a0c93ec93b),
+        ),
+        (
+            PartitionKind::MicrosoftBasicData,
+            Uuid::from_u128(0xebd0a0a2_b9e5_4433_87c0_68b6b72699c7),
+        ),
+        (
+            PartitionKind::AppleApfs,
+            Uuid::from_u128(0x7c3457ef_0000_11aa_aa11_00306543ecac),
+        ),
+        (
+            PartitionKind::AppleApfsIsc,
+            Uuid::from_u128(0x69646961_6700_11aa_aa11_00306543ecac),
+        ),
+        (
+            PartitionKind::AppleApfsRecovery,
+            Uuid::from_u128(0x52637672_7900_11aa_aa11_00306543ecac),
+        ),
+        (
+            PartitionKind::AppleHfs,
+            Uuid::from_u128(0x48465300_0000_11aa_aa11_00306543ecac),
+        ),
+        (
+            PartitionKind::AppleRecoveryHd,
+            Uuid::from_u128(0x426f6f74_0000_11aa_aa11_00306543ecac),
+        ),
+        (
+            PartitionKind::LinuxFilesystem,
+            Uuid::from_u128(0x0fc63daf_8483_4772_8e79_3d69d8477de4),
+        ),
+        (
+            PartitionKind::LinuxRootArm64,
+            Uuid::from_u128(0xb921b045_1df0_41c3_af44_4c6f280d3fae),
+        ),
+        (
+            PartitionKind::LinuxXbootldr,
+            Uuid::from_u128(0xbc13c2ff_59e6_4262_a352_b275fd6f7172),
+        ),
+        (
+            PartitionKind::LinuxSwap,
+            Uuid::from_u128(0x0657fd6d_a4ab_43c4_84e5_0933c84b4f4f),
+        ),
+        (
+            PartitionKind::LinuxLvm,
+            Uuid::from_u128(0xe6d6d379_f507_44c2_a23c_238f2a3df928),
+        ),
+    ];
+
+    pub fn from_guid(guid: Uuid) -> Self {
+        Self::REGISTRY
+            .iter()
+            .find(|(_, g)| *g == guid)
+            .map_or(PartitionKind::Unknown(guid), |(kind, _)| *kind)
+    }
+
+    pub fn guid(&self) -> Uuid {
+        match self {
+            PartitionKind::Unknown(guid) => *guid,
+            kind => Self::REGISTRY.iter().find(|(k, _)| k == kind).unwrap().1,
+        }
+    }
+
+    pub fn name(&self) -> Option<&'static str> {
+        Some(match self {
+            PartitionKind::EfiSystem => "EFI System",
+            PartitionKind::MicrosoftBasicData => "Microsoft basic data",
+            PartitionKind::AppleApfs => "Apple APFS",
+            PartitionKind::AppleApfsIsc => "Apple APFS ISC",
+            PartitionKind::AppleApfsRecovery => "Apple APFS Recovery",
+            PartitionKind::AppleHfs => "Apple HFS+",
+            PartitionKind::AppleRecoveryHd => "Apple Boot (Recovery HD)",
+            PartitionKind::LinuxFilesystem => "Linux filesystem",
+            PartitionKind::LinuxRootArm64 => "Linux root (ARM64)",
+            PartitionKind::LinuxXbootldr => "Linux extended boot",
+            PartitionKind::LinuxSwap => "Linux swap",
+            PartitionKind::LinuxLvm => "Linux LVM",
+            PartitionKind::Unknown(_) => return None,
+        })
+    }
+}
+
+impl fmt::Display for PartitionKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name() {
+            Some(name) => f.write_str(name),
+            None => write!(f, "{:x}", self.guid()),
+        }
+    }
+}
+
+/// GPT partition entry attribute flags.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Attributes(pub u64);
+
+impl Attributes {
+    pub const REQUIRED: u64 = 1 << 0;
+    pub const NO_BLOCK_IO: u64 = 1 << 1;
+    pub const LEGACY_BIOS_BOOTABLE: u64 = 1 << 2;
+
+    // Type-specific bits as used by Microsoft basic data and the Linux discoverable partitions
+    // specification
+    pub const GROWFS: u64 = 1 << 59;
+    pub const READ_ONLY: u64 = 1 << 60;
+    pub const HIDDEN: u64 = 1 << 62;
+    pub const NO_AUTO: u64 = 1 << 63;
+
+    const NAMES: &'static [(u64, &'static str)] = &[
+        (Self::REQUIRED, "required"),
+        (Self::NO_BLOCK_IO, "no-block-io"),
+        (Self::LEGACY_BIOS_BOOTABLE, "legacy-boot"),
+        (Self::GROWFS, "growfs"),
+        (Self::READ_ONLY, "read-only"),
+        (Self::HIDDEN, "hidden"),
+        (Self::NO_AUTO, "no-auto"),
+    ];
+
+    pub fn contains(&self, flags: u64) -> bool {
+        self.0 & flags == flags
+    }
+
+    /// Bits 48-63, whose meaning depends on the partition type.
+    pub fn type_specific(&self) -> u16 {
+        (self.0 >> 48) as u16
+    }
+}
+
+impl fmt::Display for Attributes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut rest = self.0;
+        let mut sep = "";
+        for (flag, name) in Self::NAMES {
+            if rest & flag != 0 {
+                write!(f, "{}{}", sep, name)?;
+                rest &= !flag;
+                sep = ",";
+            }
+        }
+        if rest != 0 || sep.is_empty() {
+            write!(f, "{}{:#x}", sep, rest)?;
+        }
+        Ok(())
     }
 }
 
+#[derive(Clone)]
 pub struct PartitionEntry {
     bytes: [u8; Self::SIZE],
 }
@@ -71,19 +356,22 @@ pub struct PartitionEntry {
 impl PartitionEntry {
     const SIZE: usize = 0x80;
 
-    fn read<R: Read + Seek>(rdr: &mut R, off: usize) -> Result<Self, Error<R::Error>> {
-        let mut part = Self {
-            bytes: [0; Self::SIZE],
-        };
-        rdr.seek(fatfs::SeekFrom::Start(off as u64))?;
-        rdr.read_exact(&mut part.bytes)?;
-        Ok(part)
+    fn from_bytes(bytes: &[u8]) -> Self {
+        Self {
+            bytes: bytes[..Self::SIZE].try_into().unwrap(),
+        }
     }
 
-    #[allow(dead_code)]
     pub fn get_type_guid(&self) -> Uuid {
         Uuid::from_bytes_le(self.bytes[0..16].try_into().unwrap())
     }
+    pub fn get_kind(&self) -> PartitionKind {
+        PartitionKind::from_guid(self.get_type_guid())
+    }
+    /// Unused entries have a nil type GUID.
+    pub fn is_empty(&self) -> bool {
+        self.get_type_guid().is_nil()
+    }
     pub fn get_partition_guid(&self) -> Uuid {
         Uuid::from_bytes_le(self.bytes[16..32].try_into().unwrap())
     }
@@ -93,70 +381,916 @@ impl PartitionEntry {
     pub fn get_ending_lba(&self) -> u64 {
         u64::from_le_bytes(self.bytes[40..48].try_into().unwrap())
     }
-    pub fn get_attributes(&self) -> u64 {
-        u64::from_le_bytes(self.bytes[48..56].try_into().unwrap())
+    pub fn get_attributes(&self) -> Attributes {
+        Attributes(u64::from_le_bytes(self.bytes[48..56].try_into().unwrap()))
+    }
+    pub fn set_attributes(&mut self, attrs: Attributes) {
+        self.bytes[48..56].copy_from_slice(&attrs.0.to_le_bytes());
     }
-    pub fn get_name(&self) -> &[u8] {
-        &self.bytes[56..72]
+    /// Decode the UTF-16LE partition name, up to the first NUL.
+    pub fn get_name(&self) -> String {
+        let units = self.bytes[56..128]
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .take_while(|&c| c != 0);
+        char::decode_utf16(units)
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect()
+    }
+    /// Set the partition name, which must fit in 36 UTF-16 code units.
+    pub fn set_name(&mut self, name: &str) -> Result<(), Error> {
+        let field = &mut self.bytes[56..128];
+        if name.contains('\0') {
+            return Err(Error::InvalidName);
+        }
+        if name.encode_utf16().count() > field.len() / 2 {
+            return Err(Error::NameTooLong);
+        }
+        field.fill(0);
+        for (c, unit) in field.chunks_exact_mut(2).zip(name.encode_utf16()) {
+            c.copy_from_slice(&unit.to_le_bytes());
+        }
+        Ok(())
     }
 }
 
-pub struct GPT<T: fatfs::ReadWriteSeek> {
-    disk: T,
+pub struct GPT<D: BlockDevice> {
+    disk: D,
     hdr: TableHeader,
+    entries: Vec<u8>,
 }
 
-impl<IO: fatfs::ReadWriteSeek> GPT<IO> {
-    pub fn new<T: fatfs::IntoStorage<IO>>(storage: T) -> Result<Self, Error<IO::Error>> {
-        let mut disk = storage.into_storage();
-
-        let hdr = TableHeader::read(&mut disk, 1)?;
+impl<D: BlockDevice> GPT<D> {
+    pub fn new(mut disk: D) -> Result<Self, Error> {
+        let (hdr, entries) = match Self::read_table(&mut disk, 1) {
+            Ok(table) => table,
+            Err(Error::Io(err)) => return Err(Error::Io(err)),
+            Err(err) => {
+                // The primary header may be garbage, but if its signature survived the
+                // alternate LBA is our best guess at where the backup lives. Otherwise it
+                // should be in the last block of the disk.
+                let primary = TableHeader::read_raw(&mut disk, 1)?;
+                let signed = primary.get_signature() == EFI_SIGNATURE;
+                let alt = if signed {
+                    println!(
+                        "GPT: primary table is invalid ({:?}), trying backup at LBA {}",
+                        err,
+                        primary.get_alternate_lba()
+                    );
+                    primary.get_alternate_lba()
+                } else {
+                    match disk.block_count().checked_sub(1) {
+                        Some(lba) => lba,
+                        None => return Err(err),
+                    }
+                };
+                let table = match Self::read_table(&mut disk, alt) {
+                    Ok(table) => table,
+                    // Without a primary signature there may simply be no GPT at all
+                    Err(backup_err) => return Err(if signed { backup_err } else { err }),
+                };
+                println!("GPT: WARNING: using backup partition table");
+                table
+            }
+        };
 
-        let gpt = Self { disk, hdr };
+        let gpt = Self { disk, hdr, entries };
         Ok(gpt)
     }
 
+    fn read_table(disk: &mut D, lba: u64) -> Result<(TableHeader, Vec<u8>), Error> {
+        let hdr = TableHeader::read(disk, lba)?;
+        let entries = hdr.read_partition_array(disk)?;
+        Ok((hdr, entries))
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.disk.block_size()
+    }
+
+    pub fn into_inner(self) -> D {
+        self.disk
+    }
+
     pub fn count(&self) -> usize {
         self.hdr.get_partition_entry_count()
     }
 
-    pub fn index(&mut self, index: usize) -> Result<PartitionEntry, Error<IO::Error>> {
-        let off = (self.hdr.get_partition_entry_lba() as usize * SECTOR_SIZE)
-            + index * self.hdr.get_partition_entry_size();
-        PartitionEntry::read(&mut self.disk, off)
+    pub fn index(&self, index: usize) -> Option<PartitionEntry> {
+        if index >= self.count() {
+            return None;
+        }
+        let off = index * self.hdr.get_partition_entry_size();
+        Some(PartitionEntry::from_bytes(&self.entries[off..]))
     }
 
-    pub fn find_by_partuuid(
-        &mut self,
-        uuid: Uuid,
-    ) -> Result<Option<PartitionEntry>, Error<IO::Error>> {
-        for i in 0..self.count() {
-            let part = self.index(i)?;
-            if part.get_type_guid().is_nil() {
-                continue;
-            }
-            if part.get_partition_guid() == uuid {
-                return Ok(Some(part));
-            }
+    /// Replace the entry at `index` in the in-memory table. Use `write()` to commit it to disk.
+    pub fn update(&mut self, index: usize, part: &PartitionEntry) -> Result<(), Error> {
+        if index >= self.count() {
+            return Err(Error::InvalidPartitionIndex);
         }
-        Ok(None)
+        let off = index * self.hdr.get_partition_entry_size();
+        self.entries[off..off + PartitionEntry::SIZE].copy_from_slice(&part.bytes);
+        Ok(())
     }
 
-    pub fn dump(&mut self) {
-        for i in 0..self.count() {
-            let part = self.index(i).unwrap();
-            let guid = part.get_type_guid();
-            if guid.is_nil() {
-                continue;
+    pub fn set_attributes(&mut self, index: usize, attrs: Attributes) -> Result<(), Error> {
+        let mut part = self.index(index).ok_or(Error::InvalidPartitionIndex)?;
+        part.set_attributes(attrs);
+        self.update(index, &part)
+    }
+
+    pub fn set_name(&mut self, index: usize, name: &str) -> Result<(), Error> {
+        let mut part = self.index(index).ok_or(Error::InvalidPartitionIndex)?;
+        part.set_name(name)?;
+        self.update(index, &part)
+    }
+
+    /// Write the in-memory table back to disk, as both the primary and the backup GPT.
+    ///
+    /// The backup is written first, so an interrupted update always leaves at least one valid
+    /// copy. This also repairs the primary if it was found to be corrupt when the table was read.
+    pub fn write(&mut self) -> Result<(), Error> {
+        let hdr = &self.hdr;
+        let array_blocks = hdr.partition_array_size().div_ceil(self.disk.block_size()) as u64;
+        let (backup_lba, primary_entry_lba, backup_entry_lba) = match hdr.my_lba {
+            1 => {
+                let backup_lba = hdr.get_alternate_lba();
+                (
+                    backup_lba,
+                    hdr.get_partition_entry_lba(),
+                    backup_lba.saturating_sub(array_blocks),
+                )
             }
+            // Rebuilding a corrupt primary, use the standard layout
+            lba => (lba, 2, hdr.get_partition_entry_lba()),
+        };
+
+        // A CRC only proves the header is intact, not that its LBAs are sane. Make sure both
+        // tables stay out of the usable area and on the disk before writing anything.
+        let fits = |start: u64, end: u64| {
+            start
+                .checked_add(array_blocks)
+                .is_some_and(|array_end| array_end <= end)
+        };
+        let valid = hdr.get_last_usable_lba() < backup_entry_lba
+            && fits(backup_entry_lba, backup_lba)
+            && backup_lba < self.disk.block_count()
+            && primary_entry_lba >= 2
+            && fits(primary_entry_lba, hdr.get_first_usable_lba());
+        if !valid {
             println!(
-                "{}: {}..{} {:x} {:x}",
+                "GPT: refusing to write, bad table layout (backup at LBA {}, arrays at {} and {})",
+                backup_lba, primary_entry_lba, backup_entry_lba
+            );
+            return Err(Error::InvalidTableLayout);
+        }
+        let mut primary = hdr.relocate(1, backup_lba, primary_entry_lba);
+        let mut backup = hdr.relocate(backup_lba, 1, backup_entry_lba);
+
+        backup.write(&mut self.disk, &self.entries)?;
+        self.disk.flush()?;
+        primary.write(&mut self.disk, &self.entries)?;
+        self.disk.flush()?;
+
+        self.hdr = primary;
+        Ok(())
+    }
+
+    /// Iterate over all used partition entries, along with their index in the table.
+    pub fn partitions(&self) -> impl Iterator<Item = (usize, PartitionEntry)> + '_ {
+        (0..self.count())
+            .filter_map(|i| Some((i, self.index(i)?)))
+            .filter(|(_, part)| !part.is_empty())
+    }
+
+    pub fn find_by_partuuid(&self, uuid: Uuid) -> Option<PartitionEntry> {
+        self.partitions()
+            .map(|(_, part)| part)
+            .find(|part| part.get_partition_guid() == uuid)
+    }
+
+    pub fn find_by_name(&self, name: &str) -> Option<PartitionEntry> {
+        self.partitions()
+            .map(|(_, part)| part)
+            .find(|part| part.get_name() == name)
+    }
+
+    pub fn find_by_type(&self, type_guid: Uuid) -> Option<PartitionEntry> {
+        self.partitions()
+            .map(|(_, part)| part)
+            .find(|part| part.get_type_guid() == type_guid)
+    }
+
+    pub fn find_all_by_type(&self, type_guid: Uuid) -> Vec<PartitionEntry> {
+        self.partitions()
+            .map(|(_, part)| part)
+            .filter(|part| part.get_type_guid() == type_guid)
+            .collect()
+    }
+
+    pub fn dump(&self) {
+        for (i, part) in self.partitions() {
+            println!(
+                "{}: {}..{} {} {:x} \"{}\" [{}]",
                 i,
                 part.get_starting_lba(),
                 part.get_ending_lba(),
-                guid,
-                part.get_partition_guid()
+                part.get_kind(),
+                part.get_partition_guid(),
+                part.get_name(),
+                part.get_attributes()
+            );
+        }
+    }
+}
+
+const MBR_SIGNATURE: u16 = 0xaa55;
+const MBR_TYPE_PROTECTIVE: u8 = 0xee;
+const MBR_TYPES_EXTENDED: [u8; 3] = [0x05, 0x0f, 0x85];
+const MBR_MAX_LOGICAL: usize = 128;
+
+/// An MBR partition, either one of the four primary ones or a logical one from an extended
+/// partition.
+#[derive(Debug, Copy, Clone)]
+pub struct MbrPartition {
+    number: u8,
+    bootable: bool,
+    part_type: u8,
+    start_lba: u64,
+    sectors: u64,
+}
+
+impl MbrPartition {
+    fn parse(bytes: &[u8], number: u8, base_lba: u64) -> Option<Self> {
+        let status = bytes[0];
+        let part_type = bytes[4];
+        let start = u32::from_le_bytes(bytes[8..12].try_into().unwrap()) as u64;
+        let sectors = u32::from_le_bytes(bytes[12..16].try_into().unwrap()) as u64;
+        if part_type == 0 || sectors == 0 || (status != 0 && status != 0x80) {
+            return None;
+        }
+        Some(Self {
+            number,
+            bootable: status == 0x80,
+            part_type,
+            start_lba: base_lba + start,
+            sectors,
+        })
+    }
+
+    /// Partition number as used by Linux: 1-4 for primary, 5+ for logical partitions.
+    pub fn get_number(&self) -> u8 {
+        self.number
+    }
+    pub fn get_type(&self) -> u8 {
+        self.part_type
+    }
+    pub fn is_bootable(&self) -> bool {
+        self.bootable
+    }
+    pub fn is_extended(&self) -> bool {
+        MBR_TYPES_EXTENDED.contains(&self.part_type)
+    }
+    pub fn get_starting_lba(&self) -> u64 {
+        self.start_lba
+    }
+    pub fn get_ending_lba(&self) -> u64 {
+        self.start_lba + self.sectors - 1
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MbrKind {
+    /// Only a 0xEE entry covering the disk, the real table is the GPT.
+    Protective,
+    /// A 0xEE entry alongside legacy entries mirroring some GPT partitions.
+    Hybrid,
+    Legacy,
+}
+
+pub struct MBR {
+    kind: MbrKind,
+    disk_signature: u32,
+    partitions: Vec<MbrPartition>,
+}
+
+impl MBR {
+    pub const TYPE_FAT32_LBA: u8 = 0x0c;
+    pub const TYPE_EFI_SYSTEM: u8 = 0xef;
+
+    fn read_sector<D: BlockDevice>(disk: &mut D, lba: u64) -> Result<Option<Vec<u8>>, Error> {
+        let mut bytes = vec![0; disk.block_size()];
+        disk.read_blocks(lba, &mut bytes)?;
+        bytes.truncate(512);
+        match u16::from_le_bytes(bytes[510..512].try_into().unwrap()) == MBR_SIGNATURE {
+            true => Ok(Some(bytes)),
+            false => Ok(None),
+        }
+    }
+
+    fn valid_entries(bytes: &[u8]) -> bool {
+        bytes[446..510]
+            .chunks_exact(16)
+            .all(|e| e[0] == 0 || e[0] == 0x80)
+    }
+
+    /// Parse the MBR in LBA 0, returns `None` if there is no (plausible) MBR.
+    pub fn read<D: BlockDevice>(disk: &mut D) -> Result<Option<Self>, Error> {
+        let bytes = match Self::read_sector(disk, 0)? {
+            // A FAT boot sector also ends in 0x55AA, but has code in place of the entries
+            Some(bytes) if Self::valid_entries(&bytes) => bytes,
+            _ => return Ok(None),
+        };
+
+        let mut partitions: Vec<MbrPartition> = bytes[446..510]
+            .chunks_exact(16)
+            .zip(1..)
+            .filter_map(|(e, number)| MbrPartition::parse(e, number, 0))
+            .collect();
+        if partitions.is_empty() {
+            return Ok(None);
+        }
+
+        let kind = match partitions
+            .iter()
+            .any(|p| p.part_type == MBR_TYPE_PROTECTIVE)
+        {
+            true if partitions.len() == 1 => MbrKind::Protective,
+            true => MbrKind::Hybrid,
+            false => MbrKind::Legacy,
+        };
+
+        if let Some(ext) = partitions.iter().find(|p| p.is_extended()).copied() {
+            Self::read_logical(disk, &ext, &mut partitions)?;
+        }
+
+        Ok(Some(Self {
+            kind,
+            disk_signature: u32::from_le_bytes(bytes[440..444].try_into().unwrap()),
+            partitions,
+        }))
+    }
+
+    /// Walk the chain of extended boot records for logical partitions.
+    fn read_logical<D: BlockDevice>(
+        disk: &mut D,
+        ext: &MbrPartition,
+        partitions: &mut Vec<MbrPartition>,
+    ) -> Result<(), Error> {
+        let mut ebr_lba = ext.start_lba;
+        for number in 5..(5 + MBR_MAX_LOGICAL as u8) {
+            let bytes = match Self::read_sector(disk, ebr_lba)? {
+                Some(bytes) => bytes,
+                None => break,
+            };
+            if let Some(part) = MbrPartition::parse(&bytes[446..462], number, ebr_lba) {
+                partitions.push(part);
+            }
+            match MbrPartition::parse(&bytes[462..478], 0, ext.start_lba) {
+                Some(next) if next.start_lba > ebr_lba => ebr_lba = next.start_lba,
+                _ => break,
+            }
+        }
+        Ok(())
+    }
+
+    pub fn kind(&self) -> MbrKind {
+        self.kind
+    }
+
+    pub fn disk_signature(&self) -> u32 {
+        self.disk_signature
+    }
+
+    /// All partitions except for the extended partition containers.
+    pub fn partitions(&self) -> impl Iterator<Item = &MbrPartition> + '_ {
+        self.partitions.iter().filter(|p| !p.is_extended())
+    }
+
+    pub fn find_by_number(&self, number: u8) -> Option<MbrPartition> {
+        self.partitions().find(|p| p.number == number).copied()
+    }
+
+    pub fn find_by_type(&self, part_type: u8) -> Option<MbrPartition> {
+        self.partitions()
+            .find(|p| p.part_type == part_type)
+            .copied()
+    }
+
+    pub fn dump(&self) {
+        println!("MBR {:08x} ({:?})", self.disk_signature, self.kind);
+        for part in self.partitions() {
+            println!(
+                "{}: {}..{} type {:#04x}{}",
+                part.number,
+                part.get_starting_lba(),
+                part.get_ending_lba(),
+                part.part_type,
+                if part.bootable { " (active)" } else { "" }
             );
         }
     }
 }
+
+/// A partition identifier as accepted by Linux's `root=PARTUUID=`: either a GPT partition
+/// GUID, or `SSSSSSSS-PP` (MBR disk signature and partition number in hex).
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PartUuid {
+    Gpt(Uuid),
+    Mbr(u32, u8),
+}
+
+impl PartUuid {
+    pub fn parse(s: &str) -> Option<Self> {
+        if let Ok(uuid) = Uuid::parse_str(s) {
+            return Some(PartUuid::Gpt(uuid));
+        }
+        let (sig, num) = s.split_once('-')?;
+        if sig.len() != 8 || num.len() != 2 {
+            return None;
+        }
+        Some(PartUuid::Mbr(
+            u32::from_str_radix(sig, 16).ok()?,
+            u8::from_str_radix(num, 16).ok()?,
+        ))
+    }
+}
+
+impl fmt::Display for PartUuid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PartUuid::Gpt(uuid) => write!(f, "{}", uuid),
+            PartUuid::Mbr(sig, num) => write!(f, "{:08x}-{:02x}", sig, num),
+        }
+    }
+}
+
+/// A partition from any of the supported partition table formats.
+#[derive(Clone)]
+pub enum Partition {
+    Gpt(PartitionEntry),
+    Mbr(MbrPartition),
+}
+
+impl Partition {
+    pub fn get_starting_lba(&self) -> u64 {
+        match self {
+            Partition::Gpt(part) => part.get_starting_lba(),
+            Partition::Mbr(part) => part.get_starting_lba(),
+        }
+    }
+    pub fn get_ending_lba(&self) -> u64 {
+        match self {
+            Partition::Gpt(part) => part.get_ending_lba(),
+            Partition::Mbr(part) => part.get_ending_lba(),
+        }
+    }
+}
+
+/// The partition table of a disk, whichever format it uses.
+pub enum PartitionTable<D: BlockDevice> {
+    Gpt(GPT<D>),
+    Mbr(MBR),
+}
+
+impl<D: BlockDevice> PartitionTable<D> {
+    pub fn new(mut disk: D) -> Result<Self, Error> {
+        let mbr = MBR::read(&mut disk)?;
+        match mbr.as_ref().map(|mbr| mbr.kind()) {
+            Some(MbrKind::Legacy) => return Ok(PartitionTable::Mbr(mbr.unwrap())),
+            Some(MbrKind::Hybrid) => println!("GPT: hybrid MBR found, ignoring legacy entries"),
+            Some(MbrKind::Protective) => {}
+            // The spec requires a protective MBR, but be lenient towards broken tools
+            None => println!("GPT: no protective MBR found"),
+        }
+
+        match GPT::new(disk) {
+            Ok(gpt) => Ok(PartitionTable::Gpt(gpt)),
+            Err(Error::InvalidGPTHeader) if mbr.is_none() => Err(Error::NoPartitionTable),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// All used partitions, in table order.
+    pub fn partitions(&self) -> Vec<Partition> {
+        match self {
+            PartitionTable::Gpt(gpt) => gpt.partitions().map(|(_, p)| Partition::Gpt(p)).collect(),
+            PartitionTable::Mbr(mbr) => mbr.partitions().map(|p| Partition::Mbr(*p)).collect(),
+        }
+    }
+
+    /// The identifier of `part` (which must be from this table), as used by `root=PARTUUID=`.
+    pub fn partuuid(&self, part: &Partition) -> PartUuid {
+        match part {
+            Partition::Gpt(part) => PartUuid::Gpt(part.get_partition_guid()),
+            Partition::Mbr(part) => {
+                let signature = match self {
+                    PartitionTable::Mbr(mbr) => mbr.disk_signature(),
+                    PartitionTable::Gpt(_) => 0,
+                };
+                PartUuid::Mbr(signature, part.get_number())
+            }
+        }
+    }
+
+    pub fn find_by_partuuid(&self, uuid: PartUuid) -> Option<Partition> {
+        match (self, uuid) {
+            (PartitionTable::Gpt(gpt), PartUuid::Gpt(uuid)) => {
+                gpt.find_by_partuuid(uuid).map(Partition::Gpt)
+            }
+            (PartitionTable::Mbr(mbr), PartUuid::Mbr(sig, num)) if sig == mbr.disk_signature() => {
+                mbr.find_by_number(num).map(Partition::Mbr)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn dump(&self) {
+        match self {
+            PartitionTable::Gpt(gpt) => gpt.dump(),
+            PartitionTable::Mbr(mbr) => mbr.dump(),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::block::tests::MemDevice;
+    use std::vec::Vec;
+
+    const DISK_SIZE: usize = 256 * 1024;
+    const ARRAY_SIZE: usize = 128 * PartitionEntry::SIZE;
+
+    const PART_UUID: &str = "3b8b1a6e-8f2a-4a5e-9d3c-1e4f7a2b9c01";
+    pub(crate) const ESP_TYPE: &str = "c12a7328-f81f-11d2-ba4b-00a0c93ec93b";
+    const PART_NAME: &str = "EFI - ASAHI Linux boot partition";
+
+    /// A partition to put in a generated GPT.
+    pub(crate) struct TestPartition<'a> {
+        pub(crate) type_guid: &'a str,
+        pub(crate) guid: &'a str,
+        pub(crate) start: u64,
+        pub(crate) end: u64,
+        pub(crate) name: &'a str,
+    }
+
+    fn write_header(disk: &mut MemDevice, my_lba: u64, alt_lba: u64, entry_lba: u64, crc: u32) {
+        let array_sectors = (ARRAY_SIZE / disk.block_size()) as u64;
+        let last_usable = disk.block_count() - 2 - array_sectors;
+        let hdr = &mut disk.sector_mut(my_lba)[..TableHeader::SIZE];
+        hdr[0..8].copy_from_slice(&EFI_SIGNATURE.to_le_bytes());
+        hdr[8..12].copy_from_slice(&EFI_REVISION.to_le_bytes());
+        hdr[12..16].copy_from_slice(&(TableHeader::SIZE as u32).to_le_bytes());
+        hdr[24..32].copy_from_slice(&my_lba.to_le_bytes());
+        hdr[32..40].copy_from_slice(&alt_lba.to_le_bytes());
+        hdr[40..48].copy_from_slice(&(2 + array_sectors).to_le_bytes());
+        hdr[48..56].copy_from_slice(&last_usable.to_le_bytes());
+        hdr[72..80].copy_from_slice(&entry_lba.to_le_bytes());
+        hdr[80..84].copy_from_slice(&128u32.to_le_bytes());
+        hdr[84..88].copy_from_slice(&(PartitionEntry::SIZE as u32).to_le_bytes());
+        hdr[88..92].copy_from_slice(&crc.to_le_bytes());
+        let crc = crc32(hdr);
+        hdr[16..20].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    /// Write a primary and backup GPT containing `parts` to `disk`.
+    pub(crate) fn write_gpt(disk: &mut MemDevice, parts: &[TestPartition]) {
+        let mut array = vec![0u8; ARRAY_SIZE];
+        for (part, entry) in parts
+            .iter()
+            .zip(array.chunks_exact_mut(PartitionEntry::SIZE))
+        {
+            entry[0..16].copy_from_slice(&Uuid::parse_str(part.type_guid).unwrap().to_bytes_le());
+            entry[16..32].copy_from_slice(&Uuid::parse_str(part.guid).unwrap().to_bytes_le());
+            entry[32..40].copy_from_slice(&part.start.to_le_bytes());
+            entry[40..48].copy_from_slice(&part.end.to_le_bytes());
+            for (i, c) in part.name.encode_utf16().enumerate() {
+                entry[56 + 2 * i..58 + 2 * i].copy_from_slice(&c.to_le_bytes());
+            }
+        }
+        let array_crc = crc32(&array);
+
+        let last = disk.block_count() - 1;
+        let backup_array_lba = last - (ARRAY_SIZE / disk.block_size()) as u64;
+        for lba in [2, backup_array_lba] {
+            disk.sector_mut(lba)[..ARRAY_SIZE].copy_from_slice(&array);
+        }
+        write_header(disk, 1, last, 2, array_crc);
+        write_header(disk, last, 1, backup_array_lba, array_crc);
+    }
+
+    fn make_disk(block_size: usize) -> MemDevice {
+        let mut disk = MemDevice::new(DISK_SIZE, block_size);
+        write_gpt(
+            &mut disk,
+            &[TestPartition {
+                type_guid: ESP_TYPE,
+                guid: PART_UUID,
+                start: 8,
+                end: 31,
+                name: PART_NAME,
+            }],
+        );
+        disk
+    }
+
+    fn check_partition(gpt: &GPT<MemDevice>) {
+        let part = gpt
+            .find_by_partuuid(Uuid::parse_str(PART_UUID).unwrap())
+            .unwrap();
+        assert_eq!(part.get_starting_lba(), 8);
+        assert_eq!(part.get_ending_lba(), 31);
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+    }
+
+    #[test]
+    fn primary_table() {
+        let gpt = GPT::new(make_disk(4096)).unwrap();
+        assert_eq!(gpt.hdr.my_lba, 1);
+        check_partition(&gpt);
+    }
+
+    #[test]
+    fn primary_table_512() {
+        let gpt = GPT::new(make_disk(512)).unwrap();
+        assert_eq!(gpt.block_size(), 512);
+        assert_eq!(gpt.hdr.my_lba, 1);
+        check_partition(&gpt);
+    }
+
+    #[test]
+    fn lookup_by_name_and_type() {
+        let gpt = GPT::new(make_disk(4096)).unwrap();
+        let esp = Uuid::parse_str(ESP_TYPE).unwrap();
+        let part = gpt.find_by_name(PART_NAME).unwrap();
+        assert_eq!(part.get_name(), PART_NAME);
+        assert_eq!(part.get_starting_lba(), 8);
+        assert!(gpt.find_by_name("EFI - ASAHI").is_none());
+        assert_eq!(gpt.find_by_type(esp).unwrap().get_starting_lba(), 8);
+        assert_eq!(gpt.find_all_by_type(esp).len(), 1);
+        assert!(gpt.find_all_by_type(Uuid::nil()).is_empty());
+    }
+
+    #[test]
+    fn partition_kinds() {
+        let gpt = GPT::new(make_disk(4096)).unwrap();
+        let parts: Vec<_> = gpt.partitions().collect();
+        assert_eq!(parts.len(), 1);
+        assert_eq!(parts[0].0, 0);
+        assert_eq!(parts[0].1.get_kind(), PartitionKind::EfiSystem);
+        assert_eq!(
+            PartitionKind::EfiSystem.guid(),
+            Uuid::parse_str(ESP_TYPE).unwrap()
+        );
+
+        let attrs = Attributes(Attributes::REQUIRED | Attributes::NO_AUTO | (1 << 50));
+        assert!(attrs.contains(Attributes::REQUIRED));
+        assert_eq!(attrs.type_specific(), 0x8004);
+        assert_eq!(
+            std::format!("{}", attrs),
+            "required,no-auto,0x4000000000000"
+        );
+        assert_eq!(std::format!("{}", Attributes(0)), "0x0");
+    }
+
+    fn read_both(disk: &mut MemDevice) -> [(TableHeader, Vec<u8>); 2] {
+        let (primary, entries) = GPT::read_table(disk, 1).unwrap();
+        let backup = GPT::read_table(disk, primary.get_alternate_lba()).unwrap();
+        [(primary, entries), backup]
+    }
+
+    #[test]
+    fn write_attributes_and_name() {
+        let mut gpt = GPT::new(make_disk(512)).unwrap();
+        let attrs = Attributes(Attributes::LEGACY_BIOS_BOOTABLE | (1 << 56));
+        gpt.set_attributes(0, attrs).unwrap();
+        gpt.set_name(0, "boot-b").unwrap();
+        assert!(matches!(
+            gpt.set_name(0, &"x".repeat(37)),
+            Err(Error::NameTooLong)
+        ));
+        // Characters outside the BMP take two code units
+        assert!(matches!(
+            gpt.set_name(0, &"\u{1f980}".repeat(19)),
+            Err(Error::NameTooLong)
+        ));
+        assert!(matches!(
+            gpt.set_name(0, "boot\0b"),
+            Err(Error::InvalidName)
+        ));
+        assert!(matches!(
+            gpt.set_attributes(128, attrs),
+            Err(Error::InvalidPartitionIndex)
+        ));
+        gpt.write().unwrap();
+
+        let mut disk = gpt.into_inner();
+        let last = disk.block_count() - 1;
+        let [(primary, entries), (backup, backup_entries)] = read_both(&mut disk);
+        assert_eq!(entries, backup_entries);
+        assert_eq!(primary.get_alternate_lba(), last);
+        assert_eq!(backup.my_lba, last);
+        assert_eq!(backup.get_alternate_lba(), 1);
+        assert_eq!(
+            backup.get_partition_entry_lba(),
+            last - (ARRAY_SIZE / 512) as u64
+        );
+
+        // Make sure the backup alone carries the update too
+        disk.sector_mut(1)[40] ^= 0xff;
+        let gpt = GPT::new(disk).unwrap();
+        assert_eq!(gpt.hdr.my_lba, last);
+        let part = gpt.index(0).unwrap();
+        assert_eq!(part.get_attributes(), attrs);
+        assert_eq!(part.get_name(), "boot-b");
+        assert_eq!(part.get_starting_lba(), 8);
+    }
+
+    #[test]
+    fn write_repairs_primary() {
+        let mut disk = make_disk(4096);
+        disk.sector_mut(2)[33] ^= 0xff;
+        let mut gpt = GPT::new(disk).unwrap();
+        assert_ne!(gpt.hdr.my_lba, 1);
+        gpt.write().unwrap();
+
+        let mut disk = gpt.into_inner();
+        let [(primary, entries), (_, backup_entries)] = read_both(&mut disk);
+        assert_eq!(primary.get_partition_entry_lba(), 2);
+        assert_eq!(entries, backup_entries);
+        check_partition(&GPT::new(disk).unwrap());
+    }
+
+    /// Disk images generated by testdata/gpt.py, independently of this module.
+    const GPT_BEFORE: &[u8] = include_bytes!("../testdata/gpt-before.img");
+    const GPT_AFTER: &[u8] = include_bytes!("../testdata/gpt-after.img");
+
+    fn reference_disk(image: &[u8]) -> MemDevice {
+        let mut disk = MemDevice::new(image.len(), 512);
+        disk.data.copy_from_slice(image);
+        disk
+    }
+
+    #[test]
+    fn write_reference_image() {
+        let mut gpt = GPT::new(reference_disk(GPT_BEFORE)).unwrap();
+        assert_eq!(gpt.index(0).unwrap().get_name(), "EFI System Partition");
+        gpt.set_name(0, "m1n1 boot").unwrap();
+        gpt.set_attributes(1, Attributes(Attributes::LEGACY_BIOS_BOOTABLE | (1 << 60)))
+            .unwrap();
+        gpt.write().unwrap();
+        assert!(gpt.into_inner().data == GPT_AFTER);
+
+        // Rebuilding the primary table from the backup gives back the same bytes
+        let mut disk = reference_disk(GPT_AFTER);
+        disk.data[512..][..512].fill(0);
+        disk.data[1024..][..ARRAY_SIZE].fill(0);
+        let mut gpt = GPT::new(disk).unwrap();
+        assert_eq!(gpt.hdr.my_lba, 79);
+        gpt.write().unwrap();
+        assert!(gpt.into_inner().data == GPT_AFTER);
+    }
+
+    #[test]
+    fn write_bad_alternate_lba() {
+        for alternate_lba in [10, 20, 254, 1 << 40] {
+            let mut disk = make_disk(1024);
+            // A CRC-valid primary header with a bogus backup location
+            let mut hdr = TableHeader::read_raw(&mut disk, 1).unwrap();
+            hdr.set_u64(32, alternate_lba);
+            hdr.set_u32(16, hdr.compute_crc32());
+            disk.write_blocks(1, &hdr.bytes).unwrap();
+            let before = disk.data.clone();
+
+            let mut gpt = GPT::new(disk).unwrap();
+            assert_eq!(gpt.hdr.my_lba, 1);
+            gpt.set_name(0, "boot-b").unwrap();
+            assert!(matches!(gpt.write(), Err(Error::InvalidTableLayout)));
+            assert!(
+                gpt.into_inner().data == before,
+                "alternate LBA {}",
+                alternate_lba
+            );
+        }
+    }
+
+    fn write_mbr_entry(disk: &mut MemDevice, lba: u64, slot: usize, part_type: u8, start: u32) {
+        let sector = disk.sector_mut(lba);
+        let e = &mut sector[446 + 16 * slot..462 + 16 * slot];
+        e[0] = if slot == 0 { 0x80 } else { 0 };
+        e[4] = part_type;
+        e[8..12].copy_from_slice(&start.to_le_bytes());
+        e[12..16].copy_from_slice(&16u32.to_le_bytes());
+        sector[510..512].copy_from_slice(&MBR_SIGNATURE.to_le_bytes());
+    }
+
+    #[test]
+    fn protective_and_hybrid_mbr() {
+        let mut disk = make_disk(512);
+        write_mbr_entry(&mut disk, 0, 0, MBR_TYPE_PROTECTIVE, 1);
+        assert_eq!(
+            MBR::read(&mut disk).unwrap().unwrap().kind(),
+            MbrKind::Protective
+        );
+        write_mbr_entry(&mut disk, 0, 1, MBR::TYPE_EFI_SYSTEM, 8);
+        assert_eq!(
+            MBR::read(&mut disk).unwrap().unwrap().kind(),
+            MbrKind::Hybrid
+        );
+
+        let pt = PartitionTable::new(disk).unwrap();
+        let uuid = PartUuid::parse(PART_UUID).unwrap();
+        assert!(matches!(pt, PartitionTable::Gpt(_)));
+        assert_eq!(pt.find_by_partuuid(uuid).unwrap().get_starting_lba(), 8);
+    }
+
+    #[test]
+    fn legacy_mbr() {
+        let mut disk = MemDevice::new(DISK_SIZE, 512);
+        disk.sector_mut(0)[440..444].copy_from_slice(&0xdeadbeefu32.to_le_bytes());
+        write_mbr_entry(&mut disk, 0, 0, MBR::TYPE_FAT32_LBA, 32);
+        write_mbr_entry(&mut disk, 0, 1, MBR_TYPES_EXTENDED[0], 64);
+        // Two logical partitions, EBR offsets are relative to the extended partition
+        write_mbr_entry(&mut disk, 64, 0, MBR::TYPE_EFI_SYSTEM, 2);
+        write_mbr_entry(&mut disk, 64, 1, MBR_TYPES_EXTENDED[0], 20);
+        write_mbr_entry(&mut disk, 84, 0, 0x83, 2);
+
+        let pt = PartitionTable::new(disk).unwrap();
+        let mbr = match &pt {
+            PartitionTable::Mbr(mbr) => mbr,
+            _ => panic!("expected an MBR"),
+        };
+        assert_eq!(mbr.kind(), MbrKind::Legacy);
+        assert_eq!(mbr.partitions().count(), 3);
+        let esp = mbr.find_by_type(MBR::TYPE_EFI_SYSTEM).unwrap();
+        assert_eq!(esp.get_number(), 5);
+        assert_eq!(esp.get_starting_lba(), 66);
+        assert!(mbr.find_by_number(1).unwrap().is_bootable());
+
+        let uuid = PartUuid::parse("deadbeef-06").unwrap();
+        assert_eq!(uuid, PartUuid::Mbr(0xdeadbeef, 6));
+        let part = pt.find_by_partuuid(uuid).unwrap();
+        assert_eq!((part.get_starting_lba(), part.get_ending_lba()), (86, 101));
+        assert!(pt
+            .find_by_partuuid(PartUuid::parse(PART_UUID).unwrap())
+            .is_none());
+    }
+
+    #[test]
+    fn no_partition_table() {
+        let mut disk = MemDevice::new(DISK_SIZE, 512);
+        // Looks like a FAT boot sector: boot code where the entries would be
+        disk.sector_mut(0)[446..510].fill(0x90);
+        disk.sector_mut(0)[510..512].copy_from_slice(&MBR_SIGNATURE.to_le_bytes());
+        assert!(MBR::read(&mut disk).unwrap().is_none());
+        assert!(matches!(
+            PartitionTable::new(disk),
+            Err(Error::NoPartitionTable)
+        ));
+    }
+
+    #[test]
+    fn corrupt_primary_header() {
+        let mut disk = make_disk(4096);
+        let last = disk.block_count() - 1;
+        disk.sector_mut(1)[40] ^= 0xff;
+        let gpt = GPT::new(disk).unwrap();
+        assert_eq!(gpt.hdr.my_lba, last);
+        check_partition(&gpt);
+    }
+
+    #[test]
+    fn corrupt_primary_signature() {
+        let mut disk = make_disk(512);
+        let last = disk.block_count() - 1;
+        disk.sector_mut(1)[0] ^= 0xff;
+        let gpt = GPT::new(disk).unwrap();
+        assert_eq!(gpt.hdr.my_lba, last);
+        check_partition(&gpt);
+    }
+
+    #[test]
+    fn corrupt_primary_array() {
+        let mut disk = make_disk(512);
+        let last = disk.block_count() - 1;
+        disk.sector_mut(2)[33] ^= 0xff;
+        let gpt = GPT::new(disk).unwrap();
+        assert_eq!(gpt.hdr.my_lba, last);
+        check_partition(&gpt);
+    }
+
+    #[test]
+    fn corrupt_both() {
+        let mut disk = make_disk(4096);
+        let last = disk.block_count() - 1;
+        disk.sector_mut(2)[33] ^= 0xff;
+        disk.sector_mut(last)[40] ^= 0xff;
+        assert!(matches!(GPT::new(disk), Err(Error::HeaderCRCMismatch)));
+    }
+}
@@ -0,0 +1,71 @@
+// SPDX-License-Identifier: MIT
+
+//! Hex encoding of digests, keys and signatures.
+
+use alloc::vec::Vec;
+use core::fmt;
+
+/// Parse exactly `N` bytes written as hex, in either case.
+pub fn parse<const N: usize>(s: &str) -> Option<[u8; N]> {
+    if s.len() != 2 * N {
+        return None;
+    }
+    let mut out = [0u8; N];
+    decode_into(s, &mut out)?;
+    Some(out)
+}
+
+/// Parse any number of bytes written as hex, in either case.
+pub fn decode(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    let mut out = vec![0; s.len() / 2];
+    decode_into(s, &mut out)?;
+    Some(out)
+}
+
+fn decode_into(s: &str, out: &mut [u8]) -> Option<()> {
+    // Not u8::from_str_radix(), which would accept a leading '+'
+    let nibble = |c: u8| (c as char).to_digit(16);
+    for (out, pair) in out.iter_mut().zip(s.as_bytes().chunks_exact(2)) {
+        *out = (nibble(pair[0])? << 4 | nibble(pair[1])?) as u8;
+    }
+    Some(())
+}
+
+/// Displays bytes as lowercase hex.
+pub struct Hex<'a>(pub &'a [u8]);
+
+impl fmt::Display for Hex<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for b in self.0 {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::format;
+
+    #[test]
+    fn round_trip() {
+        let bytes = [0x00, 0xba, 0x78, 0x16, 0xff];
+        let text = format!("{}", Hex(&bytes));
+        assert_eq!(text, "00ba7816ff");
+        assert_eq!(parse(&text), Some(bytes));
+        assert_eq!(parse(&text.to_uppercase()), Some(bytes));
+        assert_eq!(parse::<4>(&text), None);
+        assert_eq!(parse::<5>(&text[1..]), None);
+        assert_eq!(parse::<5>("00ba78g6ff"), None);
+        assert_eq!(parse::<5>("00+a7816ff"), None);
+
+        assert_eq!(decode(&text).as_deref(), Some(&bytes[..]));
+        assert_eq!(decode(""), Some(vec![]));
+        assert_eq!(decode(&text[1..]), None);
+        assert_eq!(decode("0g"), None);
+    }
+}
@@ -0,0 +1,255 @@
+// SPDX-License-Identifier: MIT
+
+//! Identification of loaded images, so we don't jump into something that isn't m1n1.
+
+use crate::bytes::u32_le;
+use crate::macho::{self, MachO};
+use core::fmt;
+
+/// Offset of `_start` from the start of the text, after the exception vectors.
+pub const M1N1_ENTRY: usize = 0x800;
+/// Start of `version_tag` in main.c, which is followed by the build tag.
+const M1N1_VERSION_TAG: &[u8] = b"##m1n1_ver##";
+/// The segment m1n1.ld puts read-only data, and so the version tag, in.
+const M1N1_RODATA_SEGMENT: &str = "RODA";
+// A raw m1n1 has no header saying where it ends, so the tag is only looked for this far in
+const M1N1_MAX_SIZE: usize = 16 << 20;
+
+// "ARM\x64" at 0x38 in the arm64 boot protocol header
+const LINUX_MAGIC: u32 = 0x644d5241;
+const LINUX_MAGIC_OFFSET: usize = 0x38;
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+// Big endian, like the rest of these headers
+const FDT_MAGIC: u32 = 0xd00dfeed;
+const UIMAGE_MAGIC: u32 = 0x27051956;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Kind {
+    /// m1n1.bin, as built from m1n1-raw.ld.
+    M1n1Raw,
+    /// m1n1.macho, as built from m1n1.ld.
+    M1n1MachO,
+    /// Any other arm64 Mach-O, such as a kernelcache.
+    MachO,
+    /// An arm64 Linux `Image`.
+    Linux,
+    Gzip,
+    /// A U-Boot Flattened Image Tree.
+    Fit,
+    /// A legacy U-Boot image.
+    UImage,
+    Devicetree,
+    Unknown,
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Kind::M1n1Raw => "m1n1 raw binary",
+            Kind::M1n1MachO => "m1n1 Mach-O",
+            Kind::MachO => "arm64 Mach-O",
+            Kind::Linux => "arm64 Linux Image",
+            Kind::Gzip => "gzip compressed data",
+            Kind::Fit => "U-Boot FIT image",
+            Kind::UImage => "legacy U-Boot image",
+            Kind::Devicetree => "devicetree blob",
+            Kind::Unknown => "unknown image",
+        })
+    }
+}
+
+fn u32_be(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32_le(data, offset)?.swap_bytes())
+}
+
+fn has_m1n1_version_tag(data: &[u8]) -> bool {
+    data.windows(M1N1_VERSION_TAG.len())
+        .any(|window| window == M1N1_VERSION_TAG)
+}
+
+fn is_m1n1_macho(data: &[u8]) -> bool {
+    let Ok(macho) = MachO::parse(data) else {
+        return false;
+    };
+    macho
+        .segment(M1N1_RODATA_SEGMENT)
+        .and_then(|seg| {
+            let start = usize::try_from(seg.fileoff).ok()?;
+            let size = usize::try_from(seg.filesize).ok()?;
+            data.get(start..start.checked_add(size)?)
+        })
+        .is_some_and(has_m1n1_version_tag)
+}
+
+/// Whether the FDT in `data` has an `/images` node, which makes it a FIT image.
+fn fdt_has_images(data: &[u8]) -> Option<bool> {
+    const BEGIN_NODE: u32 = 1;
+    const END_NODE: u32 = 2;
+    const PROP: u32 = 3;
+    const NOP: u32 = 4;
+    const END: u32 = 9;
+
+    let size = (u32_be(data, 4)? as usize).min(data.len());
+    let data = &data[..size];
+    let mut offset = u32_be(data, 8)? as usize;
+    let mut depth = 0;
+
+    loop {
+        let token = u32_be(data, offset)?;
+        offset += 4;
+        match token {
+            BEGIN_NODE => {
+                let name = data.get(offset..)?;
+                let len = name.iter().position(|&c| c == 0)?;
+                // The root node has an empty name, its children are at depth 1
+                if depth == 1 && &name[..len] == b"images" {
+                    return Some(true);
+                }
+                depth += 1;
+                offset += (len + 1).next_multiple_of(4);
+            }
+            END_NODE if depth > 0 => depth -= 1,
+            PROP => {
+                let len = u32_be(data, offset)? as usize;
+                offset = offset.checked_add(8 + len.next_multiple_of(4))?;
+            }
+            NOP => {}
+            END => return Some(false),
+            _ => return None,
+        }
+    }
+}
+
+/// Identify an image from its headers.
+pub fn identify(data: &[u8]) -> Kind {
+    if u32_le(data, 0) == Some(macho::MAGIC) && u32_le(data, 4) == Some(macho::CPU_TYPE_ARM64) {
+        if is_m1n1_macho(data) {
+            Kind::M1n1MachO
+        } else {
+            Kind::MachO
+        }
+    } else if u32_le(data, LINUX_MAGIC_OFFSET) == Some(LINUX_MAGIC) {
+        Kind::Linux
+    } else if data.starts_with(&GZIP_MAGIC) {
+        Kind::Gzip
+    } else if u32_be(data, 0) == Some(UIMAGE_MAGIC) {
+        Kind::UImage
+    } else if u32_be(data, 0) == Some(FDT_MAGIC) {
+        match fdt_has_images(data) {
+            Some(true) => Kind::Fit,
+            Some(false) => Kind::Devicetree,
+            None => Kind::Unknown,
+        }
+    } else if has_m1n1_version_tag(&data[..data.len().min(M1N1_MAX_SIZE)]) {
+        Kind::M1n1Raw
+    } else {
+        Kind::Unknown
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hex;
+    use crate::macho::tests::M1N1_MACHO;
+    use crate::sha256::sha256;
+    use std::vec::Vec;
+
+    /// SHA-256 of the start.S testdata/m1n1.macho was built from.
+    const START_S_SHA256: &str = "a79f7ef0ec8c20fd2f82a62525d1b7d14fca6e07e8d7cc286523cef2d99e91ff";
+
+    /// The text of m1n1.macho, which m1n1-raw.ld lays out at the start of m1n1.bin too.
+    fn m1n1() -> &'static [u8] {
+        &M1N1_MACHO[0x4000..]
+    }
+
+    fn be(values: &[u32]) -> Vec<u8> {
+        values
+            .iter()
+            .flat_map(|value| value.to_be_bytes())
+            .collect()
+    }
+
+    /// A devicetree with a property and an empty node `child` in the root node.
+    fn fdt(child: &str) -> Vec<u8> {
+        let mut dt_struct = be(&[1, 0, 3, 4, 0]);
+        dt_struct.extend(b"fit\0");
+        dt_struct.extend(be(&[1]));
+        let mut name = child.as_bytes().to_vec();
+        name.resize((name.len() + 1).next_multiple_of(4), 0);
+        dt_struct.extend(name);
+        dt_struct.extend(be(&[2, 2, 9]));
+
+        let len = dt_struct.len() as u32;
+        let mut data = be(&[FDT_MAGIC, 40 + len, 40, 40 + len, 40, 17, 16, 0, 0, len]);
+        data.extend(dt_struct);
+        data
+    }
+
+    #[test]
+    fn kinds() {
+        assert_eq!(identify(m1n1()), Kind::M1n1Raw);
+
+        let mut macho = M1N1_MACHO.to_vec();
+        assert_eq!(identify(&macho), Kind::M1n1MachO);
+        // Whatever _start does, it's the version tag that counts
+        macho[0x4000 + M1N1_ENTRY] = 0;
+        assert_eq!(identify(&macho), Kind::M1n1MachO);
+        macho[0xc000] = 0;
+        assert_eq!(identify(&macho), Kind::MachO);
+        // Payloads don't count either
+        let mut macho = [M1N1_MACHO, b"payload"].concat();
+        macho[0xc000] = 0;
+        macho.extend(M1N1_VERSION_TAG);
+        assert_eq!(identify(&macho), Kind::MachO);
+
+        let mut linux = vec![0u8; 0x40];
+        linux[LINUX_MAGIC_OFFSET..LINUX_MAGIC_OFFSET + 4].copy_from_slice(b"ARM\x64");
+        assert_eq!(identify(&linux), Kind::Linux);
+
+        assert_eq!(identify(b"\x1f\x8b\x08\x00"), Kind::Gzip);
+        assert_eq!(identify(b"\x27\x05\x19\x56\x00"), Kind::UImage);
+        assert_eq!(identify(&fdt("images")), Kind::Fit);
+        assert_eq!(identify(&fdt("chosen")), Kind::Devicetree);
+
+        assert_eq!(identify(b""), Kind::Unknown);
+        assert_eq!(identify(&[0u8; 0x1000]), Kind::Unknown);
+        // Truncated before the version tag
+        assert_eq!(identify(&m1n1()[..0x8000 + 11]), Kind::Unknown);
+        let mut raw = vec![0u8; M1N1_MAX_SIZE];
+        raw.extend(M1N1_VERSION_TAG);
+        assert_eq!(identify(&raw), Kind::Unknown);
+    }
+
+    /// The fixture is linked from start.S, so a change to it needs a rebuild, see
+    /// testdata/README.md. Then update the digest here.
+    #[test]
+    fn fixture_matches_start_s() {
+        let start_s = include_bytes!("../../src/start.S");
+        assert_eq!(
+            hex::parse(START_S_SHA256),
+            Some(sha256(start_s)),
+            "start.S changed, rebuild testdata/m1n1.macho"
+        );
+
+        // chainload.c enters a raw m1n1 at M1N1_ENTRY, and the fixture agrees
+        let macho = MachO::parse(M1N1_MACHO).unwrap();
+        let text = macho.segment("TEXT").unwrap();
+        assert_eq!(macho.entry_point(), Ok(text.vmaddr + M1N1_ENTRY as u64));
+    }
+
+    #[test]
+    fn bad_fdt() {
+        let data = fdt("images");
+        assert_eq!(identify(&data[..40]), Kind::Unknown);
+        // Anything goes, as long as we don't read out of bounds
+        for len in 0..data.len() {
+            identify(&data[..len]);
+        }
+
+        let mut data = fdt("images");
+        // Bogus property length, before the node
+        data[52..56].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert_eq!(identify(&data), Kind::Unknown);
+    }
+}
@@ -13,15 +13,42 @@ extern crate alloc;
 
 pub mod adt;
 #[cfg(feature = "chainload")]
+pub mod block;
+#[cfg(feature = "chainload")]
+pub mod bls;
+pub mod bytes;
+#[cfg(feature = "chainload")]
+pub mod cache;
+#[cfg(feature = "chainload")]
 pub mod chainload;
+#[cfg(feature = "chainload")]
+pub mod config;
+pub mod crc32;
+#[cfg(feature = "chainload")]
+pub mod decompress;
 pub mod dlmalloc;
+pub mod ed25519;
 pub mod float;
 #[cfg(feature = "chainload")]
 pub mod gpt;
 pub mod gpu;
+pub mod hex;
+#[cfg(feature = "chainload")]
+pub mod image;
+pub mod macho;
 #[cfg(feature = "chainload")]
 pub mod nvme;
 pub mod print;
+#[cfg(feature = "chainload")]
+pub mod ramdisk;
+pub mod sha256;
+pub mod sha512;
+#[cfg(feature = "chainload")]
+pub mod signature;
+#[cfg(feature = "chainload")]
+pub mod slots;
+#[cfg(feature = "chainload")]
+pub mod spec;
 
 #[cfg(not(test))]
 use crate::dlmalloc::DLMalloc;
@@ -0,0 +1,605 @@
+// SPDX-License-Identifier: MIT
+
+//! Bounds-checked parsing of 64-bit Mach-O images, such as m1n1.macho and Apple firmware.
+
+use crate::bytes;
+use crate::c_size_t;
+use crate::println;
+use alloc::vec::Vec;
+use core::ffi::{c_int, c_void};
+use core::fmt;
+use core::ops::Range;
+
+pub const MAGIC: u32 = 0xfeedfacf;
+pub const CPU_TYPE_ARM64: u32 = 0x0100000c;
+
+pub const LC_UNIXTHREAD: u32 = 0x5;
+pub const LC_SEGMENT_64: u32 = 0x19;
+pub const LC_MAIN: u32 = 0x80000028;
+
+const HEADER_SIZE: usize = 0x20;
+const SEGMENT_SIZE: usize = 0x48;
+const SECTION_SIZE: usize = 0x50;
+
+const ARM_THREAD_STATE64: u32 = 6;
+// After x0-x28, fp, lr and sp
+const THREAD_STATE_PC: usize = 32 * 8;
+
+/// The segment payloads are appended in, whose file size is the maximum payload size.
+const PAYLOAD_SEGMENT: &str = "PYLD";
+// Sanity limit for the memory a Mach-O takes up once loaded
+const MAX_LOAD_SIZE: u64 = 1 << 30;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error {
+    Truncated,
+    BadMagic,
+    /// A load command at this offset is malformed.
+    BadCommand(usize),
+    NoSegments,
+    NoEntryPoint,
+    TooLarge,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Truncated => write!(f, "truncated Mach-O"),
+            Error::BadMagic => write!(f, "not a 64-bit Mach-O"),
+            Error::BadCommand(offset) => write!(f, "bad load command at {:#x}", offset),
+            Error::NoSegments => write!(f, "no segments"),
+            Error::NoEntryPoint => write!(f, "no entrypoint"),
+            Error::TooLarge => write!(f, "image too large"),
+        }
+    }
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Result<u32, Error> {
+    bytes::u32_le(data, offset).ok_or(Error::Truncated)
+}
+
+fn u64_at(data: &[u8], offset: usize) -> Result<u64, Error> {
+    bytes::u64_le(data, offset).ok_or(Error::Truncated)
+}
+
+fn name_at(data: &[u8], offset: usize) -> Result<[u8; 16], Error> {
+    let bytes = data.get(offset..offset + 16).ok_or(Error::Truncated)?;
+    Ok(bytes.try_into().unwrap())
+}
+
+/// A fixed size name, NUL padded unless it takes up all 16 bytes.
+fn name_str(name: &[u8; 16]) -> &str {
+    let len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+    core::str::from_utf8(&name[..len]).unwrap_or("")
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub cputype: u32,
+    pub cpusubtype: u32,
+    pub filetype: u32,
+    pub ncmds: u32,
+    pub sizeofcmds: u32,
+    pub flags: u32,
+}
+
+/// A load command, with `data` starting at its `cmd` field.
+#[derive(Debug, Copy, Clone)]
+pub struct LoadCommand<'a> {
+    pub cmd: u32,
+    pub data: &'a [u8],
+}
+
+pub struct Commands<'a> {
+    data: &'a [u8],
+    left: u32,
+}
+
+impl<'a> Iterator for Commands<'a> {
+    type Item = LoadCommand<'a>;
+
+    fn next(&mut self) -> Option<LoadCommand<'a>> {
+        // Sizes were checked when parsing the header
+        self.left = self.left.checked_sub(1)?;
+        let cmd = u32_at(self.data, 0).ok()?;
+        let size = u32_at(self.data, 4).ok()? as usize;
+        let (data, rest) = self.data.split_at_checked(size)?;
+        self.data = rest;
+        Some(LoadCommand { cmd, data })
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Segment<'a> {
+    pub name: [u8; 16],
+    pub vmaddr: u64,
+    pub vmsize: u64,
+    pub fileoff: u64,
+    pub filesize: u64,
+    pub maxprot: u32,
+    pub initprot: u32,
+    pub flags: u32,
+    sections: &'a [u8],
+}
+
+impl<'a> Segment<'a> {
+    fn parse(cmd: &LoadCommand<'a>) -> Result<Self, Error> {
+        let data = cmd.data;
+        let nsects = u32_at(data, 0x40)? as usize;
+        let sections = nsects
+            .checked_mul(SECTION_SIZE)
+            .and_then(|size| data.get(SEGMENT_SIZE..SEGMENT_SIZE + size))
+            .ok_or(Error::Truncated)?;
+
+        Ok(Segment {
+            name: name_at(data, 0x8)?,
+            vmaddr: u64_at(data, 0x18)?,
+            vmsize: u64_at(data, 0x20)?,
+            fileoff: u64_at(data, 0x28)?,
+            filesize: u64_at(data, 0x30)?,
+            maxprot: u32_at(data, 0x38)?,
+            initprot: u32_at(data, 0x3c)?,
+            flags: u32_at(data, 0x44)?,
+            sections,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        name_str(&self.name)
+    }
+
+    pub fn vm_range(&self) -> Range<u64> {
+        self.vmaddr..self.vmaddr.saturating_add(self.vmsize)
+    }
+
+    pub fn sections(&self) -> impl Iterator<Item = Section> + 'a {
+        self.sections
+            .chunks_exact(SECTION_SIZE)
+            .map(|data| Section::parse(data).unwrap())
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Section {
+    pub name: [u8; 16],
+    pub segname: [u8; 16],
+    pub addr: u64,
+    pub size: u64,
+    pub offset: u32,
+    pub align: u32,
+    pub flags: u32,
+}
+
+impl Section {
+    fn parse(data: &[u8]) -> Result<Self, Error> {
+        Ok(Section {
+            name: name_at(data, 0x0)?,
+            segname: name_at(data, 0x10)?,
+            addr: u64_at(data, 0x20)?,
+            size: u64_at(data, 0x28)?,
+            offset: u32_at(data, 0x30)?,
+            align: u32_at(data, 0x34)?,
+            flags: u32_at(data, 0x40)?,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        name_str(&self.name)
+    }
+
+    pub fn segname(&self) -> &str {
+        name_str(&self.segname)
+    }
+}
+
+pub struct MachO<'a> {
+    data: &'a [u8],
+    pub header: Header,
+}
+
+impl<'a> MachO<'a> {
+    /// Parse the header of the Mach-O in `data` and check its load commands.
+    pub fn parse(data: &'a [u8]) -> Result<Self, Error> {
+        if u32_at(data, 0)? != MAGIC {
+            return Err(Error::BadMagic);
+        }
+        let header = Header {
+            cputype: u32_at(data, 0x4)?,
+            cpusubtype: u32_at(data, 0x8)?,
+            filetype: u32_at(data, 0xc)?,
+            ncmds: u32_at(data, 0x10)?,
+            sizeofcmds: u32_at(data, 0x14)?,
+            flags: u32_at(data, 0x18)?,
+        };
+        let macho = MachO { data, header };
+
+        let mut cmds = macho.commands_data()?;
+        for _ in 0..header.ncmds {
+            let offset = HEADER_SIZE + header.sizeofcmds as usize - cmds.len();
+            let size = u32_at(cmds, 4).map_err(|_| Error::BadCommand(offset))? as usize;
+            if size < 8 || !size.is_multiple_of(8) || size > cmds.len() {
+                return Err(Error::BadCommand(offset));
+            }
+            let cmd = LoadCommand {
+                cmd: u32_at(cmds, 0)?,
+                data: &cmds[..size],
+            };
+            if cmd.cmd == LC_SEGMENT_64 {
+                Segment::parse(&cmd).map_err(|_| Error::BadCommand(offset))?;
+            }
+            cmds = &cmds[size..];
+        }
+
+        Ok(macho)
+    }
+
+    fn commands_data(&self) -> Result<&'a [u8], Error> {
+        let size = self.header.sizeofcmds as usize;
+        self.data
+            .get(HEADER_SIZE..HEADER_SIZE + size)
+            .ok_or(Error::Truncated)
+    }
+
+    pub fn commands(&self) -> Commands<'a> {
+        Commands {
+            data: self.commands_data().unwrap_or_default(),
+            left: self.header.ncmds,
+        }
+    }
+
+    pub fn segments(&self) -> impl Iterator<Item = Segment<'a>> + 'a {
+        self.commands()
+            .filter(|cmd| cmd.cmd == LC_SEGMENT_64)
+            .map(|cmd| Segment::parse(&cmd).unwrap())
+    }
+
+    pub fn segment(&self, name: &str) -> Option<Segment<'a>> {
+        self.segments().find(|seg| seg.name() == name)
+    }
+
+    /// The address range all segments are mapped in.
+    pub fn vm_range(&self) -> Result<Range<u64>, Error> {
+        self.segments()
+            .filter(|seg| seg.vmsize != 0)
+            .map(|seg| seg.vm_range())
+            .reduce(|a, b| a.start.min(b.start)..a.end.max(b.end))
+            .ok_or(Error::NoSegments)
+    }
+
+    /// The offset in the file that is mapped at `vmaddr`, if any.
+    pub fn file_offset(&self, vmaddr: u64) -> Option<usize> {
+        let seg = self.segments().find(|seg| {
+            vmaddr >= seg.vmaddr && vmaddr - seg.vmaddr < seg.vmsize.min(seg.filesize)
+        })?;
+        let offset = seg.fileoff.checked_add(vmaddr - seg.vmaddr)?;
+        usize::try_from(offset).ok()
+    }
+
+    /// The address execution starts at, from `LC_UNIXTHREAD` or `LC_MAIN`.
+    pub fn entry_point(&self) -> Result<u64, Error> {
+        for cmd in self.commands() {
+            match cmd.cmd {
+                LC_UNIXTHREAD => {
+                    // A list of thread states, each a flavor and a count of 32-bit words
+                    let mut offset = 8;
+                    while offset < cmd.data.len() {
+                        let flavor = u32_at(cmd.data, offset)?;
+                        let count = u32_at(cmd.data, offset + 4)? as usize;
+                        offset += 8;
+                        if flavor == ARM_THREAD_STATE64 {
+                            if count * 4 < THREAD_STATE_PC + 8 {
+                                return Err(Error::Truncated);
+                            }
+                            return u64_at(cmd.data, offset + THREAD_STATE_PC);
+                        }
+                        offset += count * 4;
+                    }
+                }
+                LC_MAIN => {
+                    // An offset in the file, in the segment that maps its start
+                    let entryoff = u64_at(cmd.data, 8)?;
+                    let seg = self
+                        .segments()
+                        .find(|seg| seg.fileoff == 0 && seg.filesize != 0)
+                        .ok_or(Error::NoSegments)?;
+                    return seg.vmaddr.checked_add(entryoff).ok_or(Error::NoEntryPoint);
+                }
+                _ => {}
+            }
+        }
+
+        Err(Error::NoEntryPoint)
+    }
+
+    /// Map all segments into a buffer starting at the lowest address of any of them.
+    ///
+    /// Segments are zero filled up to their VM size, except the payload segment of m1n1,
+    /// which only takes up as much as was appended to the file.
+    pub fn load(&self) -> Result<Vec<u8>, Error> {
+        let range = self.vm_range()?;
+
+        let mut loads = Vec::new();
+        let mut size = 0;
+        for seg in self.segments().filter(|seg| seg.vmsize != 0) {
+            let file = usize::try_from(seg.fileoff)
+                .ok()
+                .and_then(|offset| self.data.get(offset..))
+                .unwrap_or_default();
+            let file = &file[..file.len().min(seg.filesize.min(seg.vmsize) as usize)];
+
+            let start = seg.vmaddr - range.start;
+            let end = if seg.name() == PAYLOAD_SEGMENT {
+                start + file.len() as u64
+            } else {
+                start.saturating_add(seg.vmsize)
+            };
+            if end > MAX_LOAD_SIZE {
+                return Err(Error::TooLarge);
+            }
+
+            size = size.max(end as usize);
+            loads.push((start as usize, file));
+        }
+
+        let mut image = vec![0; size];
+        for (start, file) in loads {
+            image[start..start + file.len()].copy_from_slice(file);
+        }
+
+        Ok(image)
+    }
+}
+
+/// A Mach-O laid out by [`MachO::load`], for C.
+#[repr(C)]
+pub struct MachOImage {
+    pub image: *mut c_void,
+    pub size: c_size_t,
+    /// Offset of the entrypoint from the start of `image`.
+    pub entry: c_size_t,
+}
+
+fn load_arm64(data: &[u8]) -> Result<(Vec<u8>, usize), Error> {
+    let macho = MachO::parse(data)?;
+    if macho.header.cputype != CPU_TYPE_ARM64 {
+        return Err(Error::BadMagic);
+    }
+
+    let base = macho.vm_range()?.start;
+    let image = macho.load()?;
+    let entry = macho
+        .entry_point()?
+        .checked_sub(base)
+        .map(|entry| entry as usize)
+        .filter(|&entry| entry < image.len())
+        .ok_or(Error::NoEntryPoint)?;
+
+    Ok((image, entry))
+}
+
+/// Lay out `data` for execution if it is an arm64 Mach-O.
+///
+/// Returns 1 if it was loaded into `out`, 0 if `data` is not a Mach-O and -1 on errors.
+///
+/// # Safety
+///
+/// `data` must be valid for `size` bytes of reads, and `out` for writes.
+#[no_mangle]
+pub unsafe extern "C" fn rust_macho_load(
+    data: *const c_void,
+    size: c_size_t,
+    out: *mut MachOImage,
+) -> c_int {
+    let data = unsafe { core::slice::from_raw_parts(data as *const u8, size) };
+    if u32_at(data, 0) != Ok(MAGIC) {
+        return 0;
+    }
+
+    match load_arm64(data) {
+        Ok((image, entry)) => {
+            println!(
+                "macho: loaded {:#x} bytes, entrypoint at {:#x}",
+                image.len(),
+                entry
+            );
+            unsafe {
+                (*out).size = image.len();
+                (*out).image = image.leak().as_mut_ptr() as *mut c_void;
+                (*out).entry = entry;
+            }
+            1
+        }
+        Err(err) => {
+            println!("macho: {}", err);
+            -1
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    const VA_BASE: u64 = 0xfffffe0007004000;
+    const MAX_PAYLOAD_SIZE: u64 = 64 << 20;
+
+    /// m1n1.macho as linked from m1n1.ld and start.S, see testdata/README.md.
+    pub(crate) const M1N1_MACHO: &[u8] = include_bytes!("../testdata/m1n1.macho");
+
+    fn segment(name: &[u8; 4], vmaddr: u64, vmsize: u64, fileoff: u64, filesize: u64) -> Vec<u8> {
+        let mut cmd = Vec::new();
+        cmd.extend(LC_SEGMENT_64.to_le_bytes());
+        cmd.extend((SEGMENT_SIZE as u32).to_le_bytes());
+        cmd.extend(name);
+        cmd.extend([0; 12]);
+        for value in [vmaddr, vmsize, fileoff, filesize] {
+            cmd.extend(value.to_le_bytes());
+        }
+        cmd.extend([0; 16]);
+        cmd
+    }
+
+    fn macho(cmds: &[Vec<u8>]) -> Vec<u8> {
+        let size: usize = cmds.iter().map(Vec::len).sum();
+        let mut data = Vec::new();
+        for value in [
+            MAGIC,
+            CPU_TYPE_ARM64,
+            2,
+            0xc,
+            cmds.len() as u32,
+            size as u32,
+            4,
+            0,
+        ] {
+            data.extend(value.to_le_bytes());
+        }
+        cmds.iter().for_each(|cmd| data.extend(cmd));
+        data
+    }
+
+    #[test]
+    fn m1n1_macho() {
+        let data = [M1N1_MACHO, b"payload"].concat();
+        let macho = MachO::parse(&data).unwrap();
+
+        assert_eq!(macho.header.ncmds, 6);
+        let names: Vec<[u8; 16]> = macho.segments().map(|seg| seg.name).collect();
+        let names: Vec<&str> = names.iter().map(name_str).collect();
+        assert_eq!(names, ["_HDR", "TEXT", "RODA", "DATA", "PYLD"]);
+        let text = macho.segment("TEXT").unwrap();
+        assert_eq!(text.vm_range(), VA_BASE + 0x4000..VA_BASE + 0xc000);
+        assert_eq!(text.sections().count(), 0);
+
+        // _start, after the exception vectors at the start of .init
+        assert_eq!(macho.entry_point(), Ok(VA_BASE + 0x4800));
+        assert_eq!(macho.file_offset(VA_BASE + 0x4800), Some(0x4800));
+        assert_eq!(macho.file_offset(VA_BASE + 0xc000), Some(0xc000));
+        // There is bss after the data, so the payload isn't where it is in the file
+        assert_eq!(macho.file_offset(VA_BASE + 0x14000), None);
+        assert_eq!(macho.file_offset(VA_BASE + 0x40000), Some(0x14000));
+        assert_eq!(
+            macho.vm_range(),
+            Ok(VA_BASE..VA_BASE + 0x40000 + MAX_PAYLOAD_SIZE)
+        );
+
+        let image = macho.load().unwrap();
+        assert_eq!(image.len(), 0x40000 + 7);
+        assert_eq!(image[..0x14000], data[..0x14000]);
+        assert_eq!(
+            image[0x4800..0x4808],
+            [0xf3, 0x03, 0x00, 0xaa, 0xa0, 0x0d, 0x80, 0x52]
+        );
+        assert_eq!(image[0xc000..0xc019], *b"##m1n1_ver##test-fixture\0");
+        assert!(image[0x14000..0x40000].iter().all(|&b| b == 0));
+        assert_eq!(&image[0x40000..], b"payload");
+
+        let mut out = MachOImage {
+            image: core::ptr::null_mut(),
+            size: 0,
+            entry: 0,
+        };
+        let ret = unsafe { rust_macho_load(data.as_ptr() as *const c_void, data.len(), &mut out) };
+        assert_eq!((ret, out.size, out.entry), (1, image.len(), 0x4800));
+        let text = &data[0x4000..];
+        let ret = unsafe { rust_macho_load(text.as_ptr() as *const c_void, text.len(), &mut out) };
+        assert_eq!(ret, 0);
+    }
+
+    /// The full m1n1 build, if the tree has been built, as the fixture lacks the C code.
+    #[test]
+    fn built_m1n1_macho() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/../build/m1n1.macho");
+        let Ok(data) = std::fs::read(path) else {
+            std::eprintln!("{} not built, skipping", path);
+            return;
+        };
+        let macho = MachO::parse(&data).unwrap();
+        let names: Vec<[u8; 16]> = macho.segments().map(|seg| seg.name).collect();
+        let names: Vec<&str> = names.iter().map(name_str).collect();
+        assert_eq!(names, ["_HDR", "TEXT", "RODA", "DATA", "PYLD"]);
+        assert_eq!(macho.entry_point(), Ok(VA_BASE + 0x4800));
+
+        let image = macho.load().unwrap();
+        assert_eq!(image[0x4800..0x4808], M1N1_MACHO[0x4800..0x4808]);
+        let mut out = MachOImage {
+            image: core::ptr::null_mut(),
+            size: 0,
+            entry: 0,
+        };
+        let ret = unsafe { rust_macho_load(data.as_ptr() as *const c_void, data.len(), &mut out) };
+        assert_eq!((ret, out.size, out.entry), (1, image.len(), 0x4800));
+    }
+
+    #[test]
+    fn sections_and_main() {
+        let mut text = segment(b"TEXT", 0x100000000, 0x8000, 0, 0x8000);
+        text[0x4..0x8].copy_from_slice(&((SEGMENT_SIZE + 2 * SECTION_SIZE) as u32).to_le_bytes());
+        text[0x40..0x44].copy_from_slice(&2u32.to_le_bytes());
+        for (name, addr) in [(b"__text", 0x100001000u64), (b"__cstr", 0x100006000)] {
+            let mut section = vec![0; SECTION_SIZE];
+            section[..6].copy_from_slice(name);
+            section[0x10..0x14].copy_from_slice(b"TEXT");
+            section[0x20..0x28].copy_from_slice(&addr.to_le_bytes());
+            section[0x28..0x30].copy_from_slice(&0x100u64.to_le_bytes());
+            text.extend(section);
+        }
+        let mut main = Vec::new();
+        for value in [LC_MAIN, 0x18] {
+            main.extend(value.to_le_bytes());
+        }
+        main.extend(0x1040u64.to_le_bytes());
+        main.extend(0u64.to_le_bytes());
+        let data = macho(&[text, main]);
+
+        let macho = MachO::parse(&data).unwrap();
+        let sections: Vec<_> = macho.segment("TEXT").unwrap().sections().collect();
+        assert_eq!(sections.len(), 2);
+        assert_eq!(
+            (sections[1].name(), sections[1].segname()),
+            ("__cstr", "TEXT")
+        );
+        assert_eq!((sections[1].addr, sections[1].size), (0x100006000, 0x100));
+        assert_eq!(macho.entry_point(), Ok(0x100001040));
+
+        // The file is shorter than the segment, the rest is zero filled
+        let image = macho.load().unwrap();
+        assert_eq!(image.len(), 0x8000);
+        assert_eq!(image[..data.len()], data);
+    }
+
+    #[test]
+    fn malformed() {
+        let data = M1N1_MACHO.to_vec();
+        assert_eq!(MachO::parse(&data[..4]).err(), Some(Error::Truncated));
+        assert_eq!(MachO::parse(&data[4..]).err(), Some(Error::BadMagic));
+        for len in 0..0x200 {
+            if let Ok(macho) = MachO::parse(&data[..len]) {
+                panic!("parsed truncated Mach-O: {:?}", macho.header);
+            }
+        }
+
+        // cmdsize of _HDR, the first segment
+        let mut bad = data.clone();
+        bad[0x144..0x148].copy_from_slice(&4u32.to_le_bytes());
+        assert_eq!(MachO::parse(&bad).err(), Some(Error::BadCommand(0x140)));
+        // Its nsects, past the end of the commands
+        let mut bad = data.clone();
+        bad[0x180..0x184].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(MachO::parse(&bad).err(), Some(Error::BadCommand(0x140)));
+
+        // A huge segment
+        let mut bad = data.clone();
+        bad[0x160..0x168].copy_from_slice(&(1u64 << 40).to_le_bytes());
+        assert_eq!(MachO::parse(&bad).unwrap().load(), Err(Error::TooLarge));
+
+        // A thread state without a pc
+        let mut bad = data.clone();
+        bad[0x2c..0x30].copy_from_slice(&0x10u32.to_le_bytes());
+        assert_eq!(
+            MachO::parse(&bad).unwrap().entry_point(),
+            Err(Error::Truncated)
+        );
+    }
+}
@@ -1,93 +1,377 @@
 // SPDX-License-Identifier: MIT
+use crate::block::{check_request, BlockDevice, Error};
+use crate::cache;
 use crate::println;
 use alloc::boxed::Box;
-use core::cmp::min;
-use core::ffi::c_void;
-use fatfs::SeekFrom;
+use alloc::vec::Vec;
+use core::ffi::{c_int, c_void};
+use core::sync::atomic::{AtomicBool, Ordering};
 
+#[cfg(not(test))]
 extern "C" {
-    fn nvme_read(nsid: u32, lba: u64, buffer: *mut c_void) -> bool;
+    fn nvme_read_blocks(
+        nsid: u32,
+        lba: u64,
+        count: u32,
+        block_size: u32,
+        buffer: *mut c_void,
+    ) -> bool;
+    fn nvme_write_blocks(
+        nsid: u32,
+        lba: u64,
+        count: u32,
+        block_size: u32,
+        buffer: *const c_void,
+    ) -> bool;
+    fn nvme_get_block_size(nsid: u32) -> u32;
+    fn nvme_get_capacity(nsid: u32) -> u64;
 }
 
-const SECTOR_SIZE: usize = 4096;
+// Host tests have no NVMe controller, they transfer from a disk in memory instead
+#[cfg(test)]
+use tests::{nvme_get_block_size, nvme_get_capacity, nvme_read_blocks, nvme_write_blocks};
 
-pub type Error = ();
+extern "C" {
+    fn nvme_flush(nsid: u32) -> bool;
+    fn nvme_get_namespaces(nsids: *mut u32, max: c_int) -> c_int;
+}
+
+// Writing to the SSD is opt-in, so that bugs in the storage stack can't corrupt user data
+static WRITES_ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn set_writes_enabled(enable: bool) {
+    WRITES_ENABLED.store(enable, Ordering::Relaxed);
+}
+
+pub fn writes_enabled() -> bool {
+    WRITES_ENABLED.load(Ordering::Relaxed)
+}
+
+#[no_mangle]
+pub extern "C" fn rust_nvme_enable_writes(enable: bool) {
+    set_writes_enabled(enable);
+}
+
+// Apple SSDs only have a handful of namespaces
+const MAX_NAMESPACES: usize = 16;
+
+/// IDs of all active namespaces, or just namespace 1 if the controller can't tell.
+pub fn namespaces() -> Vec<u32> {
+    let mut nsids = [0u32; MAX_NAMESPACES];
+    let count = unsafe { nvme_get_namespaces(nsids.as_mut_ptr(), MAX_NAMESPACES as c_int) };
+    if count <= 0 {
+        println!("nvme: can't list namespaces, assuming namespace 1");
+        return vec![1];
+    }
+    nsids[..count as usize].to_vec()
+}
+
+// NVMe page size, multi-block transfers require page aligned buffers
+const PAGE_SIZE: usize = 4096;
+// Size of the bounce buffer for unaligned transfers, in pages
+const BOUNCE_PAGES: usize = 32;
 
 #[repr(C, align(4096))]
-struct SectorBuffer([u8; SECTOR_SIZE]);
+struct Page([u8; PAGE_SIZE]);
 
-fn alloc_sector_buf() -> Box<SectorBuffer> {
-    let p: Box<SectorBuffer> = unsafe { Box::new_zeroed().assume_init() };
-    debug_assert_eq!(0, p.0.as_ptr().align_offset(4096));
-    p
+/// The LBA size of the namespace, which is 0 if it couldn't be identified.
+fn get_block_size(nsid: u32) -> Result<usize, Error> {
+    let block_size = unsafe { nvme_get_block_size(nsid) } as usize;
+    if block_size == 0 || !block_size.is_power_of_two() || block_size > cache::MAX_BLOCK_SIZE {
+        println!(
+            "nvme: unusable block size {} for namespace {}",
+            block_size, nsid
+        );
+        return Err(Error::BadBlockSize(block_size));
+    }
+    Ok(block_size)
 }
 
+/// Number of blocks of the storage, from the namespace capacity and the starting offset.
+fn get_block_count(nsid: u32, offset: u64) -> u64 {
+    let capacity = unsafe { nvme_get_capacity(nsid) };
+    if capacity == 0 {
+        println!(
+            "nvme: unknown capacity for namespace {}, not bounds checking",
+            nsid
+        );
+        return u64::MAX - offset;
+    }
+    capacity.saturating_sub(offset)
+}
+
+fn is_aligned(buf: &[u8]) -> bool {
+    buf.as_ptr().align_offset(PAGE_SIZE) == 0
+}
+
+/// An NVMe namespace as a block device, starting `offset` blocks into the namespace.
+///
+/// There is no caching here, wrap it in a `cache::CachedDevice` for filesystem use.
 pub struct NVMEStorage {
     nsid: u32,
     offset: u64,
-    lba: Option<u64>,
-    buf: Box<SectorBuffer>,
-    pos: u64,
+    block_size: usize,
+    block_count: u64,
+    bounce: Option<Box<[Page]>>,
 }
 
 impl NVMEStorage {
-    pub fn new(nsid: u32, offset: u64) -> NVMEStorage {
-        NVMEStorage {
-            nsid: nsid,
-            offset: offset,
-            lba: None,
-            buf: alloc_sector_buf(),
-            pos: 0,
-        }
+    /// Open namespace `nsid`, with `offset` (in logical blocks) as the start of the storage.
+    ///
+    /// Fails if the block size of the namespace is unknown or unsupported, as every LBA would
+    /// be off otherwise.
+    pub fn new(nsid: u32, offset: u64) -> Result<NVMEStorage, Error> {
+        Ok(NVMEStorage {
+            nsid,
+            offset,
+            block_size: get_block_size(nsid)?,
+            block_count: get_block_count(nsid, offset),
+            bounce: None,
+        })
     }
-}
 
-impl fatfs::IoBase for NVMEStorage {
-    type Error = Error;
-}
+    pub fn nsid(&self) -> u32 {
+        self.nsid
+    }
 
-impl fatfs::Read for NVMEStorage {
-    fn read(&mut self, mut buf: &mut [u8]) -> Result<usize, Self::Error> {
-        let mut read = 0;
-
-        while !buf.is_empty() {
-            let lba = self.pos / SECTOR_SIZE as u64;
-            let off = self.pos as usize % SECTOR_SIZE;
-
-            if Some(lba) != self.lba {
-                self.lba = Some(lba);
-                let lba = lba + self.offset;
-                if !unsafe { nvme_read(self.nsid, lba, self.buf.0.as_mut_ptr() as *mut c_void) } {
-                    println!("nvme_read({}, {}) failed", self.nsid, lba);
-                    return Err(());
-                }
+    /// Run `f` on each `BOUNCE_PAGES` sized chunk of a `len` byte transfer starting at `lba`,
+    /// with a page aligned bounce buffer for that chunk.
+    fn with_bounce<F>(&mut self, lba: u64, len: usize, mut f: F) -> Result<(), Error>
+    where
+        F: FnMut(&Self, u64, usize, &mut [u8]) -> Result<(), Error>,
+    {
+        let mut pages = self
+            .bounce
+            .take()
+            .unwrap_or_else(|| unsafe { Box::new_zeroed_slice(BOUNCE_PAGES).assume_init() });
+        let bounce = unsafe {
+            core::slice::from_raw_parts_mut(pages.as_mut_ptr() as *mut u8, BOUNCE_PAGES * PAGE_SIZE)
+        };
+
+        let blocks_per_chunk = bounce.len() / self.block_size;
+        let mut ret = Ok(());
+        for (i, off) in (0..len).step_by(bounce.len()).enumerate() {
+            let chunk_len = bounce.len().min(len - off);
+            let lba = lba + (i * blocks_per_chunk) as u64;
+            ret = f(self, lba, off, &mut bounce[..chunk_len]);
+            if ret.is_err() {
+                break;
             }
-            let copy_len = min(SECTOR_SIZE - off, buf.len());
-            buf[..copy_len].copy_from_slice(&self.buf.0[off..off + copy_len]);
-            buf = &mut buf[copy_len..];
-            read += copy_len;
-            self.pos += copy_len as u64;
         }
-        Ok(read)
+
+        self.bounce = Some(pages);
+        ret
+    }
+
+    /// Read into a page aligned `buf`, with multi-block commands.
+    fn read_aligned(&self, lba: u64, buf: &mut [u8]) -> Result<(), Error> {
+        let lba = lba + self.offset;
+        let count = (buf.len() / self.block_size) as u64;
+        if !unsafe {
+            nvme_read_blocks(
+                self.nsid,
+                lba,
+                count as u32,
+                self.block_size as u32,
+                buf.as_mut_ptr() as *mut c_void,
+            )
+        } {
+            println!("nvme_read_blocks({}, {}, {}) failed", self.nsid, lba, count);
+            return Err(Error::Io { lba, count });
+        }
+        Ok(())
+    }
+
+    fn write_aligned(&self, lba: u64, buf: &[u8]) -> Result<(), Error> {
+        let lba = lba + self.offset;
+        let count = (buf.len() / self.block_size) as u64;
+        if !unsafe {
+            nvme_write_blocks(
+                self.nsid,
+                lba,
+                count as u32,
+                self.block_size as u32,
+                buf.as_ptr() as *const c_void,
+            )
+        } {
+            println!(
+                "nvme_write_blocks({}, {}, {}) failed",
+                self.nsid, lba, count
+            );
+            return Err(Error::Io { lba, count });
+        }
+        Ok(())
     }
 }
 
-impl fatfs::Write for NVMEStorage {
-    fn write(&mut self, _buf: &[u8]) -> Result<usize, Self::Error> {
-        Err(())
+impl BlockDevice for NVMEStorage {
+    fn block_size(&self) -> usize {
+        self.block_size
     }
-    fn flush(&mut self) -> Result<(), Self::Error> {
-        Err(())
+
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), Error> {
+        check_request(self, lba, buf.len())?;
+        if is_aligned(buf) {
+            return self.read_aligned(lba, buf);
+        }
+        self.with_bounce(lba, buf.len(), |dev, lba, off, bounce| {
+            dev.read_aligned(lba, bounce)?;
+            buf[off..off + bounce.len()].copy_from_slice(bounce);
+            Ok(())
+        })
+    }
+
+    fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> Result<(), Error> {
+        check_request(self, lba, buf.len())?;
+        if self.is_read_only() {
+            println!(
+                "nvme: refusing to write to namespace {}, writes are disabled",
+                self.nsid
+            );
+            return Err(Error::ReadOnly);
+        }
+        if is_aligned(buf) {
+            return self.write_aligned(lba, buf);
+        }
+        self.with_bounce(lba, buf.len(), |dev, lba, off, bounce| {
+            bounce.copy_from_slice(&buf[off..off + bounce.len()]);
+            dev.write_aligned(lba, bounce)
+        })
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        if !unsafe { nvme_flush(self.nsid) } {
+            println!("nvme_flush({}) failed", self.nsid);
+            return Err(Error::Io { lba: 0, count: 0 });
+        }
+        Ok(())
+    }
+
+    fn is_read_only(&self) -> bool {
+        !writes_enabled()
     }
 }
 
-impl fatfs::Seek for NVMEStorage {
-    fn seek(&mut self, from: SeekFrom) -> Result<u64, Self::Error> {
-        self.pos = match from {
-            SeekFrom::Start(n) => n,
-            SeekFrom::End(_n) => panic!("SeekFrom::End not supported"),
-            SeekFrom::Current(n) => self.pos.checked_add_signed(n).ok_or(())?,
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::CachedDevice;
+    use core::cell::{Cell, RefCell};
+    use std::vec::Vec;
+
+    const BLOCK_SIZE: usize = 512;
+    const BLOCKS: usize = 64;
+
+    std::thread_local! {
+        static DISK: RefCell<Vec<u8>> = RefCell::new((0..BLOCK_SIZE * BLOCKS).map(|i| (i * 7 / 3) as u8).collect());
+        static LBA_SIZE: Cell<u32> = const { Cell::new(BLOCK_SIZE as u32) };
+    }
+
+    pub(super) unsafe fn nvme_get_block_size(_nsid: u32) -> u32 {
+        LBA_SIZE.with(Cell::get)
+    }
+
+    pub(super) unsafe fn nvme_get_capacity(_nsid: u32) -> u64 {
+        BLOCKS as u64
+    }
+
+    /// The range of the disk a transfer covers, with the checks nvme_rw() makes.
+    fn transfer(lba: u64, count: u32, block_size: u32, buffer: usize) -> Option<(usize, usize)> {
+        if !buffer.is_multiple_of(PAGE_SIZE) || block_size as usize != BLOCK_SIZE {
+            return None;
+        }
+        let start = lba as usize * BLOCK_SIZE;
+        let len = count as usize * BLOCK_SIZE;
+        (start + len <= BLOCK_SIZE * BLOCKS).then_some((start, len))
+    }
+
+    pub(super) unsafe fn nvme_read_blocks(
+        _nsid: u32,
+        lba: u64,
+        count: u32,
+        block_size: u32,
+        buffer: *mut c_void,
+    ) -> bool {
+        let Some((start, len)) = transfer(lba, count, block_size, buffer as usize) else {
+            return false;
+        };
+        let buf = unsafe { core::slice::from_raw_parts_mut(buffer as *mut u8, len) };
+        DISK.with_borrow(|disk| buf.copy_from_slice(&disk[start..start + len]));
+        true
+    }
+
+    pub(super) unsafe fn nvme_write_blocks(
+        _nsid: u32,
+        lba: u64,
+        count: u32,
+        block_size: u32,
+        buffer: *const c_void,
+    ) -> bool {
+        let Some((start, len)) = transfer(lba, count, block_size, buffer as usize) else {
+            return false;
         };
-        Ok(self.pos)
+        let buf = unsafe { core::slice::from_raw_parts(buffer as *const u8, len) };
+        DISK.with_borrow_mut(|disk| disk[start..start + len].copy_from_slice(buf));
+        true
+    }
+
+    fn storage() -> NVMEStorage {
+        NVMEStorage::new(1, 2).unwrap()
+    }
+
+    fn disk(range: core::ops::Range<usize>) -> Vec<u8> {
+        DISK.with_borrow(|disk| disk[range].to_vec())
+    }
+
+    #[test]
+    fn small_blocks() {
+        let mut dev = storage();
+
+        // Page aligned, straight into the buffer, and unaligned, through the bounce buffer
+        let mut page = Page([0; PAGE_SIZE]);
+        dev.read_blocks(3, &mut page.0[..5 * BLOCK_SIZE]).unwrap();
+        assert_eq!(
+            page.0[..5 * BLOCK_SIZE],
+            disk(5 * BLOCK_SIZE..10 * BLOCK_SIZE)
+        );
+        let mut buf = vec![0; 9 * BLOCK_SIZE + 1];
+        dev.read_blocks(0, &mut buf[1..]).unwrap();
+        assert_eq!(buf[1..], disk(2 * BLOCK_SIZE..11 * BLOCK_SIZE));
+
+        set_writes_enabled(true);
+        let data: Vec<u8> = (0..3 * BLOCK_SIZE).map(|i| (i % 251) as u8).collect();
+        dev.write_blocks(10, &data).unwrap();
+        set_writes_enabled(false);
+        assert_eq!(disk(12 * BLOCK_SIZE..15 * BLOCK_SIZE), data);
+        assert!(matches!(dev.write_blocks(10, &data), Err(Error::ReadOnly)));
+
+        // Clean multi-block reads bypass the cache
+        let mut dev = CachedDevice::new(storage());
+        let mut buf = vec![0; 16 * BLOCK_SIZE];
+        dev.read_blocks(8, &mut buf).unwrap();
+        assert_eq!(buf, disk(10 * BLOCK_SIZE..26 * BLOCK_SIZE));
+    }
+
+    #[test]
+    fn block_sizes() {
+        let dev = storage();
+        assert_eq!(
+            (dev.block_size(), dev.block_count()),
+            (BLOCK_SIZE, BLOCKS as u64 - 2)
+        );
+
+        // Failed identify, not a power of two, too large for the cache
+        for size in [0, 520, 8192] {
+            LBA_SIZE.with(|lba_size| lba_size.set(size));
+            assert!(matches!(
+                NVMEStorage::new(1, 0),
+                Err(Error::BadBlockSize(s)) if s == size as usize
+            ));
+        }
+        LBA_SIZE.with(|lba_size| lba_size.set(BLOCK_SIZE as u32));
     }
 }
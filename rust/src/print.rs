@@ -1,6 +1,8 @@
 // SPDX-License-Identifier: MIT
+#[cfg(not(test))]
 use core::ffi::c_void;
 
+#[cfg(not(test))]
 extern "C" {
     fn iodev_console_write(buf: *const c_void, len: u64);
 }
@@ -31,12 +33,20 @@ impl IODevConsoleWriter {
     }
 }
 
+#[cfg(not(test))]
 #[inline]
 fn write(msg: &str) -> core::fmt::Result {
     unsafe { iodev_console_write(msg.as_ptr() as _, msg.len() as u64) };
     Ok(())
 }
 
+// Host tests have no iodev console, send everything to stdout instead
+#[cfg(test)]
+fn write(msg: &str) -> core::fmt::Result {
+    std::print!("{}", msg);
+    Ok(())
+}
+
 #[macro_export]
 macro_rules! println {
     () => { $crate::println!("") };
@@ -0,0 +1,124 @@
+// SPDX-License-Identifier: MIT
+
+//! A block device over a range of memory, usually a disk image appended to m1n1 as a payload.
+
+use crate::block::{check_request, BlockDevice, Error};
+use crate::c_size_t;
+use crate::println;
+use core::ffi::c_void;
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+static RAMDISK_BASE: AtomicPtr<u8> = AtomicPtr::new(core::ptr::null_mut());
+static RAMDISK_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+const EFI_SIGNATURE: &[u8] = b"EFI PART";
+
+/// Register the memory range `base..base + size` as the RAM disk.
+///
+/// # Safety
+///
+/// The range must be valid for reads and writes, and not used for anything else while m1n1 runs.
+#[no_mangle]
+pub unsafe extern "C" fn rust_register_ramdisk(base: *mut c_void, size: c_size_t) {
+    println!("ramdisk: registered {:p}, {:#x} bytes", base, size);
+    RAMDISK_SIZE.store(size, Ordering::Relaxed);
+    RAMDISK_BASE.store(base as *mut u8, Ordering::Release);
+}
+
+pub struct RamDisk {
+    base: *mut u8,
+    size: usize,
+    block_size: usize,
+}
+
+impl RamDisk {
+    /// Wrap `size` bytes of memory at `base`, as a device with `block_size` byte blocks.
+    ///
+    /// # Safety
+    ///
+    /// The range must be valid for reads and writes for as long as the device is in use.
+    pub unsafe fn new(base: *mut u8, size: usize, block_size: usize) -> Self {
+        RamDisk {
+            base,
+            size,
+            block_size,
+        }
+    }
+
+    /// The RAM disk registered from C, if any.
+    ///
+    /// The block size is 4096 if there is a GPT header in the second 4K block, and 512 otherwise,
+    /// which also suits bare FAT images.
+    pub fn registered() -> Option<Self> {
+        let base = RAMDISK_BASE.load(Ordering::Acquire);
+        if base.is_null() {
+            return None;
+        }
+        let size = RAMDISK_SIZE.load(Ordering::Relaxed);
+        // rust_register_ramdisk() promised this is ours
+        let mut disk = unsafe { Self::new(base, size, 512) };
+        if disk.data().get(4096..4104) == Some(EFI_SIGNATURE) {
+            disk.block_size = 4096;
+        }
+        Some(disk)
+    }
+
+    fn data(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.base, self.size) }
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        (self.size / self.block_size) as u64
+    }
+
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), Error> {
+        check_request(self, lba, buf.len())?;
+        let off = lba as usize * self.block_size;
+        buf.copy_from_slice(&self.data()[off..off + buf.len()]);
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> Result<(), Error> {
+        check_request(self, lba, buf.len())?;
+        let off = lba as usize * self.block_size;
+        self.data()[off..off + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    #[test]
+    fn registered_ramdisk() {
+        assert!(RamDisk::registered().is_none());
+
+        let mut image: Vec<u8> = (0..16 * 4096).map(|i| (i / 4096) as u8).collect();
+        image[4096..4104].copy_from_slice(EFI_SIGNATURE);
+        unsafe { rust_register_ramdisk(image.as_mut_ptr() as *mut c_void, image.len()) };
+
+        let mut disk = RamDisk::registered().unwrap();
+        assert_eq!(disk.block_size(), 4096);
+        assert_eq!(disk.block_count(), 16);
+
+        let mut buf = [0u8; 8192];
+        disk.write_blocks(3, &[0xaa; 4096]).unwrap();
+        disk.read_blocks(2, &mut buf).unwrap();
+        assert_eq!(buf[0], 2);
+        assert_eq!(buf[4096..], [0xaa; 4096]);
+        assert!(disk.read_blocks(15, &mut buf).is_err());
+        assert_eq!(image[3 * 4096], 0xaa);
+    }
+}
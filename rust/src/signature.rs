@@ -0,0 +1,280 @@
+// SPDX-License-Identifier: MIT
+
+//! Ed25519 signatures on chainloaded images.
+//!
+//! Files are signed with a detached signature in `<path>.sig`, either the raw 64 bytes or their
+//! hex encoding. The public key is built in with `M1N1_CHAINLOAD_KEY=<hex>` in the environment,
+//! or set with a `chainload_key=<hex>` variable. A built-in key always enforces signatures, so a
+//! locked-down machine can't be redirected with variables. Otherwise `chainload_verify=` picks
+//! the policy, which defaults to enforcing once there is a key.
+
+use crate::ed25519::{self, PUBLIC_KEY_SIZE, SIGNATURE_SIZE};
+use crate::hex;
+use crate::println;
+use alloc::boxed::Box;
+use core::ffi::{c_char, c_int, CStr};
+use core::fmt;
+use core::sync::atomic::{AtomicPtr, AtomicU8, Ordering};
+
+/// Extension of detached signature files.
+pub const SIG_EXT: &str = ".sig";
+
+const BUILTIN_KEY: Option<&str> = option_env!("M1N1_CHAINLOAD_KEY");
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Policy {
+    /// Don't check signatures.
+    Off,
+    /// Check signatures, but only complain about bad ones.
+    Warn,
+    /// Refuse files without a good signature.
+    Enforce,
+}
+
+impl Policy {
+    fn parse(s: &str) -> Option<Policy> {
+        match s {
+            "off" => Some(Policy::Off),
+            "warn" => Some(Policy::Warn),
+            "enforce" => Some(Policy::Enforce),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error {
+    NoKey,
+    NoSignature,
+    BadSignatureFile,
+    BadSignature,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Error::NoKey => "no public key configured",
+            Error::NoSignature => "no signature",
+            Error::BadSignatureFile => "malformed signature file",
+            Error::BadSignature => "signature does not match",
+        })
+    }
+}
+
+// Policy as set from C, POLICY_UNSET until then
+const POLICY_UNSET: u8 = u8::MAX;
+static POLICY: AtomicU8 = AtomicU8::new(POLICY_UNSET);
+static KEY: AtomicPtr<[u8; PUBLIC_KEY_SIZE]> = AtomicPtr::new(core::ptr::null_mut());
+
+fn builtin_key() -> Option<[u8; PUBLIC_KEY_SIZE]> {
+    let key = BUILTIN_KEY?;
+    let parsed = hex::parse(key.trim_ascii());
+    if parsed.is_none() {
+        // Fail closed, nothing verifies against an invalid key
+        println!("signature: invalid built-in key {}", key);
+        return Some([0; PUBLIC_KEY_SIZE]);
+    }
+    parsed
+}
+
+/// Verifies files against a key according to a policy.
+#[derive(Debug, Copy, Clone)]
+pub struct Verifier {
+    pub key: Option<[u8; PUBLIC_KEY_SIZE]>,
+    pub policy: Policy,
+}
+
+impl Verifier {
+    /// The verifier configured at build time or from C.
+    pub fn current() -> Verifier {
+        if let Some(key) = builtin_key() {
+            return Verifier {
+                key: Some(key),
+                policy: Policy::Enforce,
+            };
+        }
+
+        let key = KEY.load(Ordering::Acquire);
+        // Keys are leaked by rust_set_chainload_key() and never freed
+        let key = (!key.is_null()).then(|| unsafe { *key });
+        let policy = match POLICY.load(Ordering::Relaxed) {
+            0 => Policy::Off,
+            1 => Policy::Warn,
+            2 => Policy::Enforce,
+            _ if key.is_some() => Policy::Enforce,
+            _ => Policy::Off,
+        };
+        Verifier { key, policy }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.policy != Policy::Off
+    }
+
+    fn verify(&self, data: &[u8], sig: Option<&[u8]>) -> Result<(), Error> {
+        let key = self.key.as_ref().ok_or(Error::NoKey)?;
+        let sig = sig.ok_or(Error::NoSignature)?;
+        let sig: [u8; SIGNATURE_SIZE] = match sig.try_into() {
+            Ok(sig) => sig,
+            Err(_) => core::str::from_utf8(sig)
+                .ok()
+                .and_then(|sig| hex::parse(sig.trim_ascii()))
+                .ok_or(Error::BadSignatureFile)?,
+        };
+
+        if ed25519::verify(key, data, &sig) {
+            Ok(())
+        } else {
+            Err(Error::BadSignature)
+        }
+    }
+
+    /// Check the contents of `name` against its detached signature, if it has one.
+    ///
+    /// This only fails if the policy is to enforce signatures.
+    pub fn check(&self, name: &str, data: &[u8], sig: Option<&[u8]>) -> Result<(), Error> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+
+        match (self.verify(data, sig), self.policy) {
+            (Ok(()), _) => {
+                println!("signature: {} verified", name);
+                Ok(())
+            }
+            (Err(err), Policy::Enforce) => {
+                println!("signature: refusing {}: {}", name, err);
+                Err(err)
+            }
+            (Err(err), _) => {
+                println!("signature: WARNING: {}: {}, loading anyway", name, err);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Set the public key chainloaded files are verified against, as hex.
+///
+/// # Safety
+///
+/// `key` must be a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn rust_set_chainload_key(key: *const c_char) -> c_int {
+    if BUILTIN_KEY.is_some() {
+        println!("signature: ignoring key variable, a key is built in");
+        return -1;
+    }
+
+    let key = unsafe { CStr::from_ptr(key) }.to_str().ok();
+    match key.and_then(|key| hex::parse(key.trim_ascii())) {
+        Some(key) => {
+            KEY.store(Box::into_raw(Box::new(key)), Ordering::Release);
+            0
+        }
+        None => {
+            println!("signature: invalid key");
+            -1
+        }
+    }
+}
+
+/// Set the signature policy, one of `off`, `warn` or `enforce`.
+///
+/// # Safety
+///
+/// `policy` must be a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn rust_set_chainload_policy(policy: *const c_char) -> c_int {
+    if BUILTIN_KEY.is_some() {
+        println!("signature: ignoring policy variable, a key is built in");
+        return -1;
+    }
+
+    let policy = unsafe { CStr::from_ptr(policy) }.to_str().ok();
+    match policy.and_then(Policy::parse) {
+        Some(policy) => {
+            POLICY.store(policy as u8, Ordering::Relaxed);
+            0
+        }
+        None => {
+            println!("signature: unknown policy, expected off, warn or enforce");
+            -1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::format;
+
+    const KEY: &str = "03a107bff3ce10be1d70dd18e74bc09967e4d6309ba50d5f1ddc8664125531b8";
+    const MSG: &[u8] = b"m1n1 stage 2";
+    const SIG: &str = "e006a8f825809fc7412ab1de4de714ce4ed3c3768eefd68f228892815e721aab\
+                       7d30bdd2f6a689b38c84174dca68fa60c8f9e890df96c1e4edfd697716b25406";
+
+    fn verifier(policy: Policy) -> Verifier {
+        Verifier {
+            key: hex::parse(KEY),
+            policy,
+        }
+    }
+
+    #[test]
+    fn policies() {
+        let raw: [u8; 64] = hex::parse(SIG).unwrap();
+        let hex = format!("{}\n", SIG.to_uppercase());
+        let mut bad = raw;
+        bad[63] ^= 0x40;
+
+        let enforce = verifier(Policy::Enforce);
+        assert_eq!(enforce.check("a", MSG, Some(&raw)), Ok(()));
+        assert_eq!(enforce.check("a", MSG, Some(hex.as_bytes())), Ok(()));
+        assert_eq!(
+            enforce.check("a", b"m1n1 stage 3", Some(&raw)),
+            Err(Error::BadSignature)
+        );
+        assert_eq!(
+            enforce.check("a", MSG, Some(&bad)),
+            Err(Error::BadSignature)
+        );
+        assert_eq!(enforce.check("a", MSG, None), Err(Error::NoSignature));
+        assert_eq!(
+            enforce.check("a", MSG, Some(b"signed, honest")),
+            Err(Error::BadSignatureFile)
+        );
+
+        let no_key = Verifier {
+            key: None,
+            policy: Policy::Enforce,
+        };
+        assert_eq!(no_key.check("a", MSG, Some(&raw)), Err(Error::NoKey));
+
+        for policy in [Policy::Warn, Policy::Off] {
+            let verifier = verifier(policy);
+            assert_eq!(verifier.check("a", MSG, Some(&bad)), Ok(()));
+            assert_eq!(verifier.check("a", MSG, None), Ok(()));
+        }
+    }
+
+    #[test]
+    fn configuration() {
+        assert!(!Verifier::current().is_enabled());
+
+        unsafe {
+            assert_eq!(rust_set_chainload_policy(c"paranoid".as_ptr()), -1);
+            assert_eq!(rust_set_chainload_key(c"1234".as_ptr()), -1);
+        }
+        assert!(!Verifier::current().is_enabled());
+
+        let key = std::ffi::CString::new(KEY).unwrap();
+        unsafe { assert_eq!(rust_set_chainload_key(key.as_ptr()), 0) };
+        let current = Verifier::current();
+        assert_eq!(current.key, hex::parse(KEY));
+        assert_eq!(current.policy, Policy::Enforce);
+
+        unsafe { assert_eq!(rust_set_chainload_policy(c"warn".as_ptr()), 0) };
+        assert_eq!(Verifier::current().policy, Policy::Warn);
+    }
+}
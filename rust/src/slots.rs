@@ -0,0 +1,263 @@
+// SPDX-License-Identifier: MIT
+
+//! A/B boot slots, with a number of tries for slots that haven't booted successfully yet.
+//!
+//! A chainload path containing `{slot}` is booted from slot `a` or `b`, as picked by the state
+//! file on the same partition. It holds `name=value` lines like the configuration file:
+//!
+//! ```text
+//! active=a
+//! a.tries=3
+//! a.successful=0
+//! b.tries=0
+//! b.successful=1
+//! ```
+//!
+//! The active slot is booted while it is marked successful or has tries left, taking one each
+//! time, otherwise the other slot is. After installing an update to a slot, set it active with
+//! a few tries, and mark it successful once it is known to boot. The booted slot is passed on
+//! as `chosen.asahi,m1n1-boot-slot`.
+//!
+//! Without a state file both slots are taken to be good, starting with `a`.
+//!
+//! Taking a try means writing the state file, so `nvme_writes=1` must be set for slots that
+//! aren't marked successful. A slot whose try can't be saved isn't booted, only a successful
+//! other slot is.
+
+use crate::println;
+use alloc::ffi::CString;
+use alloc::string::String;
+use core::fmt;
+use core::fmt::Write;
+
+/// Path of the state file, relative to the root of the chainload partition.
+pub const STATE_PATH: &str = "m1n1/slots.conf";
+/// Placeholder for the slot name in chainload paths.
+pub const PLACEHOLDER: &str = "{slot}";
+
+const CHOSEN_VAR: &str = "chosen.asahi,m1n1-boot-slot";
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Slot {
+    A,
+    B,
+}
+
+impl Slot {
+    pub fn other(self) -> Slot {
+        match self {
+            Slot::A => Slot::B,
+            Slot::B => Slot::A,
+        }
+    }
+
+    fn parse(name: &str) -> Option<Slot> {
+        match name {
+            "a" => Some(Slot::A),
+            "b" => Some(Slot::B),
+            _ => None,
+        }
+    }
+
+    /// `path` with the placeholder replaced by the name of this slot.
+    pub fn path(self, path: &str) -> String {
+        path.replace(PLACEHOLDER, &format!("{}", self))
+    }
+
+    /// The variable telling the next stage which slot it was booted from.
+    pub fn chosen_var(self) -> CString {
+        CString::new(format!("{}={}", CHOSEN_VAR, self)).unwrap()
+    }
+}
+
+impl fmt::Display for Slot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Slot::A => "a",
+            Slot::B => "b",
+        })
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SlotState {
+    /// Boot attempts left before the slot is given up on.
+    pub tries: u32,
+    /// Whether the slot is known to boot, set by the next stage.
+    pub successful: bool,
+}
+
+impl SlotState {
+    pub fn is_bootable(&self) -> bool {
+        self.successful || self.tries > 0
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct State {
+    pub active: Slot,
+    pub slots: [SlotState; 2],
+}
+
+impl Default for State {
+    fn default() -> Self {
+        let good = SlotState {
+            tries: 0,
+            successful: true,
+        };
+        State {
+            active: Slot::A,
+            slots: [good; 2],
+        }
+    }
+}
+
+impl State {
+    /// Parse a state file, falling back to the defaults for anything missing or malformed.
+    pub fn parse(text: &[u8]) -> State {
+        let mut state = State::default();
+
+        for (i, line) in text.split(|&c| c == b'\n').enumerate() {
+            let line = line.trim_ascii();
+            if line.is_empty() || line[0] == b'#' {
+                continue;
+            }
+
+            let ok = core::str::from_utf8(line)
+                .ok()
+                .and_then(|line| line.split_once('='))
+                .and_then(|(name, value)| state.set(name, value));
+            if ok.is_none() {
+                println!("slots: line {}: bad state variable", i + 1);
+            }
+        }
+
+        state
+    }
+
+    fn set(&mut self, name: &str, value: &str) -> Option<()> {
+        if name == "active" {
+            self.active = Slot::parse(value)?;
+            return Some(());
+        }
+
+        let (slot, field) = name.split_once('.')?;
+        let slot = self.slot_mut(Slot::parse(slot)?);
+        match field {
+            "tries" => slot.tries = value.parse().ok()?,
+            "successful" => slot.successful = value == "1",
+            _ => return None,
+        }
+        Some(())
+    }
+
+    pub fn slot(&self, slot: Slot) -> &SlotState {
+        &self.slots[slot as usize]
+    }
+
+    fn slot_mut(&mut self, slot: Slot) -> &mut SlotState {
+        &mut self.slots[slot as usize]
+    }
+
+    /// Pick the slot to boot, taking a try from it if it hasn't booted successfully yet.
+    pub fn select(&mut self) -> Option<Slot> {
+        let slot = [self.active, self.active.other()]
+            .into_iter()
+            .find(|&slot| self.slot(slot).is_bootable())?;
+
+        let state = self.slot_mut(slot);
+        if !state.successful {
+            state.tries -= 1;
+        }
+        Some(slot)
+    }
+
+    /// Give up on `slot`, which failed to load.
+    pub fn mark_bad(&mut self, slot: Slot) {
+        *self.slot_mut(slot) = SlotState {
+            tries: 0,
+            successful: false,
+        };
+    }
+
+    /// The contents of the state file.
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        writeln!(text, "active={}", self.active).unwrap();
+        for slot in [Slot::A, Slot::B] {
+            let state = self.slot(slot);
+            writeln!(text, "{}.tries={}", slot, state.tries).unwrap();
+            writeln!(text, "{}.successful={}", slot, state.successful as u8).unwrap();
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let state =
+            State::parse(b"# updated\nactive=b\n b.tries=3 \nb.successful=0\na.successful=1\n");
+        assert_eq!(state.active, Slot::B);
+        assert_eq!(
+            state.slots,
+            [
+                SlotState {
+                    tries: 0,
+                    successful: true
+                },
+                SlotState {
+                    tries: 3,
+                    successful: false
+                },
+            ]
+        );
+        assert_eq!(State::parse(state.to_text().as_bytes()), state);
+
+        // Malformed lines are skipped
+        let state = State::parse(b"active=c\nb.tries=-1\nc.tries=1\nb.color=red\nb.successful=0");
+        assert_eq!(state.active, Slot::A);
+        assert_eq!(
+            *state.slot(Slot::B),
+            SlotState {
+                tries: 0,
+                successful: false
+            }
+        );
+        assert_eq!(State::parse(b""), State::default());
+    }
+
+    #[test]
+    fn fallback() {
+        let mut state = State::parse(b"active=a\na.tries=2\na.successful=0\n");
+        assert_eq!(state.select(), Some(Slot::A));
+        assert_eq!(state.select(), Some(Slot::A));
+        assert_eq!(state.slot(Slot::A).tries, 0);
+        // Out of tries, so it's the old slot from now on
+        assert_eq!(state.select(), Some(Slot::B));
+        assert_eq!(state.select(), Some(Slot::B));
+        assert_eq!(state.active, Slot::A);
+
+        // Until the next stage marks the new one successful
+        state.slots[0].successful = true;
+        assert_eq!(state.select(), Some(Slot::A));
+        assert_eq!(state.slot(Slot::A).tries, 0);
+
+        state.mark_bad(Slot::A);
+        state.mark_bad(Slot::B);
+        assert_eq!(state.select(), None);
+    }
+
+    #[test]
+    fn paths() {
+        assert_eq!(Slot::B.path("m1n1/boot-{slot}.bin"), "m1n1/boot-b.bin");
+        assert_eq!(Slot::A.path("{slot}/m1n1.bin"), "a/m1n1.bin");
+        assert_eq!(
+            Slot::A.chosen_var().as_c_str(),
+            c"chosen.asahi,m1n1-boot-slot=a"
+        );
+    }
+}
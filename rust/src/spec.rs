@@ -0,0 +1,505 @@
+// SPDX-License-Identifier: MIT
+
+//! Chainload spec parser.
+//!
+//! A spec is a `|`-separated list of alternatives, tried in order:
+//!
+//! ```text
+//! [ramdisk: | nsN:] [selector {, selector}] ; path {; option}
+//! ```
+//!
+//! Selectors are `partuuid=<uuid>`, `partlabel=<name>`, `parttype=<esp|guid>` and `ns=<n>`, and
+//! a bare partition UUID is accepted for compatibility with the original `<partuuid>;<path>`
+//! form. All partition selectors have to match. Without any, the whole device is taken to be
+//! the filesystem. Values and the path can be double quoted, with `\` escaping the next
+//! character, to include spaces, `,`, `;` or `|`.
+//!
+//! The only option is `sha256=<hex>`, the digest the file has to match.
+//!
+//! A `{slot}` in the path of a chainload spec boots from A/B slots, see [`crate::slots`].
+
+use crate::block::BlockDevice;
+use crate::gpt::{PartUuid, Partition, PartitionKind, PartitionTable, MBR};
+use crate::hex::{self, Hex};
+use crate::sha256;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use uuid::Uuid;
+
+/// The device to look for the partition on.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Device {
+    /// An NVMe namespace, or all of them in order.
+    Nvme(Option<u32>),
+    RamDisk,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PartType {
+    /// EFI system partition, in either a GPT or an MBR.
+    Esp,
+    Guid(Uuid),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Selector {
+    PartUuid(PartUuid),
+    PartLabel(String),
+    PartType(PartType),
+}
+
+impl Selector {
+    /// Whether `part`, from the partition table `pt`, is selected.
+    pub fn matches<D: BlockDevice>(&self, pt: &PartitionTable<D>, part: &Partition) -> bool {
+        match (self, part) {
+            (Selector::PartUuid(uuid), part) => pt.partuuid(part) == *uuid,
+            (Selector::PartLabel(label), Partition::Gpt(part)) => part.get_name() == *label,
+            (Selector::PartType(PartType::Esp), Partition::Gpt(part)) => {
+                part.get_kind() == PartitionKind::EfiSystem
+            }
+            (Selector::PartType(PartType::Esp), Partition::Mbr(part)) => {
+                part.get_type() == MBR::TYPE_EFI_SYSTEM
+            }
+            (Selector::PartType(PartType::Guid(guid)), Partition::Gpt(part)) => {
+                part.get_type_guid() == *guid
+            }
+            // MBR partitions have no labels or type GUIDs
+            _ => false,
+        }
+    }
+}
+
+/// `s` in double quotes, escaped so that `quoted()` reads it back unchanged.
+struct Quoted<'a>(&'a str);
+
+impl fmt::Display for Quoted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("\"")?;
+        for c in self.0.chars() {
+            if c == '"' || c == '\\' {
+                f.write_str("\\")?;
+            }
+            write!(f, "{}", c)?;
+        }
+        f.write_str("\"")
+    }
+}
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Selector::PartUuid(uuid) => write!(f, "partuuid={}", uuid),
+            Selector::PartLabel(label) => write!(f, "partlabel={}", Quoted(label)),
+            Selector::PartType(PartType::Esp) => f.write_str("parttype=esp"),
+            Selector::PartType(PartType::Guid(guid)) => write!(f, "parttype={}", guid),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Spec {
+    pub device: Device,
+    /// Partition selectors, empty for the whole device.
+    pub selectors: Vec<Selector>,
+    pub path: String,
+    /// Expected SHA-256 digest of the file.
+    pub sha256: Option<[u8; sha256::DIGEST_SIZE]>,
+}
+
+impl fmt::Display for Spec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.device {
+            Device::Nvme(None) => {}
+            Device::Nvme(Some(nsid)) => write!(f, "ns{}:", nsid)?,
+            Device::RamDisk => f.write_str("ramdisk:")?,
+        }
+        for (i, sel) in self.selectors.iter().enumerate() {
+            write!(f, "{}{}", if i > 0 { "," } else { "" }, sel)?;
+        }
+        write!(f, ";{}", Quoted(&self.path))?;
+        if let Some(digest) = &self.sha256 {
+            write!(f, ";sha256={}", Hex(digest))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// 1-based column (in characters) of the offending input.
+    pub column: usize,
+    pub msg: &'static str,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "column {}: {}", self.column, self.msg)
+    }
+}
+
+struct Parser<'a> {
+    s: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn rest(&self) -> &'a str {
+        &self.s[self.pos..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn bump(&mut self) {
+        if let Some(c) = self.peek() {
+            self.pos += c.len_utf8();
+        }
+    }
+
+    fn eat(&mut self, prefix: &str) -> bool {
+        let found = self.rest().starts_with(prefix);
+        if found {
+            self.pos += prefix.len();
+        }
+        found
+    }
+
+    fn skip_ws(&mut self) {
+        while self.peek().is_some_and(|c| c.is_ascii_whitespace()) {
+            self.bump();
+        }
+    }
+
+    fn error<T>(&self, pos: usize, msg: &'static str) -> Result<T, ParseError> {
+        Err(ParseError {
+            column: self.s[..pos].chars().count() + 1,
+            msg,
+        })
+    }
+
+    /// Everything up to one of `delims`, with surrounding whitespace trimmed.
+    fn word(&mut self, delims: &[char]) -> &'a str {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| !delims.contains(&c)) {
+            self.bump();
+        }
+        self.s[start..self.pos].trim()
+    }
+
+    fn quoted(&mut self) -> Result<String, ParseError> {
+        let start = self.pos;
+        self.bump();
+        let mut out = String::new();
+        loop {
+            match self.peek() {
+                None => return self.error(start, "unterminated quote"),
+                Some('"') => break,
+                Some('\\') => {
+                    self.bump();
+                    match self.peek() {
+                        Some(c) => out.push(c),
+                        None => return self.error(start, "unterminated quote"),
+                    }
+                }
+                Some(c) => out.push(c),
+            }
+            self.bump();
+        }
+        self.bump();
+        Ok(out)
+    }
+
+    /// A possibly quoted value, ending at one of `delims`.
+    fn value(&mut self, delims: &[char]) -> Result<String, ParseError> {
+        self.skip_ws();
+        if self.peek() == Some('"') {
+            self.quoted()
+        } else {
+            Ok(self.word(delims).into())
+        }
+    }
+
+    fn device_prefix(&mut self) -> Result<Device, ParseError> {
+        if self.eat("ramdisk:") {
+            return Ok(Device::RamDisk);
+        }
+        let digits = self.rest().strip_prefix("ns").map(|r| {
+            r.find(|c: char| !c.is_ascii_digit())
+                .map_or(r, |end| &r[..end])
+        });
+        match digits {
+            Some(digits)
+                if !digits.is_empty() && self.rest()[2 + digits.len()..].starts_with(':') =>
+            {
+                let start = self.pos;
+                self.pos += 2 + digits.len() + 1;
+                match digits.parse() {
+                    Ok(nsid) => Ok(Device::Nvme(Some(nsid))),
+                    Err(_) => self.error(start, "invalid namespace"),
+                }
+            }
+            _ => Ok(Device::Nvme(None)),
+        }
+    }
+
+    fn selector(&mut self, spec: &mut Spec) -> Result<(), ParseError> {
+        self.skip_ws();
+        let start = self.pos;
+        let key = self.word(&[',', ';', '|', '=', '"']);
+        if !self.eat("=") {
+            // Legacy bare partition UUID
+            let Some(uuid) = PartUuid::parse(key) else {
+                return self.error(start, "expected a partition UUID or key=value");
+            };
+            spec.selectors.push(Selector::PartUuid(uuid));
+            return Ok(());
+        }
+
+        self.skip_ws();
+        let value_start = self.pos;
+        let value = self.value(&[',', ';', '|'])?;
+        let selector = match key {
+            "partuuid" => match PartUuid::parse(&value) {
+                Some(uuid) => Selector::PartUuid(uuid),
+                None => return self.error(value_start, "invalid partition UUID"),
+            },
+            "partlabel" => Selector::PartLabel(value),
+            "parttype" if value.eq_ignore_ascii_case("esp") => Selector::PartType(PartType::Esp),
+            "parttype" => match Uuid::parse_str(&value) {
+                Ok(guid) => Selector::PartType(PartType::Guid(guid)),
+                Err(_) => return self.error(value_start, "unknown partition type"),
+            },
+            "ns" => {
+                match value.parse() {
+                    Ok(nsid) if spec.device != Device::RamDisk => {
+                        spec.device = Device::Nvme(Some(nsid))
+                    }
+                    Ok(_) => return self.error(start, "ns= can't be used with ramdisk:"),
+                    Err(_) => return self.error(value_start, "invalid namespace"),
+                }
+                return Ok(());
+            }
+            _ => return self.error(start, "unknown key"),
+        };
+        spec.selectors.push(selector);
+        Ok(())
+    }
+
+    fn option(&mut self, spec: &mut Spec) -> Result<(), ParseError> {
+        self.skip_ws();
+        let start = self.pos;
+        let key = self.word(&[';', '|', '=']);
+        if !self.eat("=") {
+            return self.error(self.pos, "expected key=value");
+        }
+
+        self.skip_ws();
+        let value_start = self.pos;
+        let value = self.value(&[';', '|'])?;
+        match key {
+            "sha256" if spec.sha256.is_some() => self.error(start, "duplicate sha256"),
+            "sha256" => match hex::parse(&value) {
+                Some(digest) => {
+                    spec.sha256 = Some(digest);
+                    Ok(())
+                }
+                None => self.error(value_start, "invalid SHA-256 digest"),
+            },
+            _ => self.error(start, "unknown option"),
+        }
+    }
+
+    fn spec(&mut self) -> Result<Spec, ParseError> {
+        self.skip_ws();
+        let mut spec = Spec {
+            device: self.device_prefix()?,
+            selectors: Vec::new(),
+            path: String::new(),
+            sha256: None,
+        };
+
+        self.skip_ws();
+        if !self.eat(";") {
+            loop {
+                self.selector(&mut spec)?;
+                self.skip_ws();
+                match self.peek() {
+                    Some(',') => self.bump(),
+                    Some(';') => {
+                        self.bump();
+                        break;
+                    }
+                    None | Some('|') => return self.error(self.pos, "expected ';' and a path"),
+                    Some(_) => return self.error(self.pos, "expected ',' or ';'"),
+                }
+            }
+        }
+
+        self.skip_ws();
+        let start = self.pos;
+        spec.path = self.value(&[';', '|'])?;
+        if spec.path.is_empty() {
+            return self.error(start, "missing path");
+        }
+
+        loop {
+            self.skip_ws();
+            if !self.eat(";") {
+                return Ok(spec);
+            }
+            self.option(&mut spec)?;
+        }
+    }
+}
+
+/// Parse a chainload spec into its alternatives.
+pub fn parse(s: &str) -> Result<Vec<Spec>, ParseError> {
+    let mut parser = Parser { s, pos: 0 };
+    let mut specs = Vec::new();
+    loop {
+        specs.push(parser.spec()?);
+        parser.skip_ws();
+        match parser.peek() {
+            None => return Ok(specs),
+            Some('|') => parser.bump(),
+            Some(_) => return parser.error(parser.pos, "expected '|' or end of spec"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UUID: &str = "3b8b1a6e-8f2a-4a5e-9d3c-1e4f7a2b9c01";
+
+    fn parse_one(s: &str) -> Spec {
+        let mut specs = parse(s).unwrap();
+        assert_eq!(specs.len(), 1);
+        specs.pop().unwrap()
+    }
+
+    fn column(s: &str) -> usize {
+        parse(s).unwrap_err().column
+    }
+
+    #[test]
+    fn legacy() {
+        let spec = parse_one(&format!("{};vmlinuz", UUID));
+        assert_eq!(spec.device, Device::Nvme(None));
+        assert_eq!(
+            spec.selectors,
+            [Selector::PartUuid(PartUuid::parse(UUID).unwrap())]
+        );
+        assert_eq!(spec.path, "vmlinuz");
+
+        let spec = parse_one("ns2:deadbeef-01;boot/Image");
+        assert_eq!(spec.device, Device::Nvme(Some(2)));
+        assert_eq!(
+            spec.selectors,
+            [Selector::PartUuid(PartUuid::Mbr(0xdeadbeef, 1))]
+        );
+
+        let spec = parse_one("ramdisk:;Image");
+        assert_eq!(spec.device, Device::RamDisk);
+        assert!(spec.selectors.is_empty());
+    }
+
+    #[test]
+    fn selectors() {
+        let spec = parse_one(
+            "ns=3, partlabel=\"EFI - ASAHI\", parttype=ESP ; \"EFI/Linux/my \\\"kernel\\\".efi\"",
+        );
+        assert_eq!(spec.device, Device::Nvme(Some(3)));
+        assert_eq!(
+            spec.selectors,
+            [
+                Selector::PartLabel("EFI - ASAHI".into()),
+                Selector::PartType(PartType::Esp)
+            ]
+        );
+        assert_eq!(spec.path, "EFI/Linux/my \"kernel\".efi");
+
+        let spec = parse_one(&format!("partuuid={},parttype={};a b", UUID, UUID));
+        assert_eq!(spec.selectors.len(), 2);
+        assert_eq!(
+            spec.selectors[1],
+            Selector::PartType(PartType::Guid(Uuid::parse_str(UUID).unwrap()))
+        );
+        assert_eq!(spec.path, "a b");
+    }
+
+    #[test]
+    fn options() {
+        let digest = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        let spec = parse_one(&format!("parttype=esp;m1n1/boot.bin ; sha256={}", digest));
+        assert_eq!(spec.path, "m1n1/boot.bin");
+        assert_eq!(spec.sha256, hex::parse(digest));
+        assert_eq!(parse_one(&format!("{}", spec)), spec);
+
+        let spec = parse_one("ramdisk:;\"a;b\"");
+        assert_eq!(spec.path, "a;b");
+        assert_eq!(spec.sha256, None);
+
+        assert_eq!(column("ramdisk:;x;sha256=12"), 19);
+        assert_eq!(column("ramdisk:;x;md5=12"), 12);
+        assert_eq!(column("ramdisk:;x;sha256"), 18);
+        assert_eq!(
+            column(&format!("ramdisk:;x;sha256={0};sha256={0}", digest)),
+            84
+        );
+    }
+
+    #[test]
+    fn fallbacks() {
+        let specs = parse("ramdisk:;\"a|b\" | parttype=esp;vmlinuz|ns1:;x").unwrap();
+        assert_eq!(specs.len(), 3);
+        assert_eq!(specs[0].path, "a|b");
+        assert_eq!(specs[1].selectors, [Selector::PartType(PartType::Esp)]);
+        assert_eq!(specs[1].path, "vmlinuz");
+        assert_eq!(specs[2].device, Device::Nvme(Some(1)));
+
+        // Display round-trips, even with quotes and backslashes
+        for spec in specs {
+            assert_eq!(parse_one(&format!("{}", spec)), spec);
+        }
+        let spec = parse_one(r#"partlabel="my \"boot\" \\ disk";"EFI\\a \"b\".efi""#);
+        assert_eq!(
+            spec.selectors,
+            [Selector::PartLabel(r#"my "boot" \ disk"#.into())]
+        );
+        assert_eq!(spec.path, r#"EFI\a "b".efi"#);
+        assert_eq!(
+            format!("{}", spec),
+            r#"partlabel="my \"boot\" \\ disk";"EFI\\a \"b\".efi""#
+        );
+        assert_eq!(parse_one(&format!("{}", spec)), spec);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(column(""), 1);
+        assert_eq!(column("vmlinuz"), 1);
+        assert_eq!(column("parttype=esp"), 13);
+        assert_eq!(column("parttype=esp;"), 14);
+        assert_eq!(column("parttype=esp,foo=bar;x"), 14);
+        assert_eq!(column("partuuid=1234;x"), 10);
+        assert_eq!(column("parttype=swap;x"), 10);
+        assert_eq!(column("ns=one;x"), 4);
+        assert_eq!(column("ramdisk:ns=1;x"), 9);
+        assert_eq!(column("parttype=esp;\"x"), 14);
+        assert_eq!(column("ramdisk:;x|"), 12);
+        assert_eq!(column("parttype=esp \"x\";y"), 10);
+        assert_eq!(column("ramdisk:;\"x\" y"), 14);
+        assert_eq!(column("ns99999999999:;x"), 1);
+        assert_eq!(
+            parse("partlabel=\"é\",foo=1;x").unwrap_err(),
+            ParseError {
+                column: 15,
+                msg: "unknown key"
+            }
+        );
+    }
+}
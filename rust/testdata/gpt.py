@@ -0,0 +1,83 @@
+#!/usr/bin/env python3
+# SPDX-License-Identifier: MIT
+
+# Writes gpt-before.img and gpt-after.img, a small disk laid out the way sgdisk lays out a new
+# GPT (protective MBR, 128 entries, backup array right before the backup header in the last
+# block), before and after renaming partition 1 and setting attributes on partition 2.
+#
+# This follows the UEFI specification on its own rather than sharing any code with gpt.rs, so
+# that the tests compare GPT::write() against an independent encoding.
+
+import struct
+import uuid
+import zlib
+
+BLOCK_SIZE = 512
+BLOCKS = 80
+ENTRIES = 128
+ENTRY_SIZE = 128
+ARRAY_BLOCKS = ENTRIES * ENTRY_SIZE // BLOCK_SIZE
+
+DISK_GUID = uuid.UUID("0b0c1d2e-3f40-4152-a364-758697a8b9ca")
+ESP_TYPE = uuid.UUID("c12a7328-f81f-11d2-ba4b-00a0c93ec93b")
+LINUX_TYPE = uuid.UUID("0fc63daf-8483-4772-8e79-3d69d8477de4")
+
+
+def entry(type_guid, guid, first, last, attrs, name):
+    name = name.encode("utf-16-le").ljust(72, b"\0")
+    return type_guid.bytes_le + guid.bytes_le + struct.pack("<QQQ", first, last, attrs) + name
+
+
+def header(my_lba, alternate_lba, entry_lba, array):
+    fields = [
+        b"EFI PART",
+        0x00010000,
+        92,
+        0,
+        0,
+        my_lba,
+        alternate_lba,
+        34,
+        BLOCKS - 34,
+        DISK_GUID.bytes_le,
+        entry_lba,
+        ENTRIES,
+        ENTRY_SIZE,
+        zlib.crc32(array),
+    ]
+    fmt = "<8sIIIIQQQQ16sQIII"
+    crc = zlib.crc32(struct.pack(fmt, *fields))
+    fields[3] = crc
+    return struct.pack(fmt, *fields).ljust(BLOCK_SIZE, b"\0")
+
+
+def protective_mbr():
+    mbr = bytearray(BLOCK_SIZE)
+    mbr[446:462] = struct.pack("<B3sB3sII", 0, b"\x00\x02\x00", 0xEE, b"\xff\xff\xff", 1, BLOCKS - 1)
+    mbr[510:512] = b"\x55\xaa"
+    return bytes(mbr)
+
+
+def disk(parts):
+    array = b"".join(entry(*part) for part in parts).ljust(ENTRIES * ENTRY_SIZE, b"\0")
+    backup_entry_lba = BLOCKS - 1 - ARRAY_BLOCKS
+
+    image = bytearray(BLOCKS * BLOCK_SIZE)
+    image[0:BLOCK_SIZE] = protective_mbr()
+    for my_lba, alternate_lba, entry_lba in [(1, BLOCKS - 1, 2), (BLOCKS - 1, 1, backup_entry_lba)]:
+        off = my_lba * BLOCK_SIZE
+        image[off:off + BLOCK_SIZE] = header(my_lba, alternate_lba, entry_lba, array)
+        off = entry_lba * BLOCK_SIZE
+        image[off:off + len(array)] = array
+    return bytes(image)
+
+
+esp = [ESP_TYPE, uuid.UUID("5f3e2b1a-7c4d-4e8f-9a0b-1c2d3e4f5a6b"), 34, 39, 0, "EFI System Partition"]
+root = [LINUX_TYPE, uuid.UUID("1a2b3c4d-5e6f-4071-8293-a4b5c6d7e8f9"), 40, 45, 0, "Linux"]
+with open("gpt-before.img", "wb") as f:
+    f.write(disk([esp, root]))
+
+esp[5] = "m1n1 boot"
+root[4] = 1 << 2 | 1 << 60
+with open("gpt-after.img", "wb") as f:
+    f.write(disk([esp, root]))
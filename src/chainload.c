@@ -13,19 +13,44 @@
 #include "xnuboot.h"
 
 #ifdef CHAINLOADING
-int rust_load_image(const char *spec, void **image, size_t *size);
+int rust_load_image(const char *spec, void **image, size_t *size, char ***vars, size_t *var_cnt);
+int rust_load_linux(const char *spec, struct linux_images *images);
 #endif
 
+struct macho_image {
+    void *image;
+    size_t size;
+    size_t entry;
+};
+
+int rust_macho_load(const void *data, size_t size, struct macho_image *out);
+
 extern u8 _chainload_stub_start[];
 extern u8 _chainload_stub_end[];
 
 int chainload_image(void *image, size_t size, char **vars, size_t var_cnt)
 {
     u64 new_base = (u64)_base;
-    size_t image_size = size;
+    size_t entry = 0x800; // m1n1.bin entrypoint, after the exception vectors
 
     printf("chainload: Preparing image...\n");
 
+    // A Mach-O has to be laid out as it is mapped, and enters wherever it says
+    struct macho_image macho;
+    int ret = rust_macho_load(image, size, &macho);
+    if (ret < 0) {
+        printf("chainload: Failed to load Mach-O\n");
+        return -1;
+    } else if (ret > 0) {
+        // The file is no longer needed and may be large, don't keep it around twice
+        free(image);
+        image = macho.image;
+        size = macho.size;
+        entry = macho.entry;
+    }
+
+    size_t image_size = size;
+
     // m1n1 variables
     for (size_t i = 0; i < var_cnt; i++)
         image_size += strlen(vars[i]) + 1;
@@ -106,7 +131,7 @@ int chainload_image(void *image, size_t size, char **vars, size_t var_cnt)
     next_stage.args[1] = (u64)new_image;
     next_stage.args[2] = new_base;
     next_stage.args[3] = image_size;
-    next_stage.args[4] = new_base + 0x800; // m1n1 entrypoint
+    next_stage.args[4] = new_base + entry;
     next_stage.restore_logo = false;
 
     return 0;
@@ -114,23 +139,60 @@ int chainload_image(void *image, size_t size, char **vars, size_t var_cnt)
 
 #ifdef CHAINLOADING
 
+// Returns whether NVMe has to be shut down afterwards
+static bool chainload_nvme_init(const char *spec)
+{
+    // Fallbacks may be on NVMe even if the first alternative is a RAM disk
+    if (!strncmp(spec, "ramdisk:", 8) && !strchr(spec, '|'))
+        return false;
+
+    if (!nvme_init()) {
+        printf("chainload: NVME init failed, only a RAM disk can be used\n");
+        return false;
+    }
+
+    return true;
+}
+
 int chainload_load(const char *spec, char **vars, size_t var_cnt)
 {
     void *image;
     size_t size;
     int ret;
+    bool nvme = chainload_nvme_init(spec);
 
-    if (!nvme_init()) {
-        printf("chainload: NVME init failed\n");
-        return -1;
-    }
-
-    ret = rust_load_image(spec, &image, &size);
-    nvme_shutdown();
+    char **cfg_vars;
+    size_t cfg_cnt;
+    ret = rust_load_image(spec, &image, &size, &cfg_vars, &cfg_cnt);
+    if (nvme)
+        nvme_shutdown();
     if (ret < 0)
         return ret;
 
-    return chainload_image(image, size, vars, var_cnt);
+    if (!cfg_cnt)
+        return chainload_image(image, size, vars, var_cnt);
+
+    // Variables from the config file go last, so they take precedence over the payload's
+    char **all_vars = malloc((var_cnt + cfg_cnt) * sizeof(char *));
+    if (!all_vars)
+        return -1;
+    memcpy(all_vars, vars, var_cnt * sizeof(char *));
+    memcpy(all_vars + var_cnt, cfg_vars, cfg_cnt * sizeof(char *));
+
+    ret = chainload_image(image, size, all_vars, var_cnt + cfg_cnt);
+    free(all_vars);
+    return ret;
+}
+
+int chainload_load_linux(const char *spec, struct linux_images *images)
+{
+    bool nvme = chainload_nvme_init(spec);
+
+    int ret = rust_load_linux(spec, images);
+    if (nvme)
+        nvme_shutdown();
+
+    return ret;
 }
 
 #else
@@ -145,4 +207,13 @@ int chainload_load(const char *spec, char **vars, size_t var_cnt)
     return -1;
 }
 
+int chainload_load_linux(const char *spec, struct linux_images *images)
+{
+    UNUSED(spec);
+    UNUSED(images);
+
+    printf("Loading Linux from files not supported in this build!\n");
+    return -1;
+}
+
 #endif
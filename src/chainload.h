@@ -5,7 +5,19 @@
 
 #include "types.h"
 
+struct linux_images {
+    void *kernel;
+    size_t kernel_size;
+    void *initrd; // NULL if none
+    size_t initrd_size;
+    void *fdt; // NULL if none
+    size_t fdt_size;
+    char *cmdline;
+};
+
+// Takes ownership of a malloc()ed Mach-O image, which is freed once laid out
 int chainload_image(void *base, size_t size, char **vars, size_t var_cnt);
 int chainload_load(const char *spec, char **vars, size_t var_cnt);
+int chainload_load_linux(const char *spec, struct linux_images *images);
 
 #endif
@@ -42,3 +42,11 @@ void *heapblock_alloc_aligned(size_t size, size_t align)
 
     return (void *)block;
 }
+
+size_t heapblock_free(void)
+{
+    u64 top_of_ram = cur_boot_args.mem_size + cur_boot_args.phys_base;
+    u64 base = (u64)heap_base;
+
+    return base < top_of_ram ? top_of_ram - base : 0;
+}
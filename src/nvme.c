@@ -15,6 +15,9 @@
 #define NVME_SHUTDOWN_TIMEOUT 5000000
 #define NVME_QUEUE_SIZE       64
 
+/* one PRP list page worth of entries, well below what ANS can transfer in one go */
+#define NVME_MAX_TRANSFER_PAGES 128
+
 #define NVME_CC            0x14
 #define NVME_CC_SHN        GENMASK(15, 14)
 #define NVME_CC_SHN_NONE   0
@@ -59,8 +62,18 @@
 #define NVME_ADMIN_CMD_CREATE_SQ 0x01
 #define NVME_ADMIN_CMD_DELETE_CQ 0x04
 #define NVME_ADMIN_CMD_CREATE_CQ 0x05
+#define NVME_ADMIN_CMD_IDENTIFY  0x06
 #define NVME_QUEUE_CONTIGUOUS    BIT(0)
 
+#define NVME_IDENTIFY_CNS_NS      0x00
+#define NVME_IDENTIFY_CNS_NS_LIST 0x02
+#define NVME_NS_LIST_MAX          1024
+
+#define NVME_ID_NS_NSZE        0
+#define NVME_ID_NS_FLBAS       26
+#define NVME_ID_NS_FLBAS_INDEX GENMASK(3, 0)
+#define NVME_ID_NS_LBAF        128
+
 #define NVME_CMD_FLUSH 0x00
 #define NVME_CMD_WRITE 0x01
 #define NVME_CMD_READ  0x02
@@ -130,6 +143,7 @@ static sart_dev_t *nvme_sart = NULL;
 static u64 nvme_base;
 
 static struct nvme_queue adminq, ioq;
+static u64 *prp_list;
 
 static bool alloc_queue(struct nvme_queue *q)
 {
@@ -324,12 +338,18 @@ bool nvme_init(void)
         goto out_adminq;
     }
 
+    prp_list = memalign(SZ_4K, SZ_4K);
+    if (!prp_list) {
+        printf("nvme: Error allocating PRP list\n");
+        goto out_ioq;
+    }
+
     ioq.adminq = false;
     adminq.adminq = true;
 
     nvme_asc = asc_init("/arm-io/ans");
     if (!nvme_asc)
-        goto out_ioq;
+        goto out_prp_list;
 
     nvme_sart = sart_init("/arm-io/sart-ans");
     if (!nvme_sart)
@@ -420,6 +440,8 @@ out_sart:
     sart_free(nvme_sart);
 out_asc:
     asc_free(nvme_asc);
+out_prp_list:
+    free(prp_list);
 out_ioq:
     free_queue(&ioq);
 out_adminq:
@@ -505,6 +527,7 @@ void nvme_shutdown(void)
     rtkit_free(nvme_rtkit);
     sart_free(nvme_sart);
     asc_free(nvme_asc);
+    free(prp_list);
     free_queue(&ioq);
     free_queue(&adminq);
     nvme_initialized = false;
@@ -512,6 +535,92 @@ void nvme_shutdown(void)
     printf("nvme: shutdown done\n");
 }
 
+static bool nvme_identify(u32 nsid, u32 cns, void *buffer)
+{
+    struct nvme_command cmd;
+
+    memset(&cmd, 0, sizeof(cmd));
+    cmd.opcode = NVME_ADMIN_CMD_IDENTIFY;
+    cmd.nsid = nsid;
+    cmd.prp1 = (u64)buffer;
+    cmd.cdw10 = cns;
+
+    return nvme_exec_command(&adminq, &cmd, NULL);
+}
+
+u32 nvme_get_block_size(u32 nsid)
+{
+    u32 block_size = 0;
+
+    if (!nvme_initialized)
+        return 0;
+
+    u8 *id = memalign(SZ_4K, SZ_4K);
+    if (!id)
+        return 0;
+    memset(id, 0, SZ_4K);
+
+    if (nvme_identify(nsid, NVME_IDENTIFY_CNS_NS, id)) {
+        u8 fmt = FIELD_GET(NVME_ID_NS_FLBAS_INDEX, id[NVME_ID_NS_FLBAS]);
+        /* LBA format: u16 metadata size, u8 log2(LBA data size), u8 relative performance */
+        u8 lbads = id[NVME_ID_NS_LBAF + 4 * fmt + 2];
+        if (lbads >= 9 && lbads < 32)
+            block_size = 1 << lbads;
+    } else {
+        printf("nvme: identify namespace %d failed\n", nsid);
+    }
+
+    free(id);
+    return block_size;
+}
+
+u64 nvme_get_capacity(u32 nsid)
+{
+    u64 capacity = 0;
+
+    if (!nvme_initialized)
+        return 0;
+
+    u8 *id = memalign(SZ_4K, SZ_4K);
+    if (!id)
+        return 0;
+    memset(id, 0, SZ_4K);
+
+    if (nvme_identify(nsid, NVME_IDENTIFY_CNS_NS, id))
+        memcpy(&capacity, &id[NVME_ID_NS_NSZE], sizeof(capacity));
+    else
+        printf("nvme: identify namespace %d failed\n", nsid);
+
+    free(id);
+    return capacity;
+}
+
+int nvme_get_namespaces(u32 *nsids, int max)
+{
+    int count = 0;
+
+    if (!nvme_initialized)
+        return -1;
+
+    u32 *list = memalign(SZ_4K, SZ_4K);
+    if (!list)
+        return -1;
+    memset(list, 0, SZ_4K);
+
+    /* active namespace IDs in increasing order, terminated by a zero entry */
+    if (!nvme_identify(0, NVME_IDENTIFY_CNS_NS_LIST, list)) {
+        printf("nvme: identify active namespace list failed\n");
+        free(list);
+        return -1;
+    }
+
+    for (int i = 0; i < NVME_NS_LIST_MAX && list[i] && count < max; i++)
+        nsids[count++] = list[i];
+
+    free(list);
+    return count;
+}
+
 bool nvme_flush(u32 nsid)
 {
     struct nvme_command cmd;
@@ -526,10 +635,13 @@ bool nvme_flush(u32 nsid)
     return nvme_exec_command(&ioq, &cmd, NULL);
 }
 
-bool nvme_read(u32 nsid, u64 lba, void *buffer)
+/*
+ * Transfer `count` blocks of `block_size` bytes. PRP entries describe the buffer page by page,
+ * so it has to be 4K aligned, but the transfer can end anywhere in a page.
+ */
+static bool nvme_rw(u8 opcode, u32 nsid, u64 lba, u32 count, u32 block_size, u64 buffer_addr)
 {
     struct nvme_command cmd;
-    u64 buffer_addr = (u64)buffer;
 
     if (!nvme_initialized)
         return false;
@@ -538,13 +650,58 @@ bool nvme_read(u32 nsid, u64 lba, void *buffer)
     if (buffer_addr & (SZ_4K - 1))
         return false;
 
-    memset(&cmd, 0, sizeof(cmd));
-    cmd.opcode = NVME_CMD_READ;
-    cmd.nsid = nsid;
-    cmd.prp1 = (u64)buffer_addr;
-    cmd.cdw10 = lba;
-    cmd.cdw11 = lba >> 32;
-    cmd.cdw12 = 1; // 4096 bytes
+    /* power of two block sizes up to the maximum transfer size keep each command page aligned */
+    u32 max_blocks = block_size ? NVME_MAX_TRANSFER_PAGES * SZ_4K / block_size : 0;
+    if (!max_blocks || (block_size & (block_size - 1)))
+        return false;
 
-    return nvme_exec_command(&ioq, &cmd, NULL);
+    while (count) {
+        u32 blocks = min(count, max_blocks);
+        u64 bytes = (u64)blocks * block_size;
+        u32 pages = ALIGN_UP(bytes, SZ_4K) / SZ_4K;
+
+        memset(&cmd, 0, sizeof(cmd));
+        cmd.opcode = opcode;
+        cmd.nsid = nsid;
+        cmd.prp1 = buffer_addr;
+        if (pages == 2) {
+            cmd.prp2 = buffer_addr + SZ_4K;
+        } else if (pages > 2) {
+            for (u32 i = 1; i < pages; i++)
+                prp_list[i - 1] = buffer_addr + i * SZ_4K;
+            cmd.prp2 = (u64)prp_list;
+        }
+        cmd.cdw10 = lba;
+        cmd.cdw11 = lba >> 32;
+        cmd.cdw12 = blocks - 1; // 0's based number of logical blocks
+
+        if (!nvme_exec_command(&ioq, &cmd, NULL))
+            return false;
+
+        lba += blocks;
+        count -= blocks;
+        buffer_addr += bytes;
+    }
+
+    return true;
+}
+
+bool nvme_read(u32 nsid, u64 lba, void *buffer)
+{
+    return nvme_rw(NVME_CMD_READ, nsid, lba, 1, SZ_4K, (u64)buffer);
+}
+
+bool nvme_write(u32 nsid, u64 lba, const void *buffer)
+{
+    return nvme_rw(NVME_CMD_WRITE, nsid, lba, 1, SZ_4K, (u64)buffer);
+}
+
+bool nvme_read_blocks(u32 nsid, u64 lba, u32 count, u32 block_size, void *buffer)
+{
+    return nvme_rw(NVME_CMD_READ, nsid, lba, count, block_size, (u64)buffer);
+}
+
+bool nvme_write_blocks(u32 nsid, u64 lba, u32 count, u32 block_size, const void *buffer)
+{
+    return nvme_rw(NVME_CMD_WRITE, nsid, lba, count, block_size, (u64)buffer);
 }
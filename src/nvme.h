@@ -10,5 +10,12 @@ void nvme_shutdown(void);
 
 bool nvme_flush(u32 nsid);
 bool nvme_read(u32 nsid, u64 lba, void *buffer);
+bool nvme_write(u32 nsid, u64 lba, const void *buffer);
+bool nvme_read_blocks(u32 nsid, u64 lba, u32 count, u32 block_size, void *buffer);
+bool nvme_write_blocks(u32 nsid, u64 lba, u32 count, u32 block_size, const void *buffer);
+
+u32 nvme_get_block_size(u32 nsid);
+u64 nvme_get_capacity(u32 nsid);
+int nvme_get_namespaces(u32 *nsids, int max);
 
 #endif
@@ -11,6 +11,7 @@
 #include "display.h"
 #include "heapblock.h"
 #include "kboot.h"
+#include "malloc.h"
 #include "mitigations.h"
 #include "smp.h"
 #include "utils.h"
@@ -32,6 +33,9 @@ static const u8 sig_magic[] = {'m', '1', 'n', '1', '_', 's', 'i', 'g'};
 static const u8 initramfs_magic[] = {
     'm', '1', 'n', '1', '_', 'i', 'n',
     'i', 't', 'r', 'a', 'm', 'f', 's'}; // followed by size as little endian uint32_t
+static const u8 ramdisk_magic[] = {
+    'm', '1', 'n', '1', '_', 'r', 'a',
+    'm', 'd', 'i', 's', 'k'}; // followed by size as little endian uint32_t
 static const u8 empty[] = {0, 0, 0, 0};
 
 // custom logo is RGBA with 256x256 retina logo followed by half resolution
@@ -43,6 +47,7 @@ static char expect_compatible[256];
 static struct kernel_header *kernel = NULL;
 static void *fdt = NULL;
 static char *chainload_spec = NULL;
+static char *linux_spec = NULL;
 
 static void *load_one_payload(void *start, size_t size);
 
@@ -166,6 +171,11 @@ static void *load_kernel(void *p, size_t size)
 #define MAX_CHOSEN_VARS 16
 
 #ifdef CHAINLOADING
+void rust_nvme_enable_writes(bool enable);
+void rust_register_ramdisk(void *base, size_t size);
+int rust_set_chainload_key(const char *key);
+int rust_set_chainload_policy(const char *policy);
+
 static size_t chosen_cnt = 1;
 static char *chosen[MAX_CHOSEN_VARS] = {
     "chosen.asahi,m1n1-stage1-version=" BUILD_TAG,
@@ -199,12 +209,22 @@ static bool check_var(u8 **p)
             chosen[chosen_cnt++] = (char *)*p;
     } else if (IS_VAR("chainload=")) {
         chainload_spec = val;
+    } else if (IS_VAR("linux=")) {
+        linux_spec = val;
     } else if (IS_VAR("display=")) {
         display_configure(val);
     } else if (IS_VAR("mitigations=")) {
         mitigations_configure(val);
     } else if (IS_VAR("tso=")) {
         enable_tso = val[0] == '1';
+#ifdef CHAINLOADING
+    } else if (IS_VAR("nvme_writes=")) {
+        rust_nvme_enable_writes(val[0] == '1');
+    } else if (IS_VAR("chainload_key=")) {
+        rust_set_chainload_key(val);
+    } else if (IS_VAR("chainload_verify=")) {
+        rust_set_chainload_policy(val);
+#endif
     } else {
         printf("Unknown variable %s\n", *p);
     }
@@ -246,6 +266,17 @@ static void *load_one_payload(void *start, size_t size)
         printf("Found a m1n1 initramfs payload at %p, 0x%x bytes\n", p, size);
         p += sizeof(initramfs_magic) + 4;
         return load_cpio(p, size);
+    } else if (!memcmp(p, ramdisk_magic, sizeof(ramdisk_magic))) {
+        u32 size;
+        memcpy(&size, p + sizeof(ramdisk_magic), 4);
+        p += sizeof(ramdisk_magic) + 4;
+#ifdef CHAINLOADING
+        printf("Found a m1n1 RAM disk payload at %p, 0x%x bytes\n", p, size);
+        rust_register_ramdisk(p, size);
+#else
+        printf("Found a m1n1 RAM disk payload at %p, but chainloading is disabled\n", p);
+#endif
+        return p + size;
     } else if (!memcmp(p, custom_logo_magic, sizeof(custom_logo_magic))) {
         printf("Found a m1n1 custom logo payload at %p, skipping 0x%lx bytes\n", p,
                sizeof(custom_logo_magic) + CUSTOM_LOGO_SIZE);
@@ -262,6 +293,53 @@ static void *load_one_payload(void *start, size_t size)
     }
 }
 
+static int load_linux(const char *spec)
+{
+    struct linux_images images;
+
+    if (chainload_load_linux(spec, &images) < 0)
+        return -1;
+
+    struct kernel_header *hdr = images.kernel;
+    if (images.kernel_size < sizeof(*hdr) ||
+        memcmp(&hdr->magic, kernel_magic, sizeof kernel_magic)) {
+        printf("linux: Not an arm64 kernel Image\n");
+        return -1;
+    }
+    if (hdr->image_size < images.kernel_size) {
+        printf("linux: Bad kernel image size 0x%lx\n", hdr->image_size);
+        return -1;
+    }
+
+    // The kernel needs to be aligned, with room for its BSS after it
+    kernel = heapblock_alloc_aligned(hdr->image_size, KERNEL_ALIGN);
+    memcpy(kernel, images.kernel, images.kernel_size);
+    free(images.kernel);
+    printf("Found a kernel at %p\n", kernel);
+
+    if (images.initrd) {
+        printf("Found an initramfs at %p, 0x%lx bytes\n", images.initrd, images.initrd_size);
+        kboot_set_initrd(images.initrd, images.initrd_size);
+    }
+
+    if (images.fdt) {
+        if (images.fdt_size < FDT_V17_SIZE || fdt_check_header(images.fdt) ||
+            fdt_totalsize(images.fdt) > images.fdt_size) {
+            printf("linux: Bad devicetree\n");
+            return -1;
+        }
+        load_fdt(images.fdt, fdt_totalsize(images.fdt));
+        if (fdt != images.fdt)
+            printf("linux: Devicetree is not for %s, ignoring it\n", expect_compatible);
+    }
+
+    // Set first, so chosen.bootargs from variables takes precedence
+    if (images.cmdline[0] && kboot_set_chosen("bootargs", images.cmdline) < 0)
+        printf("linux: Failed to set the command line\n");
+
+    return 0;
+}
+
 void do_enable_tso(void)
 {
     u64 actlr = mrs(ACTLR_EL1);
@@ -313,6 +391,9 @@ int payload_run(void)
         return chainload_load(chainload_spec, chosen, chosen_cnt);
     }
 
+    if (linux_spec && load_linux(linux_spec) < 0)
+        return -1;
+
     if (kernel && fdt) {
         cpufreq_init();
         smp_start_secondaries();